@@ -1,3 +1,5 @@
+use std::fmt::{self, Display, Formatter};
+
 const LOGO_START: usize = 0x0104;
 const LOGO_END: usize = 0x0134;
 const CHECKSUM_START: usize = 0x0134;
@@ -6,6 +8,7 @@ const CGB_FLAG: usize = 0x0143;
 const TITLE_END: usize = 0x0144;
 const NEW_LICENSEE_START: usize = 0x0144;
 const NEW_LICENSEE_END: usize = 0x0146;
+const SGB_FLAG: usize = 0x0146;
 const FEATURES: usize = 0x0147;
 const ROM_SIZE: usize = 0x0148;
 const RAM_SIZE: usize = 0x0149;
@@ -17,6 +20,8 @@ const HEADER_END: usize = 0x0150;
 const CGB_COMPAT: u8 = 0x80;
 const CGB_EXCL: u8 = 0xC0;
 
+const SGB_SUPPORT: u8 = 0x03;
+
 const USE_NEW_LICENSEE: u8 = 0x33;
 
 const LOGO_BYTES: &[u8] = &[
@@ -30,9 +35,18 @@ const HASH_LEN: usize = 4096;
 #[derive(Default, Debug)]
 pub struct Cart(Vec<u8>);
 
+/// Why a cart was rejected
 #[derive(Debug)]
 pub struct Error(pub &'static str);
 
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
 #[derive(Debug)]
 pub enum ColorSupport {
     BackwardsCompatible,
@@ -103,6 +117,24 @@ impl Cart {
         std::str::from_utf8(&region[0..end_pos]).expect("validated ascii")
     }
 
+    /// Sum of the title bytes, including the CGB flag
+    pub fn title_checksum(&self) -> u8 {
+        self.0[TITLE_START..TITLE_END]
+            .iter()
+            .fold(0u8, |acc, &b| acc.wrapping_add(b))
+    }
+
+    pub fn header_checksum(&self) -> u8 {
+        self.0[CHECKSUM_DIGEST]
+    }
+
+    pub(crate) fn nintendo_licensed(&self) -> bool {
+        match self.0[OLD_LICENSEE] {
+            USE_NEW_LICENSEE => &self.0[NEW_LICENSEE_START..NEW_LICENSEE_END] == b"01",
+            old => old == 0x01,
+        }
+    }
+
     pub fn color_supported(&self) -> ColorSupport {
         match self.0[CGB_FLAG] {
             CGB_COMPAT => ColorSupport::BackwardsCompatible,
@@ -111,6 +143,11 @@ impl Cart {
         }
     }
 
+    /// SGB features also require the new licensee code to be used
+    pub fn sgb_supported(&self) -> bool {
+        self.0[SGB_FLAG] == SGB_SUPPORT && self.0[OLD_LICENSEE] == USE_NEW_LICENSEE
+    }
+
     pub fn rom_size(&self) -> usize {
         32 * 1024 * 2usize.pow(self.0[ROM_SIZE] as _)
     }
@@ -357,3 +394,19 @@ impl Cart {
         }
     }
 }
+
+impl Cart {
+    /// A 32 KiB ROM-only cart for tests, which jumps from the entry point to `code` at $0150.
+    /// `patch` can change the rest of the ROM before its header checksum is set
+    pub fn test(code: &[u8], patch: impl FnOnce(&mut Vec<u8>)) -> Self {
+        let mut data = vec![0; 0x8000];
+        data[0x0100..0x0104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        data[LOGO_START..LOGO_END].copy_from_slice(LOGO_BYTES);
+        data[HEADER_END..HEADER_END + code.len()].copy_from_slice(code);
+        patch(&mut data);
+        data[CHECKSUM_DIGEST] = data[CHECKSUM_START..CHECKSUM_END]
+            .iter()
+            .fold(0u8, |acc, &b| acc.wrapping_sub(b).wrapping_sub(1));
+        Self::new(data).expect("valid test cart")
+    }
+}
@@ -1,97 +1,336 @@
+mod compat;
+
+use crate::cart::Cart;
 use serde::{
     Deserialize, Deserializer, Serialize, Serializer,
     de::{self, SeqAccess, Visitor},
     ser::SerializeTuple,
 };
-use std::{cell::Cell, fmt, rc::Rc};
+use std::fmt;
 
 pub const WIDTH: usize = 160;
 pub const HEIGHT: usize = 144;
+/// SGB frames include the border around the game screen
+pub const SGB_WIDTH: usize = 256;
+pub const SGB_HEIGHT: usize = 224;
+
+const PIXELS: usize = WIDTH * HEIGHT;
 
-#[derive(Clone)]
-pub struct Frame(pub Rc<[[Cell<Pixel>; WIDTH]; HEIGHT]>);
+/// Row-major framebuffer of shades and palette entries. The exported pixel formats are
+/// converted once the frame is finished
+pub struct Frame {
+    width: usize,
+    height: usize,
+    indices: Box<[u8]>,
+    /// Each pixel's entry in `palette`
+    colors: Box<[u8]>,
+    palette: Vec<Pixel>,
+    rgb8: Box<[u8]>,
+    rgba8: Box<[u8]>,
+    rgb565: Box<[u16]>,
+}
 
-#[derive(Copy, Clone, Serialize, Deserialize, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
 pub struct Pixel(pub u8, pub u8, pub u8);
 
+/// The four colors shades are drawn with, lightest first
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Palette(pub [Pixel; 4]);
+
+/// Separate palettes for the background and window, and for each object palette register
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Palettes {
+    pub bg: Palette,
+    pub obj0: Palette,
+    pub obj1: Palette,
+}
+
+#[derive(Debug)]
+pub struct PaletteError {
+    pub line: usize,
+    pub reason: &'static str,
+}
+
 #[derive(Copy, Clone, Default, Debug)]
 pub enum Theme {
     #[default]
     Grayscale,
     Classic,
+    Custom(Palettes),
+    /// The colorization the CGB boot ROM picks for the cart
+    Cgb,
 }
 
 pub(crate) type Rgb555 = [u8; 2];
 
 impl Pixel {
-    fn white() -> Self {
-        Self(255, 255, 255)
+    pub(crate) const fn from_hex(hex: u32) -> Self {
+        Self((hex >> 16) as u8, (hex >> 8) as u8, hex as u8)
     }
 
-    fn light() -> Self {
-        Self(170, 170, 170)
+    /// Little-endian, with red in the lowest bits
+    pub(crate) fn from_rgb555([lower, upper]: Rgb555) -> Self {
+        let color = u16::from_le_bytes([lower, upper]);
+        let channel = |shift: u16| {
+            let c = ((color >> shift) & 0b11111) as u8;
+            (c << 3) | (c >> 2)
+        };
+        Self(channel(0), channel(5), channel(10))
     }
 
-    fn dark() -> Self {
-        Self(85, 85, 85)
+    fn parse(color: &str) -> Option<Self> {
+        let hex = color.strip_prefix('#').unwrap_or(color);
+        if hex.len() != 6 {
+            return None;
+        }
+        u32::from_str_radix(hex, 16).ok().map(Self::from_hex)
     }
+}
+
+impl Palette {
+    pub const GRAYSCALE: Self = Self::from_hex([0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000]);
+    pub const CLASSIC: Self = Self::from_hex([0x9BBC0F, 0x8BAC0F, 0x306230, 0x0F380F]);
 
-    fn black() -> Self {
-        Self(0, 0, 0)
+    pub(crate) const fn from_hex([c0, c1, c2, c3]: [u32; 4]) -> Self {
+        Self([
+            Pixel::from_hex(c0),
+            Pixel::from_hex(c1),
+            Pixel::from_hex(c2),
+            Pixel::from_hex(c3),
+        ])
     }
 
-    fn lightest_green() -> Self {
-        Self(155, 188, 15)
+    pub fn color(&self, shade: u8) -> Pixel {
+        self.0[(shade & 0b11) as usize]
     }
+}
+
+impl Palettes {
+    /// Where each layer's colors start in `entries`
+    pub(crate) const BG: u8 = 0;
+    pub(crate) const OBJ0: u8 = 4;
+    pub(crate) const OBJ1: u8 = 8;
 
-    fn light_green() -> Self {
-        Self(139, 172, 15)
+    pub const fn all(palette: Palette) -> Self {
+        Self {
+            bg: palette,
+            obj0: palette,
+            obj1: palette,
+        }
     }
 
-    fn dark_green() -> Self {
-        Self(48, 98, 48)
+    /// One `<layer> <color> <color> <color> <color>` entry per line, lightest color first.
+    /// Layers are `bg`, `obj0` and `obj1`, colors are hex `#RRGGBB`.
+    /// Object palettes which are left out use the `bg` palette, and `;` starts a comment
+    pub fn parse(palettes: &str) -> Result<Self, PaletteError> {
+        let mut bg = None;
+        let mut obj0 = None;
+        let mut obj1 = None;
+        for (i, line) in palettes.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let err = |reason| PaletteError {
+                line: i + 1,
+                reason,
+            };
+            let mut words = line.split_whitespace();
+            let layer = match words.next().map(str::to_ascii_lowercase).as_deref() {
+                Some("bg") => &mut bg,
+                Some("obj0") => &mut obj0,
+                Some("obj1") => &mut obj1,
+                _ => return Err(err("unknown layer")),
+            };
+            let colors = words
+                .map(|color| Pixel::parse(color).ok_or(err("invalid color")))
+                .collect::<Result<Vec<_>, _>>()?;
+            let colors = colors.try_into().map_err(|_| err("expected 4 colors"))?;
+            if layer.replace(Palette(colors)).is_some() {
+                return Err(err("layer defined twice"));
+            }
+        }
+        let bg = bg.ok_or(PaletteError {
+            line: palettes.lines().count(),
+            reason: "missing bg palette",
+        })?;
+        Ok(Self {
+            bg,
+            obj0: obj0.unwrap_or(bg),
+            obj1: obj1.unwrap_or(bg),
+        })
     }
 
-    fn darkest_green() -> Self {
-        Self(15, 56, 15)
+    /// The layers' colors as one frame palette
+    pub(crate) fn entries(&self) -> impl Iterator<Item = Pixel> {
+        [self.bg, self.obj0, self.obj1]
+            .into_iter()
+            .flat_map(|palette| palette.0)
     }
+}
 
-    pub(crate) fn from_2bit(bits: u8, theme: Theme) -> Self {
-        match (bits & 0b11, theme) {
-            (0b00, Theme::Grayscale) => Self::white(),
-            (0b01, Theme::Grayscale) => Self::light(),
-            (0b10, Theme::Grayscale) => Self::dark(),
-            (0b11, Theme::Grayscale) => Self::black(),
-            (0b00, Theme::Classic) => Self::lightest_green(),
-            (0b01, Theme::Classic) => Self::light_green(),
-            (0b10, Theme::Classic) => Self::dark_green(),
-            (0b11, Theme::Classic) => Self::darkest_green(),
-            _ => unreachable!(),
+impl Theme {
+    pub fn palettes(self, cart: &Cart) -> Palettes {
+        match self {
+            Self::Grayscale => Palettes::all(Palette::GRAYSCALE),
+            Self::Classic => Palettes::all(Palette::CLASSIC),
+            Self::Custom(palettes) => palettes,
+            Self::Cgb => compat::palettes(cart),
         }
     }
+}
 
-    #[expect(dead_code)]
-    pub(crate) fn from_rgb555([lower, upper]: Rgb555) -> Self {
-        let r = lower & 0b11111000;
-        let g = ((lower & 0b00000111) << 5) | ((upper & 0b11000000) >> 3);
-        let b = (upper & 0b00111110) << 2;
-        Self(r, g, b)
+impl Default for Palettes {
+    fn default() -> Self {
+        Self::all(Palette::GRAYSCALE)
+    }
+}
+
+impl Frame {
+    /// A white frame
+    pub(crate) fn new(width: usize, height: usize) -> Self {
+        let pixels = width * height;
+        let mut frame = Self {
+            width,
+            height,
+            indices: vec![0; pixels].into(),
+            colors: vec![0; pixels].into(),
+            palette: vec![Palette::GRAYSCALE.color(0)],
+            rgb8: vec![0; pixels * 3].into(),
+            rgba8: vec![0; pixels * 4].into(),
+            rgb565: vec![0; pixels].into(),
+        };
+        frame.finish();
+        frame
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Colors set later are looked up through the new palette. The exported formats keep the
+    /// old colors until the frame is finished
+    pub(crate) fn set_palette(&mut self, palette: impl IntoIterator<Item = Pixel>) {
+        self.palette.clear();
+        self.palette.extend(palette);
+    }
+
+    /// `color` is an entry in the frame's palette
+    pub(crate) fn set(&mut self, x: usize, y: usize, index: u8, color: u8) {
+        let i = y * self.width + x;
+        self.indices[i] = index;
+        self.colors[i] = color;
+    }
+
+    pub(crate) fn color(&self, x: usize, y: usize) -> u8 {
+        self.colors[y * self.width + x]
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> Pixel {
+        self.palette[self.color(x, y) as usize]
+    }
+
+    /// Convert the pixels set since the last call to the exported formats, in place
+    pub(crate) fn finish(&mut self) {
+        let rgb8 = self.rgb8.chunks_exact_mut(3);
+        let rgba8 = self.rgba8.chunks_exact_mut(4);
+        let pixels = self
+            .colors
+            .iter()
+            .zip(rgb8.zip(rgba8))
+            .zip(&mut self.rgb565);
+        for ((&color, (rgb8, rgba8)), rgb565) in pixels {
+            let Pixel(r, g, b) = self.palette[color as usize];
+            rgb8.copy_from_slice(&[r, g, b]);
+            rgba8.copy_from_slice(&[r, g, b, 255]);
+            *rgb565 = ((r as u16 >> 3) << 11) | ((g as u16 >> 2) << 5) | (b as u16 >> 3);
+        }
+    }
+
+    /// 3 bytes per pixel
+    pub fn as_rgb8(&self) -> &[u8] {
+        &self.rgb8
+    }
+
+    /// 4 bytes per pixel, always opaque
+    pub fn as_rgba8(&self) -> &[u8] {
+        &self.rgba8
+    }
+
+    pub fn as_rgb565(&self) -> &[u16] {
+        &self.rgb565
+    }
+
+    /// The 2-bit shade of each pixel, after the DMG palette registers are applied
+    pub fn as_indices(&self) -> &[u8] {
+        &self.indices
+    }
+
+    /// Each pixel's entry in `palette`
+    pub fn as_colors(&self) -> &[u8] {
+        &self.colors
+    }
+
+    pub fn palette(&self) -> &[Pixel] {
+        &self.palette
+    }
+
+    pub fn hash(&self) -> String {
+        sha256::digest(self.as_rgb8())
+    }
+}
+
+impl Clone for Frame {
+    fn clone(&self) -> Self {
+        Self {
+            width: self.width,
+            height: self.height,
+            indices: self.indices.clone(),
+            colors: self.colors.clone(),
+            palette: self.palette.clone(),
+            rgb8: self.rgb8.clone(),
+            rgba8: self.rgba8.clone(),
+            rgb565: self.rgb565.clone(),
+        }
+    }
+
+    fn clone_from(&mut self, source: &Self) {
+        if (self.width, self.height) != (source.width, source.height) {
+            *self = source.clone();
+            return;
+        }
+        self.indices.copy_from_slice(&source.indices);
+        self.colors.copy_from_slice(&source.colors);
+        self.palette.clone_from(&source.palette);
+        self.rgb8.copy_from_slice(&source.rgb8);
+        self.rgba8.copy_from_slice(&source.rgba8);
+        self.rgb565.copy_from_slice(&source.rgb565);
+    }
+}
+
+/// Finished frames are equal when they show the same colors, whatever their palettes
+impl PartialEq for Frame {
+    fn eq(&self, other: &Self) -> bool {
+        (self.width, self.height) == (other.width, other.height) && self.rgb8 == other.rgb8
     }
 }
 
 impl Default for Frame {
     fn default() -> Self {
-        Self(Rc::new(std::array::repeat(std::array::repeat(Cell::new(
-            Pixel::white(),
-        )))))
+        Self::new(WIDTH, HEIGHT)
     }
 }
 
+/// Only DMG-sized frames are saved, as each pixel's color and shade
 impl Serialize for Frame {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        let mut tuple = serializer.serialize_tuple(WIDTH * HEIGHT)?;
-        for pixel in self.0.iter().flatten() {
-            tuple.serialize_element(pixel)?;
+        let mut tuple = serializer.serialize_tuple(PIXELS)?;
+        for i in 0..PIXELS {
+            tuple.serialize_element(&(self.pixel(i % WIDTH, i / WIDTH), self.indices[i]))?;
         }
         tuple.end()
     }
@@ -104,20 +343,63 @@ impl<'de> Deserialize<'de> for Frame {
             type Value = Frame;
 
             fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
-                write!(f, "{} pixel color values", WIDTH * HEIGHT)
+                write!(f, "{PIXELS} pixel colors and shades")
             }
 
             fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
-                let frame = Frame::default();
-                for pixel in frame.0.iter().flatten() {
-                    pixel.set(
-                        seq.next_element()?
-                            .ok_or_else(|| de::Error::invalid_length(WIDTH * HEIGHT, &self))?,
-                    );
+                let mut frame = Frame::default();
+                frame.palette.clear();
+                for i in 0..PIXELS {
+                    let (pixel, index): (Pixel, u8) = seq
+                        .next_element()?
+                        .ok_or_else(|| de::Error::invalid_length(PIXELS, &self))?;
+                    let color = match frame.palette.iter().position(|&color| color == pixel) {
+                        Some(color) => color,
+                        None if frame.palette.len() < 256 => {
+                            frame.palette.push(pixel);
+                            frame.palette.len() - 1
+                        }
+                        None => return Err(de::Error::custom("more than 256 colors")),
+                    };
+                    frame.set(i % WIDTH, i / WIDTH, index & 0b11, color as u8);
                 }
+                frame.finish();
                 Ok(frame)
             }
         }
-        deserializer.deserialize_tuple(WIDTH * HEIGHT, FrameVisitor)
+        deserializer.deserialize_tuple(PIXELS, FrameVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shades_survive_round_trip() {
+        let palettes = Palettes::all(Palette::from_hex([0xE0F8D0, 0x88C070, 0x346856, 0x081820]));
+        let mut frame = Frame::default();
+        frame.set_palette(palettes.entries());
+        for shade in 0..4 {
+            frame.set(shade as usize, 0, shade, Palettes::OBJ1 + shade);
+        }
+        frame.finish();
+        let saved = rmp_serde::to_vec(&frame).unwrap();
+        let loaded: Frame = rmp_serde::from_slice(&saved).unwrap();
+        assert_eq!(loaded.as_indices(), frame.as_indices());
+        assert_eq!(loaded.as_rgb8(), frame.as_rgb8());
+        assert_eq!(loaded.pixel(3, 0), Pixel::from_hex(0x081820));
+    }
+
+    #[test]
+    fn formats_update_when_finished() {
+        let mut frame = Frame::default();
+        frame.set_palette([Pixel(255, 255, 255), Pixel(0xF8, 0x04, 0x08)]);
+        frame.set(1, 0, 3, 1);
+        assert_eq!(frame.as_rgb8()[3..6], [255, 255, 255]);
+        frame.finish();
+        assert_eq!(frame.as_rgb8()[3..6], [0xF8, 0x04, 0x08]);
+        assert_eq!(frame.as_rgba8()[4..8], [0xF8, 0x04, 0x08, 255]);
+        assert_eq!(frame.as_rgb565()[1], 0xF821);
     }
 }
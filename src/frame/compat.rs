@@ -0,0 +1,210 @@
+use super::{Palette, Palettes, Pixel};
+use crate::cart::Cart;
+
+const FOURTH_LETTER: usize = 0x0137;
+
+// The tables below are the CGB boot ROM's
+
+/// Title checksums of the carts with their own palettes. Checksums from `FIRST_DUPLICATE` on
+/// are shared by several titles, and also have to match the title's 4th letter
+const CHECKSUMS: [u8; 94] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B, 0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3,
+    0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3,
+];
+const FIRST_DUPLICATE: usize = 65;
+const FOURTH_LETTERS: &[u8; CHECKSUMS.len() - FIRST_DUPLICATE] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+/// Index into `COMBINATIONS` for each checksum
+const COMBINATION_INDICES: [u8; CHECKSUMS.len()] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44, 21, 32, 31, 20, 5, 33, 13, 14, 5, 29,
+    5, 18, 9, 3, 2, 26, 25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34, 5, 42, 6,
+    5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0, 39, 36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39,
+    24, 31, 50, 17, 46, 6, 27, 0, 47, 41, 41, 0, 0, 19, 34, 23, 18, 29,
+];
+
+/// Where the obj0, obj1 and bg palettes start in `COLORS`
+type Combination = (usize, usize, usize);
+
+/// Most combinations start at a palette in `COLORS`
+const fn combination(obj0: usize, obj1: usize, bg: usize) -> Combination {
+    (obj0 * 4, obj1 * 4, bg * 4)
+}
+
+/// A few combinations start at an arbitrary color, taking colors from two palettes
+const COMBINATIONS: [Combination; 51] = [
+    combination(4, 4, 29),
+    combination(18, 18, 18),
+    combination(20, 20, 20),
+    combination(24, 24, 24),
+    combination(9, 9, 9),
+    combination(0, 0, 0),
+    combination(27, 27, 27),
+    combination(5, 5, 5),
+    combination(12, 12, 12),
+    combination(26, 26, 26),
+    combination(16, 8, 8),
+    combination(4, 28, 28),
+    combination(4, 2, 2),
+    combination(3, 4, 4),
+    combination(4, 29, 29),
+    combination(28, 4, 28),
+    combination(2, 17, 2),
+    combination(16, 16, 8),
+    combination(4, 4, 7),
+    combination(4, 4, 18),
+    combination(4, 4, 20),
+    combination(19, 19, 9),
+    (4 * 4 - 1, 4 * 4 - 1, 11 * 4),
+    combination(17, 17, 2),
+    combination(4, 4, 2),
+    combination(4, 4, 3),
+    combination(28, 28, 0),
+    combination(3, 3, 0),
+    combination(0, 0, 1),
+    combination(18, 22, 18),
+    combination(20, 22, 20),
+    combination(24, 22, 24),
+    combination(16, 22, 8),
+    combination(17, 4, 13),
+    (28 * 4 - 1, 0, 14 * 4),
+    (28 * 4 - 1, 4 * 4, 15 * 4),
+    combination(19, 22, 9),
+    combination(16, 28, 10),
+    combination(4, 23, 28),
+    combination(17, 22, 2),
+    combination(4, 0, 2),
+    combination(4, 28, 3),
+    combination(28, 3, 0),
+    combination(3, 28, 4),
+    combination(21, 28, 4),
+    combination(3, 28, 0),
+    combination(25, 3, 28),
+    combination(0, 28, 8),
+    combination(4, 3, 28),
+    combination(28, 3, 6),
+    combination(4, 28, 29),
+];
+
+/// RGB555 colors, four per palette and lightest first
+#[rustfmt::skip]
+const COLORS: [u16; 30 * 4] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000,
+    0x639F, 0x4279, 0x15B0, 0x04CB,
+    0x7FFF, 0x6E31, 0x454A, 0x0000,
+    0x7FFF, 0x1BEF, 0x0200, 0x0000,
+    0x7FFF, 0x421F, 0x1CF2, 0x0000,
+    0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000,
+    0x7FFF, 0x03EF, 0x01D6, 0x0000,
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000,
+    0x7E74, 0x03FF, 0x0180, 0x0000,
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B,
+    0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000,
+    0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0,
+    0x03ED, 0x7FFF, 0x255F, 0x0000,
+    0x036A, 0x021F, 0x03FF, 0x7FFF,
+    0x7FFF, 0x01DF, 0x0112, 0x0000,
+    0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000,
+    0x299F, 0x001A, 0x000C, 0x0000,
+    0x7FFF, 0x027F, 0x001F, 0x0000,
+    0x7FFF, 0x03E0, 0x0206, 0x0120,
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00,
+    0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000,
+    0x03FF, 0x001F, 0x000C, 0x0000,
+    0x7FFF, 0x033F, 0x0193, 0x0000,
+    0x0000, 0x4200, 0x037F, 0x7FFF,
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000,
+    0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+fn palette(start: usize) -> Palette {
+    Palette(std::array::from_fn(|i| {
+        Pixel::from_rgb555(COLORS[start + i].to_le_bytes())
+    }))
+}
+
+/// The palettes the CGB boot ROM colorizes a DMG cart with. Carts not published by Nintendo, and
+/// carts without an entry, get the first combination
+pub fn palettes(cart: &Cart) -> Palettes {
+    let index = cart
+        .nintendo_licensed()
+        .then(|| {
+            let checksum = cart.title_checksum();
+            let fourth_letter = cart.data()[FOURTH_LETTER];
+            CHECKSUMS.iter().enumerate().position(|(i, &sum)| {
+                sum == checksum
+                    && (i < FIRST_DUPLICATE || FOURTH_LETTERS[i - FIRST_DUPLICATE] == fourth_letter)
+            })
+        })
+        .flatten()
+        .unwrap_or(0);
+    let (obj0, obj1, bg) = COMBINATIONS[COMBINATION_INDICES[index] as usize];
+    Palettes {
+        bg: palette(bg),
+        obj0: palette(obj0),
+        obj1: palette(obj1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cart(title: &[u8], old_licensee: u8) -> Cart {
+        Cart::test(&[], |data| {
+            data[0x0134..0x0134 + title.len()].copy_from_slice(title);
+            data[0x014B] = old_licensee;
+        })
+    }
+
+    #[test]
+    fn matches_title_checksum() {
+        // TETRIS
+        let palettes = self::palettes(&cart(b"TETRIS", 0x01));
+        assert_eq!(palettes, Palettes::all(palette(24 * 4)));
+        assert_eq!(palettes.bg.color(1), Pixel(0xFF, 0xFF, 0x00));
+    }
+
+    #[test]
+    fn shared_checksums_match_fourth_letter() {
+        let blue = self::palettes(&cart(b"POKEMON BLUE", 0x01));
+        assert_eq!(blue.bg, palette(28 * 4));
+        assert_eq!(blue.obj0, palette(4 * 4));
+        // same checksum, different 4th letter
+        assert_eq!(
+            self::palettes(&cart(b"POKXMOM BLUE", 0x01)),
+            self::palettes(&cart(b"", 0x01))
+        );
+    }
+
+    #[test]
+    fn colors_span_palettes() {
+        // SUPER MARIOLAND's object palettes start at the last color of one palette
+        let palettes = self::palettes(&cart(b"SUPER MARIOLAND", 0x01));
+        assert_eq!(palettes.obj0.color(0), Pixel(0, 0, 0));
+        assert_eq!(palettes.obj0.color(1), Pixel(0xFF, 0xFF, 0xFF));
+    }
+
+    #[test]
+    fn only_nintendo_carts() {
+        let default = self::palettes(&cart(b"", 0x01));
+        assert_eq!(default.bg, palette(29 * 4));
+        assert_eq!(self::palettes(&cart(b"TETRIS", 0x02)), default);
+        let new_licensee = Cart::test(&[], |data| {
+            data[0x0134..0x013A].copy_from_slice(b"TETRIS");
+            data[0x0144..0x0146].copy_from_slice(b"01");
+            data[0x014B] = 0x33;
+        });
+        assert_eq!(
+            self::palettes(&new_licensee),
+            Palettes::all(palette(24 * 4))
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use crate::frame::Frame;
+use image::{Rgb, RgbImage};
+use std::{
+    fmt::{self, Display, Formatter},
+    path::PathBuf,
+};
+
+const DIFF_COLOR: Rgb<u8> = Rgb([255, 0, 0]);
+
+/// Compares frames against reference PNGs stored in a directory
+pub struct Golden {
+    references: PathBuf,
+    output: PathBuf,
+    bless: bool,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Image(image::ImageError),
+    Missing(PathBuf),
+    Size {
+        width: u32,
+        height: u32,
+    },
+    Mismatch {
+        pixels: usize,
+        first: (u32, u32),
+        actual: PathBuf,
+        diff: PathBuf,
+    },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Image(_) => write!(f, "couldn't read or write image"),
+            Self::Missing(path) => write!(f, "no reference frame at {}", path.display()),
+            Self::Size { width, height } => {
+                write!(
+                    f,
+                    "frame size differs from the reference's {width}x{height}"
+                )
+            }
+            Self::Mismatch {
+                pixels,
+                first: (x, y),
+                actual,
+                diff,
+            } => write!(
+                f,
+                "{pixels} pixels differ, starting at ({x}, {y}), frame written to {} and diff to {}",
+                actual.display(),
+                diff.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Image(err) => Some(err),
+            Self::Missing(_) | Self::Size { .. } | Self::Mismatch { .. } => None,
+        }
+    }
+}
+
+pub fn to_image(frame: &Frame) -> RgbImage {
+    RgbImage::from_raw(
+        frame.width() as _,
+        frame.height() as _,
+        frame.as_rgb8().to_vec(),
+    )
+    .expect("buffer matches frame dimensions")
+}
+
+impl Golden {
+    /// Mismatching frames and their diff images are written to `output`. When blessing, `check`
+    /// overwrites the reference images instead of comparing against them
+    pub fn new(references: impl Into<PathBuf>, output: impl Into<PathBuf>, bless: bool) -> Self {
+        Self {
+            references: references.into(),
+            output: output.into(),
+            bless,
+        }
+    }
+
+    pub fn check(&self, name: &str, frame: &Frame) -> Result<(), Error> {
+        let reference_path = self.references.join(format!("{name}.png"));
+        let actual = to_image(frame);
+        if self.bless {
+            actual.save(&reference_path).map_err(Error::Image)?;
+            log::info!(path:? = reference_path; "blessed reference frame");
+            return Ok(());
+        }
+        if !reference_path.exists() {
+            return Err(Error::Missing(reference_path));
+        }
+        let reference = image::open(&reference_path)
+            .map_err(Error::Image)?
+            .into_rgb8();
+        if reference.dimensions() != actual.dimensions() {
+            let (width, height) = reference.dimensions();
+            return Err(Error::Size { width, height });
+        }
+
+        let mut diff = RgbImage::new(actual.width(), actual.height());
+        let mut mismatched = 0;
+        let mut first = None;
+        for ((x, y, expected), got) in reference.enumerate_pixels().zip(actual.pixels()) {
+            if expected == got {
+                // faded copy of the reference, so mismatches stand out
+                let Rgb([r, g, b]) = *expected;
+                diff.put_pixel(x, y, Rgb([r / 4 + 191, g / 4 + 191, b / 4 + 191]));
+            } else {
+                diff.put_pixel(x, y, DIFF_COLOR);
+                mismatched += 1;
+                first.get_or_insert((x, y));
+            }
+        }
+        let Some(first) = first else {
+            return Ok(());
+        };
+
+        let actual_path = self.output.join(format!("{name}.actual.png"));
+        let diff_path = self.output.join(format!("{name}.diff.png"));
+        actual.save(&actual_path).map_err(Error::Image)?;
+        diff.save(&diff_path).map_err(Error::Image)?;
+        Err(Error::Mismatch {
+            pixels: mismatched,
+            first,
+            actual: actual_path,
+            diff: diff_path,
+        })
+    }
+}
@@ -1,8 +1,13 @@
-use crate::frame::Theme;
+use crate::{
+    cart::{Cart, ColorSupport},
+    frame::Theme,
+};
 use serde::{Deserialize, Serialize};
 use std::{
     fmt::{self, Display, Formatter},
     io::Write,
+    ops::BitOr,
+    str::FromStr,
 };
 
 mod audio;
@@ -10,19 +15,28 @@ mod mem;
 mod opcode;
 mod register;
 mod render;
+mod scheduler;
+mod sgb;
 mod timer;
 mod util;
 
 pub mod cart;
 pub mod frame;
+#[cfg(feature = "golden")]
+pub mod golden;
+pub mod movie;
 pub mod system;
+pub mod trace;
+#[cfg(feature = "video")]
+pub mod video;
 
+pub use mem::{Access, Error as MemoryError, OpCacheStats};
 pub use util::ScreenPos;
 
 #[derive(Default)]
 pub struct Input {
     pub joypad: Joypad,
-    pub save_state: Option<Box<dyn Write>>,
+    pub save_state: Option<Box<dyn Write + Send>>,
 }
 
 #[derive(Copy, Clone, PartialEq, Default, Serialize, Deserialize, Debug)]
@@ -37,36 +51,221 @@ pub struct Joypad {
     pub b: bool,
 }
 
+/// Buttons held in either joypad state
+impl BitOr for Joypad {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self {
+            start: self.start || rhs.start,
+            select: self.select || rhs.select,
+            up: self.up || rhs.up,
+            down: self.down || rhs.down,
+            left: self.left || rhs.left,
+            right: self.right || rhs.right,
+            a: self.a || rhs.a,
+            b: self.b || rhs.b,
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Default, Serialize, Deserialize, Debug)]
 pub enum Mode {
     #[default]
     Dmg,
     Cgb,
+    Sgb,
+}
+
+/// Hardware revisions, which differ in the state their boot ROMs leave behind
+#[derive(Copy, Clone, PartialEq, Default, Serialize, Deserialize, Debug)]
+pub enum Model {
+    Dmg0,
+    #[default]
+    Dmg,
+    Mgb,
+    Sgb,
+    Sgb2,
+    Cgb,
+    Agb,
+}
+
+impl Model {
+    pub const ALL: [Self; 7] = [
+        Self::Dmg0,
+        Self::Dmg,
+        Self::Mgb,
+        Self::Sgb,
+        Self::Sgb2,
+        Self::Cgb,
+        Self::Agb,
+    ];
+
+    /// The mode a cart runs in. Color models run carts without CGB support in DMG mode
+    pub fn mode(self, cart: &Cart) -> Mode {
+        match self {
+            Self::Dmg0 | Self::Dmg | Self::Mgb => Mode::Dmg,
+            Self::Sgb | Self::Sgb2 => Mode::Sgb,
+            Self::Cgb | Self::Agb => match cart.color_supported() {
+                ColorSupport::No => Mode::Dmg,
+                _ => Mode::Cgb,
+            },
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Dmg0 => "dmg0",
+            Self::Dmg => "dmg",
+            Self::Mgb => "mgb",
+            Self::Sgb => "sgb",
+            Self::Sgb2 => "sgb2",
+            Self::Cgb => "cgb",
+            Self::Agb => "agb",
+        }
+    }
+}
+
+impl Display for Model {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Model {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|model| model.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("unknown model '{name}'"))
+    }
+}
+
+/// How the PPU draws pixels
+#[derive(Copy, Clone, PartialEq, Default, Serialize, Deserialize, Debug)]
+pub enum Renderer {
+    /// Pixel FIFO stepped every dot
+    #[default]
+    Fifo,
+    /// Each line drawn at once at the end of mode 3, from the registers at that point
+    Scanline,
+    /// The FIFO, checked against the scanline renderer after each line
+    Differential,
+}
+
+impl Renderer {
+    pub const ALL: [Self; 3] = [Self::Fifo, Self::Scanline, Self::Differential];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Fifo => "fifo",
+            Self::Scanline => "scanline",
+            Self::Differential => "differential",
+        }
+    }
+}
+
+impl Display for Renderer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Renderer {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|renderer| renderer.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("unknown renderer '{name}'"))
+    }
+}
+
+/// When the timer, serial, OAM DMA and PPU are ticked
+#[derive(Copy, Clone, PartialEq, Default, Serialize, Deserialize, Debug)]
+pub enum Scheduling {
+    /// Only on the dots they have something to do, catching up on the others when they're next
+    /// ticked or their state can be seen. The CPU runs an op's M-cycles back to back until an
+    /// event is due
+    #[default]
+    Events,
+    /// Every dot, along with the CPU
+    Dots,
+}
+
+impl Scheduling {
+    pub const ALL: [Self; 2] = [Self::Events, Self::Dots];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Events => "events",
+            Self::Dots => "dots",
+        }
+    }
+}
+
+impl Display for Scheduling {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Scheduling {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|scheduling| scheduling.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| format!("unknown scheduling '{name}'"))
+    }
+}
+
+impl Mode {
+    /// Size of the frames the system outputs
+    pub fn screen_size(self) -> (usize, usize) {
+        match self {
+            Self::Dmg | Self::Cgb => (frame::WIDTH, frame::HEIGHT),
+            Self::Sgb => (frame::SGB_WIDTH, frame::SGB_HEIGHT),
+        }
+    }
 }
 
 #[derive(Default)]
 pub struct Options {
     pub theme: Theme,
+    pub renderer: Renderer,
+    pub scheduling: Scheduling,
     pub short_circuit: Option<u64>,
     pub debug: bool,
     pub strict_mem_access: bool,
+    /// Illegal opcodes freeze the CPU like on hardware, instead of returning an error
+    pub lock_up: bool,
     pub skip_boot: bool,
     pub symbols: Option<String>,
     pub breakpoints: Vec<String>,
+    pub trace: Option<trace::Trace>,
 }
 
 impl Display for Options {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         writeln!(
             f,
-            "theme - {:?}, short_circuit - {:?}, debug - {}, strict_mem_access - {}, skip_boot - {}, symbols - {}, breakpoints - {}",
+            "theme - {:?}, renderer - {}, scheduling - {}, short_circuit - {:?}, debug - {}, strict_mem_access - {}, lock_up - {}, skip_boot - {}, symbols - {}, breakpoints - {}, trace - {}",
             self.theme,
+            self.renderer,
+            self.scheduling,
             self.short_circuit,
             self.debug,
             self.strict_mem_access,
+            self.lock_up,
             self.skip_boot,
             self.symbols.is_some(),
-            self.breakpoints.len()
+            self.breakpoints.len(),
+            self.trace.is_some()
         )
     }
 }
@@ -78,3 +277,26 @@ pub enum SymbolError {
     BreakpointNotFound(String),
     NoneLoaded,
 }
+
+impl Display for SymbolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(_) => write!(f, "couldn't read symbols"),
+            Self::Parse(_) => write!(f, "couldn't parse symbol address"),
+            Self::BreakpointNotFound(breakpoint) => {
+                write!(f, "'{breakpoint}' not found in symbols")
+            }
+            Self::NoneLoaded => write!(f, "no symbols loaded"),
+        }
+    }
+}
+
+impl std::error::Error for SymbolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            Self::Parse(err) => Some(err),
+            Self::BreakpointNotFound(_) | Self::NoneLoaded => None,
+        }
+    }
+}
@@ -1,17 +1,25 @@
 mod mbc;
+mod op_cache;
+mod pages;
 
 use crate::{
-    Joypad, Mode,
-    cart::Cart,
+    Joypad, Mode, Model,
+    cart::{self, Cart},
     frame::Rgb555,
-    mem::mbc::{Mbc, Mbc1ExtBank},
+    mem::{
+        mbc::Mbc,
+        op_cache::{Key, OpCache},
+        pages::{PAGE_SIZE, Page, Pages},
+    },
     opcode::{self, Op},
-    timer::Timer,
-    util::Hex,
+    sgb::Sgb,
+    timer::{self, Timer},
 };
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteArray;
-use std::fmt::Write;
+use std::fmt::{self, Display, Formatter, Write};
+
+pub use op_cache::OpCacheStats;
 
 pub const ROM_BANK_0_START: u16 = 0x0000;
 pub const ROM_BANK_N_START: u16 = 0x4000;
@@ -86,8 +94,17 @@ pub const OBJ_PRIORITY_MODE_REG: u16 = 0xFF6C;
 pub const WRAM_BANK_REG: u16 = 0xFF70;
 pub const IE_REG: u16 = 0xFFFF;
 
+/// The ® drawn after the logo by the DMG boot ROMs
+const REGISTERED_TILE: [u8; 8] = [0x3C, 0x42, 0xB9, 0xA5, 0xB9, 0xA5, 0x42, 0x3C];
+
 const TILES_LEN: usize = (0x9800 - VRAM_START) as usize / std::mem::size_of::<Tile>();
 
+/// Dots from writing the OAM DMA register to copying the last byte
+const OAM_DMA_END: u16 = 161 * 4;
+
+/// Dots per bit of a serial transfer on the internal 8192 Hz clock
+const SERIAL_BIT_DOTS: u16 = 512;
+
 pub type Tile = [(u8, u8); 8];
 
 #[derive(Serialize, Deserialize)]
@@ -99,6 +116,12 @@ pub struct Memory {
     cart: Cart,
     #[serde(skip)]
     strict_mem_access: bool,
+    #[serde(skip)]
+    pages: Pages,
+    #[serde(skip)]
+    op_cache: OpCache,
+    #[serde(skip)]
+    io_written: bool,
     mbc: Mbc,
     lock: Lock,
     #[serde(with = "serde_bytes")]
@@ -111,12 +134,15 @@ pub struct Memory {
     joypad: Joypad,
     joypad_reg: u8,
     serial_transfer: [u8; 2],
+    /// Dots into a transfer clocked by this side of the link
+    #[serde(default)]
+    serial_dots: Option<u16>,
     timer: Timer,
     interrupts: u8,
     audio: Audio,
     lcd: Lcd,
     oam_dma: u8,
-    oam_dma_ticks: Option<u16>,
+    oam_dma_transfer: Option<OamDma>,
     cgb_key0: u8,
     cgb_key1: u8,
     cgb_vram_bank: u8,
@@ -130,13 +156,107 @@ pub struct Memory {
     #[serde(with = "serde_bytes")]
     hram: [u8; (HRAM_END - HRAM_START) as _],
     ie: u8,
+    #[serde(default)]
+    sgb: Option<Box<Sgb>>,
 }
 
 #[derive(Debug)]
 pub enum Error {
-    Op(opcode::Error),
-    OutOfBounds(u16),
-    SegFault,
+    /// The bytes at `addr` aren't an op
+    Op { addr: u16, source: opcode::Error },
+    /// An access to an unmapped address, with strict memory access
+    OutOfBounds { addr: u16, access: Access },
+    /// An access running past the end of its region
+    SegFault { addr: u16, access: Access },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Op { addr, .. } => write!(f, "couldn't decode the op at {addr:04X}"),
+            Self::OutOfBounds { addr, access } => {
+                write!(f, "{access} of unmapped address {addr:04X}")
+            }
+            Self::SegFault { addr, access } => {
+                write!(f, "{access} at {addr:04X} runs past the end of its region")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Op { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum Access {
+    Read,
+    Write,
+    /// Reading an op for the CPU to run
+    Fetch,
+}
+
+impl Display for Access {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Read => "read",
+            Self::Write => "write",
+            Self::Fetch => "fetch",
+        })
+    }
+}
+
+/// An op being fetched a byte per M-cycle, opcode first, through the same bus as other reads
+#[derive(Copy, Clone, Serialize, Deserialize, Debug)]
+pub struct Fetch {
+    pc: u16,
+    bytes: [u8; 3],
+    fetched: u8,
+    /// Known once the opcode is fetched
+    len: u8,
+    /// HALT didn't increment pc, so the opcode is fetched again as the first operand
+    halt_bug: bool,
+    /// The op from the cache, which can only be used if no byte was read from a blocked bus
+    cached: Option<Op>,
+    blocked: bool,
+}
+
+impl Fetch {
+    pub fn new(pc: u16, halt_bug: bool) -> Self {
+        Self {
+            pc,
+            bytes: [0; 3],
+            fetched: 0,
+            len: 1,
+            halt_bug,
+            cached: None,
+            blocked: false,
+        }
+    }
+
+    /// Whether bytes of the op are left to fetch
+    pub fn pending(&self) -> bool {
+        self.fetched < self.len
+    }
+
+    /// Where the next op starts, once the opcode is fetched
+    pub fn end(&self) -> u16 {
+        self.next_addr_after(self.len)
+    }
+
+    fn next_addr(&self) -> u16 {
+        self.next_addr_after(self.fetched)
+    }
+
+    fn next_addr_after(&self, fetched: u8) -> u16 {
+        let repeated = (self.halt_bug && fetched > 0) as u16;
+        self.pc.wrapping_add(fetched.into()).wrapping_sub(repeated)
+    }
 }
 
 #[derive(PartialEq, Copy, Clone, Serialize, Deserialize, Debug)]
@@ -146,6 +266,16 @@ pub enum Lock {
     VramOam,
 }
 
+#[derive(Copy, Clone, Serialize, Deserialize, Debug)]
+struct OamDma {
+    /// Dots since the transfer started. After an M-cycle of setup, a byte is copied every M-cycle
+    dots: u16,
+    /// Restarting a transfer keeps the bus blocked through its setup
+    restarted: bool,
+    /// The byte last copied, which the CPU reads from a blocked bus
+    byte: u8,
+}
+
 #[derive(Default, Serialize, Deserialize, Debug)]
 struct Audio {
     master: u8,
@@ -174,6 +304,9 @@ struct Audio {
     ch4_volume_env: u8,
     ch4_freq_rand: u8,
     ch4_ctrl: u8,
+    /// The step of the length, sweep and envelope clocks, advanced by DIV-APU
+    #[serde(default)]
+    frame_sequencer: u8,
 }
 
 #[derive(Default, Serialize, Deserialize, Debug)]
@@ -195,14 +328,23 @@ struct Lcd {
 }
 
 impl Memory {
-    pub fn init(boot_rom: Vec<u8>, cart: Cart, mode: Mode, strict_mem_access: bool) -> Self {
+    pub fn init(
+        boot_rom: Vec<u8>,
+        cart: Cart,
+        mode: Mode,
+        strict_mem_access: bool,
+    ) -> Result<Self, cart::Error> {
         let is_cgb = mode == Mode::Cgb;
-        let mbc = Mbc::from_cart(&cart);
-        Self {
+        let mbc = Mbc::from_cart(&cart)?;
+        let sgb = (mode == Mode::Sgb).then(|| Box::new(Sgb::new(cart.sgb_supported())));
+        let mut memory = Self {
             mode,
             boot_rom,
             cart,
             strict_mem_access,
+            pages: Default::default(),
+            op_cache: Default::default(),
+            io_written: false,
             mbc,
             lock: Lock::Unlocked,
             vram: [0; _],
@@ -213,12 +355,13 @@ impl Memory {
             joypad: Default::default(),
             joypad_reg: 0,
             serial_transfer: [0, 0],
+            serial_dots: None,
             timer: Default::default(),
             interrupts: 0,
             audio: Default::default(),
             lcd: Default::default(),
             oam_dma: 0,
-            oam_dma_ticks: None,
+            oam_dma_transfer: None,
             cgb_key0: 0,
             cgb_key1: 0,
             cgb_vram_bank: 0,
@@ -231,47 +374,201 @@ impl Memory {
             cgb_wram_bank: 0,
             hram: [0; _],
             ie: 0,
-        }
+            sgb,
+        };
+        memory.map_pages();
+        Ok(memory)
     }
 
     pub fn bank(&self, addr: u16) -> Option<u16> {
         self.mbc.bank_and_cart_addr(addr).map(|(bank, _)| bank)
     }
 
+    /// Restore the cart after loading a save state
     pub fn set_cart(&mut self, cart: Cart) {
         self.cart = cart;
+        self.map_pages();
     }
 
-    pub fn reset_mbc(&mut self) {
-        self.mbc = Mbc::from_cart(&self.cart);
+    /// Set up the IO registers and VRAM as the model's boot ROM leaves them, and unmap it
+    pub fn post_boot(&mut self, model: Model) {
+        let color = matches!(model, Model::Cgb | Model::Agb);
+        self.boot_rom_ctrl = 0x01;
+        self.map_cart();
+        self.select_joypad(0x00);
+        self.serial_transfer = [0x00, if color { 0x7F } else { 0x7E }];
+        // the lower byte is only known for DMG, and the others depend on the cart header
+        let counter = match model {
+            Model::Dmg0 => 0x1800,
+            Model::Dmg | Model::Mgb => 0xABCC,
+            Model::Sgb | Model::Sgb2 | Model::Cgb | Model::Agb => 0x0000,
+        };
+        self.timer.set_counter(counter);
+        self.timer.tac = 0xF8;
+        // IF reads 0xE1, but its unused upper bits aren't stored
+        self.interrupts = 0x01;
+        self.audio = Audio {
+            master: if matches!(model, Model::Sgb | Model::Sgb2) {
+                0xF0
+            } else {
+                0xF1
+            },
+            panning: 0xF3,
+            vin_volume: 0x77,
+            ch1_sweep: 0x80,
+            ch1_duty_length: 0xBF,
+            ch1_volume_env: 0xF3,
+            ch1_period_low: 0xFF,
+            ch1_period_high_ctrl: 0xBF,
+            ch2_duty_length: 0x3F,
+            ch2_period_low: 0xFF,
+            ch2_period_high_ctrl: 0xBF,
+            ch3_dac: 0x7F,
+            ch3_length: 0xFF,
+            ch3_output_level: 0x9F,
+            ch3_period_low: 0xFF,
+            ch3_period_high_ctrl: 0xBF,
+            ch4_length: 0xFF,
+            ch4_ctrl: 0xBF,
+            ..Default::default()
+        };
+        self.lcd.ctrl = 0x91;
+        self.lcd.stat = 0x85;
+        self.lcd.bg_palette = 0xFC;
+        self.oam_dma = if color { 0x00 } else { 0xFF };
+        if self.mode == Mode::Cgb {
+            self.cgb_key0 = self.cart.data()[0x0143];
+        } else if color {
+            // DMG compatibility mode
+            self.cgb_key0 = 0x04;
+        }
+
+        // the DMG boot ROMs leave the logo in VRAM, scrolled into the middle of the screen
+        if matches!(model, Model::Dmg0 | Model::Dmg | Model::Mgb) {
+            let logo = &self.cart.data()[0x0104..0x0134];
+            // each nibble of the logo is doubled in width and height, with 2 logo bytes per tile
+            let rows = logo.iter().flat_map(|&byte| {
+                [byte >> 4, byte & 0x0F].map(|nibble| {
+                    (0..4).fold(0u8, |acc, bit| {
+                        acc | (((nibble >> bit) & 1) * (0b11 << (bit * 2)))
+                    })
+                })
+            });
+            for (i, row) in rows.enumerate() {
+                // rows are doubled, and only use the low bitplane
+                self.vram[0x10 + i * 4] = row;
+                self.vram[0x10 + i * 4 + 2] = row;
+            }
+            for (i, &row) in REGISTERED_TILE.iter().enumerate() {
+                self.vram[0x190 + i * 2] = row;
+            }
+            for tile in 1..=12u8 {
+                self.vram[0x1903 + tile as usize] = tile;
+                self.vram[0x1923 + tile as usize] = tile + 12;
+            }
+            self.vram[0x1910] = 0x19;
+        }
+    }
+
+    pub fn sgb_mut(&mut self) -> Option<&mut Sgb> {
+        self.sgb.as_deref_mut()
     }
 
     pub fn tick(&mut self) -> Result<(), Error> {
-        let timer_result = self.timer.tick();
-        if timer_result.interrupt {
-            self.interrupts |= 0b00000100;
+        self.tick_timer();
+        self.tick_serial();
+        self.tick_oam_dma()
+    }
+
+    pub fn tick_timer(&mut self) {
+        let timer_result = self.timer.tick(self.double_speed());
+        self.handle_timer(timer_result);
+    }
+
+    /// Dots before the timer next does more than count
+    pub fn timer_idle_dots(&self) -> u64 {
+        self.timer.idle_ticks(self.double_speed())
+    }
+
+    pub fn skip_timer(&mut self, dots: u64) {
+        self.timer.skip(dots);
+    }
+
+    /// Without a link partner, each bit shifted out of SB is replaced by a 1
+    pub fn tick_serial(&mut self) {
+        let Some(dots) = self.serial_dots.map(|dots| dots + 1) else {
+            return;
+        };
+        if dots.is_multiple_of(SERIAL_BIT_DOTS) {
+            self.serial_transfer[0] = (self.serial_transfer[0] << 1) | 1;
         }
-        if timer_result.div_apu {
-            // TODO
+        self.serial_dots = Some(dots);
+        if dots == 8 * SERIAL_BIT_DOTS {
+            self.serial_dots = None;
+            self.serial_transfer[1] &= 0b01111111;
+            self.interrupts |= 0b00001000;
         }
-        match &mut self.oam_dma_ticks {
-            Some(0) => {
-                self.oam_dma_ticks = None;
-                let source = (self.oam_dma as u16) << 8;
-                for offset in 0..160 {
-                    self.write(OAM_START + offset, self.read(source + offset)?)?;
-                }
+    }
+
+    /// Dots before the serial transfer next shifts a bit, which it won't until it's started
+    pub fn serial_idle_dots(&self) -> u64 {
+        match self.serial_dots {
+            Some(dots) => (SERIAL_BIT_DOTS - 1 - dots % SERIAL_BIT_DOTS).into(),
+            None => u64::MAX,
+        }
+    }
+
+    pub fn skip_serial(&mut self, dots: u64) {
+        if let Some(serial_dots) = &mut self.serial_dots {
+            *serial_dots += dots as u16;
+        }
+    }
+
+    pub fn tick_oam_dma(&mut self) -> Result<(), Error> {
+        if let Some(mut dma) = self.oam_dma_transfer {
+            dma.dots += 1;
+            if dma.dots.is_multiple_of(4) && (8..=OAM_DMA_END).contains(&dma.dots) {
+                let offset = dma.dots / 4 - 2;
+                dma.byte = self.read_ppu(((self.oam_dma as u16) << 8) + offset)?;
+                self.oam[offset as usize] = dma.byte;
             }
-            Some(ticks) => *ticks -= 1,
-            None => {}
+            // the bus is blocked until the M-cycle copying the last byte is over
+            self.oam_dma_transfer = (dma.dots <= OAM_DMA_END).then_some(dma);
         }
         Ok(())
     }
 
+    /// Dots before OAM DMA next copies a byte, which it won't until it's started
+    pub fn oam_dma_idle_dots(&self) -> u64 {
+        if self.oam_dma_transfer.is_some() {
+            0
+        } else {
+            u64::MAX
+        }
+    }
+
+    /// Whether the CPU wrote an IO register since the last call
+    pub fn take_io_written(&mut self) -> bool {
+        std::mem::take(&mut self.io_written)
+    }
+
+    fn handle_timer(&mut self, result: timer::Result) {
+        if result.interrupt {
+            self.interrupts |= 0b00000100;
+        }
+        if result.div_apu {
+            self.audio.frame_sequencer = (self.audio.frame_sequencer + 1) % 8;
+        }
+    }
+
+    fn double_speed(&self) -> bool {
+        self.cgb_key1 & 0b10000000 != 0
+    }
+
     pub fn set_joypad(&mut self, joypad: Joypad) {
         let joyp_before = self.joypad_reg;
         self.joypad = joypad;
-        self.write(JOYPAD_REG, joyp_before).expect("valid address");
+        self.select_joypad(joyp_before);
         if self.read(JOYPAD_REG).unwrap() != 0xff {
             log::info!("joyp_reg: {:b}", self.read(JOYPAD_REG).unwrap());
         }
@@ -284,136 +581,199 @@ impl Memory {
         }
     }
 
+    /// Update the joypad register for the buttons selected by its P14 and P15 bits
+    fn select_joypad(&mut self, selection: u8) {
+        // with several SGB joypads, the lower bits identify the selected joypad when neither
+        // P14 or P15 are low. Only the first joypad's buttons are emulated
+        let player = self.sgb.as_ref().map_or(0, |sgb| sgb.player());
+        let joypad = if player == 0 {
+            self.joypad
+        } else {
+            Joypad::default()
+        };
+        // bits are inverted. 0 = on, 1 = off
+        self.joypad_reg = match (selection & 0b00100000 != 0, selection & 0b00010000 != 0) {
+            (true, true) => 0xFF - player,
+            (true, false) => {
+                0b11101111
+                    & if joypad.right { 0b11111110 } else { 0xFF }
+                    & if joypad.left { 0b11111101 } else { 0xFF }
+                    & if joypad.up { 0b11111011 } else { 0xFF }
+                    & if joypad.down { 0b11110111 } else { 0xFF }
+            }
+            (false, true) => {
+                0b11011111
+                    & if joypad.a { 0b11111110 } else { 0xFF }
+                    & if joypad.b { 0b11111101 } else { 0xFF }
+                    & if joypad.select { 0b11111011 } else { 0xFF }
+                    & if joypad.start { 0b11110111 } else { 0xFF }
+            }
+            (false, false) => {
+                0b11001111
+                    & if joypad.right || joypad.a {
+                        0b11111110
+                    } else {
+                        0xFF
+                    }
+                    & if joypad.left || joypad.b {
+                        0b11111101
+                    } else {
+                        0xFF
+                    }
+                    & if joypad.up || joypad.select {
+                        0b11111011
+                    } else {
+                        0xFF
+                    }
+                    & if joypad.down || joypad.start {
+                        0b11110111
+                    } else {
+                        0xFF
+                    }
+            }
+        };
+    }
+
     pub fn set_lock(&mut self, lock: Lock) {
         self.lock = lock;
     }
 
     pub fn read(&self, addr: u16) -> Result<u8, Error> {
-        self.read_inner(addr, false).map(|mem| mem[0])
+        self.read_inner(addr, false, Access::Read).map(|mem| mem[0])
     }
 
     pub fn read_ppu(&self, addr: u16) -> Result<u8, Error> {
-        self.read_inner(addr, true).map(|mem| mem[0])
+        self.read_inner(addr, true, Access::Read).map(|mem| mem[0])
     }
 
-    pub fn read_op(&self, pc: u16) -> Result<(Op, u16), Error> {
-        let mem = self.read_inner(pc, false)?;
-        Op::decode(mem)
-            .map(|(op, new_mem)| (op, pc + (mem.len() - new_mem.len()) as u16))
-            .map_err(Error::Op)
+    /// Fetch the op's next byte, returning the op once all its bytes are fetched. Ops from ROM
+    /// and WRAM are decoded once and cached
+    pub fn fetch_op(&mut self, fetch: &mut Fetch) -> Result<Option<Op>, Error> {
+        let addr = fetch.next_addr();
+        fetch.blocked |= self.oam_dma_conflict(addr).is_some();
+        let byte = self.read_inner(addr, false, Access::Fetch)?[0];
+        fetch.bytes[fetch.fetched as usize] = byte;
+        fetch.fetched += 1;
+        if fetch.fetched == 1 {
+            fetch.cached = match self.op_cache.get(self.op_key(fetch)) {
+                Some((op, len)) => {
+                    fetch.len = len;
+                    Some(op)
+                }
+                // an op's length only depends on its opcode
+                None => {
+                    fetch.len = decode(&[byte, 0, 0], fetch.pc)?.1;
+                    None
+                }
+            };
+        }
+        if fetch.pending() {
+            return Ok(None);
+        }
+        match fetch.cached {
+            Some(op) if !fetch.blocked => Ok(Some(op)),
+            _ => {
+                let (op, len) = decode(&fetch.bytes[..fetch.len.into()], fetch.pc)?;
+                if let Some(key) = self.op_key(fetch) {
+                    self.op_cache.insert(key, op, len);
+                }
+                Ok(Some(op))
+            }
+        }
+    }
+
+    /// Where the op is cached, if its bytes are the ones in memory
+    fn op_key(&self, fetch: &Fetch) -> Option<Key> {
+        if fetch.halt_bug || fetch.blocked {
+            return None;
+        }
+        Key::new(self.pages.get(fetch.pc), fetch.pc)
+    }
+
+    pub fn op_cache_stats(&self) -> OpCacheStats {
+        self.op_cache.stats()
     }
 
     pub fn oam(&self) -> &[u8; 160] {
-        &self.oam
+        if self.oam_dma_blocking() {
+            &[0xFF; 160]
+        } else {
+            &self.oam
+        }
+    }
+
+    fn oam_dma_blocking(&self) -> bool {
+        self.oam_dma_transfer
+            .is_some_and(|dma| dma.restarted || dma.dots >= 8)
+    }
+
+    /// The byte being copied by OAM DMA, if it's using the address's bus. The CPU keeps access
+    /// to IO and HRAM, and to VRAM or the external bus when the transfer uses the other one
+    fn oam_dma_conflict(&self, addr: u16) -> Option<&u8> {
+        let vram = |addr| (VRAM_START..SRAM_START).contains(&addr);
+        let source = (self.oam_dma as u16) << 8;
+        (self.oam_dma_blocking() && addr < OAM_START && vram(addr) == vram(source))
+            .then(|| &self.oam_dma_transfer.as_ref().expect("blocking").byte)
     }
 
-    fn read_inner(&self, addr: u16, ppu: bool) -> Result<&[u8], Error> {
+    fn read_inner(&self, addr: u16, ppu: bool, access: Access) -> Result<&[u8], Error> {
         fn as_slice(byte: &u8) -> &[u8] {
             std::slice::from_ref(byte)
         }
 
-        match addr {
-            ROM_BANK_0_START..ROM_BANK_N_START
-                if (addr as usize) < self.boot_rom.len() && self.read(BOOT_ROM_CTRL_REG)? == 0 =>
-            {
-                Ok(&self.boot_rom[addr.into()..])
-            }
+        if !ppu && let Some(byte) = self.oam_dma_conflict(addr) {
+            return Ok(as_slice(byte));
+        }
 
-            ROM_BANK_0_START..VRAM_START => {
-                let (_, addr) = self
-                    .mbc
-                    .bank_and_cart_addr(addr)
-                    .ok_or(Error::OutOfBounds(addr))?;
-                Ok(&self.cart.data()[addr..])
+        let offset = addr as usize % PAGE_SIZE;
+        match self.pages.get(addr) {
+            Page::BootRom => Ok(&self.boot_rom[addr.into()..]),
+            Page::Rom(start) => Ok(&self.cart.data()[start + offset..]),
+            Page::Vram { .. } if !ppu && self.lock == Lock::VramOam => Ok(&[0xFF; 16]),
+            Page::Vram { bank: 0, start } => Ok(&self.vram[start + offset..]),
+            Page::Vram { start, .. } => {
+                Ok(&self.vram_cgb.as_ref().expect("is_some if cgb")[start + offset..])
             }
-
-            VRAM_START..SRAM_START => {
-                if !ppu && self.lock == Lock::VramOam {
-                    Ok(&[0xFF; 16])
-                } else {
-                    match self.mode {
-                        Mode::Cgb if self.read(VRAM_BANK_REG)? != 0 => {
-                            Ok(&self.vram_cgb.as_ref().expect("is_some if cgb")
-                                [(addr - VRAM_START).into()..])
-                        }
-                        _ => Ok(&self.vram[(addr - VRAM_START).into()..]),
-                    }
-                }
+            Page::Sram { bank, start } => Ok(&self.mbc.sram(bank)[start + offset..]),
+            Page::Wram {
+                bank: bank @ 0..2,
+                start,
+            } => Ok(&self.wram[bank][start + offset..]),
+            Page::Wram { bank, start } => {
+                Ok(&self.wram_cgb.as_ref().expect("is_some if cgb")[bank - 2][start + offset..])
             }
+            Page::Handler => self.read_io(addr, ppu, access),
+        }
+    }
+
+    /// Read from a page that isn't mapped directly: OAM, IO and HRAM, or SRAM while it's
+    /// disabled, 4-bit or replaced by the RTC
+    fn read_io(&self, addr: u16, ppu: bool, access: Access) -> Result<&[u8], Error> {
+        fn as_slice(byte: &u8) -> &[u8] {
+            std::slice::from_ref(byte)
+        }
+
+        match addr {
+            // past the end of the ROM
+            ROM_BANK_0_START..VRAM_START => Ok(&[0xFF; 16]),
 
             SRAM_START..WRAM_BANK_0_START => match &self.mbc {
-                Mbc::None { sram } => Ok(&sram[(addr - SRAM_START).into()..]),
-                Mbc::One {
-                    sram_enabled: false,
-                    ..
-                }
-                | Mbc::Two {
-                    sram_enabled: false,
-                    ..
-                }
-                | Mbc::Three {
-                    sram_and_rtc_enabled: false,
-                    ..
-                }
-                | Mbc::Five {
-                    sram_enabled: false,
-                    ..
-                } => Ok(&[0xFF; 16]),
-                Mbc::One {
-                    extended_bank: Mbc1ExtBank::Rom { sram, .. },
-                    ..
-                } => Ok(&sram[(addr - SRAM_START).into()..]),
-                Mbc::One {
-                    extended_bank:
-                        Mbc1ExtBank::Ram {
-                            advanced,
-                            sram_bank_reg,
-                            sram,
-                        },
+                Mbc::Two {
+                    sram_enabled: true,
+                    sram_4bit,
                     ..
-                } => {
-                    let sram_bank = if *advanced {
-                        *sram_bank_reg as usize
-                    } else {
-                        0
-                    };
-                    Ok(&sram[sram_bank][(addr - SRAM_START).into()..])
-                }
-                Mbc::Two { sram_4bit, .. } => Ok(&sram_4bit[(addr & 0x01FF).into()..]),
-                Mbc::Three {
-                    sram_bank_or_rtc_reg: sram_bank @ 0x00..=0x07,
-                    sram,
-                    ..
-                } => Ok(&sram[*sram_bank as usize][(addr - SRAM_START).into()..]),
+                } => Ok(&sram_4bit[(addr & 0x01FF).into()..]),
                 Mbc::Three {
-                    sram_bank_or_rtc_reg,
+                    sram_and_rtc_enabled: true,
+                    sram_bank_or_rtc_reg: rtc_reg @ 0x08..=0x0C,
                     rtc,
                     ..
-                } => Ok(&rtc[(sram_bank_or_rtc_reg - 0x08).into()..]),
-                Mbc::Five {
-                    sram_bank_reg,
-                    sram,
-                    ..
-                } => Ok(&sram[*sram_bank_reg as usize][(addr - SRAM_START).into()..]),
+                } => Ok(&rtc[(rtc_reg - 0x08).into()..]),
+                _ => Ok(&[0xFF; 16]),
             },
 
-            WRAM_BANK_0_START..WRAM_BANK_N_START => {
-                Ok(&self.wram[0][(addr - WRAM_BANK_0_START).into()..])
-            }
-
-            WRAM_BANK_N_START..ERAM_START => match self.mode {
-                Mode::Dmg => Ok(&self.wram[1][(addr - WRAM_BANK_N_START).into()..]),
-                _ => match self.read(WRAM_BANK_REG)? {
-                    0 | 1 => Ok(&self.wram[1][(addr - WRAM_BANK_N_START).into()..]),
-                    wram_bank => Ok(&self.wram_cgb.as_ref().expect("is_some if cgb")
-                        [wram_bank as usize][(addr - WRAM_BANK_N_START).into()..]),
-                },
-            },
-
-            ERAM_START..OAM_START => self.read_inner(addr - (ERAM_START - WRAM_BANK_0_START), ppu),
-
             OAM_START..OAM_END => {
-                if ppu || self.lock == Lock::Unlocked {
+                if ppu || (self.lock == Lock::Unlocked && !self.oam_dma_blocking()) {
                     Ok(&self.oam[(addr - OAM_START).into()..])
                 } else {
                     Ok(&[0xFF; 16])
@@ -522,7 +882,7 @@ impl Memory {
 
             IE_REG => Ok(as_slice(&self.ie)),
 
-            _ if self.strict_mem_access => Err(Error::OutOfBounds(addr)),
+            _ if self.strict_mem_access => Err(Error::OutOfBounds { addr, access }),
             _ => Ok(&[0xFF]),
         }
     }
@@ -535,253 +895,120 @@ impl Memory {
         self.write_slice_inner(addr, &[data], true)
     }
 
-    pub fn write_slice(&mut self, addr: u16, data: &[u8]) -> Result<(), Error> {
-        self.write_slice_inner(addr, data, false)
-    }
-
     fn write_slice_inner(&mut self, addr: u16, data: &[u8], ppu: bool) -> Result<(), Error> {
-        log::trace!(addr:? = Hex(addr), data:? = Hex(data); "mem write");
         if data.is_empty() {
-            return Err(Error::SegFault);
+            return Err(Error::SegFault {
+                addr,
+                access: Access::Write,
+            });
         }
 
-        fn as_slice(byte: &mut u8) -> &mut [u8] {
-            std::slice::from_mut(byte)
+        if !ppu && self.oam_dma_conflict(addr).is_some() {
+            return Ok(());
         }
 
-        let slice = match addr {
-            ROM_BANK_0_START..VRAM_START => {
-                match &mut self.mbc {
-                    Mbc::One { sram_enabled, .. } if addr < 0x2000 => {
-                        *sram_enabled = data[0] & 0b00001111 == 0x0A;
-                    }
-                    Mbc::One { rom_bank_reg, .. } if addr < 0x4000 => {
-                        *rom_bank_reg = data[0] & 0b00011111;
-                    }
-                    Mbc::One {
-                        extended_bank:
-                            Mbc1ExtBank::Ram {
-                                sram_bank_reg: target,
-                                ..
-                            }
-                            | Mbc1ExtBank::Rom {
-                                rom_bank_upper_reg: target,
-                                ..
-                            },
-                        ..
-                    } if addr < 0x6000 => {
-                        *target = data[0] & 0b00000011;
-                    }
-                    Mbc::One {
-                        extended_bank:
-                            Mbc1ExtBank::Ram { advanced, .. } | Mbc1ExtBank::Rom { advanced, .. },
-                        ..
-                    } => {
-                        *advanced = data[0] % 2 == 1;
-                    }
-                    Mbc::Two {
-                        rom_bank_reg,
-                        sram_enabled,
-                        ..
-                    } if addr < 0x4000 => {
-                        if addr & 0x0100 == 0 {
-                            *sram_enabled = data[0] & 0b00001111 == 0x0A;
-                        } else {
-                            *rom_bank_reg = data[0] & 0b00001111;
-                        }
-                    }
-                    Mbc::Three {
-                        sram_and_rtc_enabled,
-                        ..
-                    } if addr < 0x2000 => {
-                        *sram_and_rtc_enabled = data[0] & 0b00001111 == 0x0A;
-                    }
-                    Mbc::Three { rom_bank_reg, .. } if addr < 0x4000 => {
-                        *rom_bank_reg = data[0] & 0b01111111;
-                    }
-                    Mbc::Three {
-                        sram_bank_or_rtc_reg,
-                        ..
-                    } if addr < 0x6000 => {
-                        if data[0] > 0x0C {
-                            panic!("unexpected SRAM bank / RTC register value")
-                        };
-                        *sram_bank_or_rtc_reg = data[0];
-                    }
-                    Mbc::Three { latching, .. } => {
-                        if data[0] == 0x00 {
-                            *latching = true;
-                        } else if data[0] == 0x01 {
-                            *latching = false;
-                        }
-                    }
-                    Mbc::Five { sram_enabled, .. } if addr < 0x2000 => {
-                        *sram_enabled = data[0] & 0b00001111 == 0x0A;
-                    }
-                    Mbc::Five { rom_bank_reg, .. } if addr < 0x3000 => {
-                        *rom_bank_reg = (*rom_bank_reg & 0xFF00) + data[0] as u16;
-                    }
-                    Mbc::Five { rom_bank_reg, .. } if addr < 0x4000 => {
-                        *rom_bank_reg = ((data[0] as u16 & 0x0001) << 8) + (*rom_bank_reg & 0x00FF);
-                    }
-                    Mbc::Five { sram_bank_reg, .. } if addr < 0x6000 => {
-                        *sram_bank_reg = data[0] & 0x0F;
-                    }
-                    _ => {}
-                }
-                return Ok(());
-            }
+        // including pages past the end of the ROM
+        if addr < VRAM_START {
+            self.mbc.write(addr, data[0]);
+            self.map_cart();
+            return Ok(());
+        }
 
-            VRAM_START..SRAM_START => {
-                if self.lock == Lock::VramOam {
-                    return Ok(());
-                } else {
-                    match self.mode {
-                        Mode::Cgb if self.read(VRAM_BANK_REG)? != 0 => {
-                            &mut self.vram_cgb.as_mut().expect("is_some if cgb")
-                                [(addr - VRAM_START).into()..]
-                        }
-                        _ => &mut self.vram[(addr - VRAM_START).into()..],
-                    }
-                }
+        let offset = addr as usize % PAGE_SIZE;
+        let page = self.pages.get(addr);
+        if let Some(Key::Wram(offset)) = Key::new(page, addr) {
+            self.op_cache.invalidate_wram(offset, data.len());
+        }
+        let slice = match page {
+            Page::BootRom | Page::Rom(_) => unreachable!("ROM writes go to the MBC"),
+            Page::Vram { .. } if self.lock == Lock::VramOam => return Ok(()),
+            Page::Vram { bank: 0, start } => &mut self.vram[start + offset..],
+            Page::Vram { start, .. } => {
+                &mut self.vram_cgb.as_mut().expect("is_some if cgb")[start + offset..]
+            }
+            Page::Sram { bank, start } => &mut self.mbc.sram_mut(bank)[start + offset..],
+            Page::Wram {
+                bank: bank @ 0..2,
+                start,
+            } => &mut self.wram[bank][start + offset..],
+            Page::Wram { bank, start } => {
+                &mut self.wram_cgb.as_mut().expect("is_some if cgb")[bank - 2][start + offset..]
             }
+            Page::Handler => {
+                self.io_written |= !ppu;
+                return self.write_io(addr, data, ppu);
+            }
+        };
+        copy_to(slice, addr, data)
+    }
 
+    /// Write to a page that isn't mapped directly
+    fn write_io(&mut self, addr: u16, data: &[u8], ppu: bool) -> Result<(), Error> {
+        fn as_slice(byte: &mut u8) -> &mut [u8] {
+            std::slice::from_mut(byte)
+        }
+
+        let slice = match addr {
             SRAM_START..WRAM_BANK_0_START => match &mut self.mbc {
-                Mbc::None { sram } => &mut sram[(addr - SRAM_START).into()..],
-                Mbc::One {
-                    sram_enabled: false,
-                    ..
-                }
-                | Mbc::Two {
-                    sram_enabled: false,
-                    ..
-                }
-                | Mbc::Three {
-                    sram_and_rtc_enabled: false,
-                    ..
-                }
-                | Mbc::Five {
-                    sram_enabled: false,
-                    ..
-                } => return Ok(()),
-                Mbc::One {
-                    extended_bank: Mbc1ExtBank::Rom { sram, .. },
-                    ..
-                } => &mut sram[(addr - SRAM_START).into()..],
-                Mbc::One {
-                    extended_bank:
-                        Mbc1ExtBank::Ram {
-                            advanced,
-                            sram_bank_reg,
-                            sram,
-                        },
-                    ..
-                } => {
-                    let sram_bank = if *advanced {
-                        *sram_bank_reg as usize
-                    } else {
-                        0
-                    };
-                    &mut sram[sram_bank][(addr - SRAM_START).into()..]
-                }
-                Mbc::Two { sram_4bit, .. } => &mut sram_4bit[(addr & 0x01FF).into()..],
-                Mbc::Three {
-                    sram_bank_or_rtc_reg: sram_bank @ 0x00..=0x07,
-                    sram,
+                Mbc::Two {
+                    sram_enabled: true,
+                    sram_4bit,
                     ..
-                } => &mut sram[*sram_bank as usize][(addr - SRAM_START).into()..],
+                } => &mut sram_4bit[(addr & 0x01FF).into()..],
                 Mbc::Three {
-                    sram_bank_or_rtc_reg,
+                    sram_and_rtc_enabled: true,
+                    sram_bank_or_rtc_reg: rtc_reg @ 0x08..=0x0C,
                     rtc,
                     ..
-                } => &mut rtc[(*sram_bank_or_rtc_reg - 0x08).into()..],
-                Mbc::Five {
-                    sram_bank_reg,
-                    sram,
-                    ..
-                } => &mut sram[*sram_bank_reg as usize][(addr - SRAM_START).into()..],
-            },
-
-            WRAM_BANK_0_START..WRAM_BANK_N_START => {
-                &mut self.wram[0][(addr - WRAM_BANK_0_START).into()..]
-            }
-
-            WRAM_BANK_N_START..ERAM_START => match self.mode {
-                Mode::Dmg => &mut self.wram[1][(addr - WRAM_BANK_N_START).into()..],
-                _ => match self.read(WRAM_BANK_REG)? {
-                    0 | 1 => &mut self.wram[1][(addr - WRAM_BANK_N_START).into()..],
-                    wram_bank => &mut self.wram_cgb.as_mut().expect("is_some if cgb")
-                        [wram_bank as usize][(addr - WRAM_BANK_N_START).into()..],
-                },
+                } => &mut rtc[(*rtc_reg - 0x08).into()..],
+                _ => return Ok(()),
             },
 
-            ERAM_START..OAM_START => {
-                return self.write_slice_inner(addr - (ERAM_START - WRAM_BANK_0_START), data, ppu);
-            }
-
-            OAM_START..OAM_END if self.lock == Lock::Unlocked => {
+            OAM_START..OAM_END if self.lock == Lock::Unlocked && !self.oam_dma_blocking() => {
                 &mut self.oam[(addr - OAM_START).into()..]
             }
             OAM_START..OAM_END => return Ok(()),
 
             JOYPAD_REG => {
                 let &[selection] = data else {
-                    return Err(Error::SegFault);
-                };
-                // bits are inverted. 0 = on, 1 = off
-                self.joypad_reg = match (selection & 0b00100000 != 0, selection & 0b00010000 != 0) {
-                    (true, true) => 0xFF,
-                    (true, false) => {
-                        0b11101111
-                            & if self.joypad.right { 0b11111110 } else { 0xFF }
-                            & if self.joypad.left { 0b11111101 } else { 0xFF }
-                            & if self.joypad.up { 0b11111011 } else { 0xFF }
-                            & if self.joypad.down { 0b11110111 } else { 0xFF }
-                    }
-                    (false, true) => {
-                        0b11011111
-                            & if self.joypad.a { 0b11111110 } else { 0xFF }
-                            & if self.joypad.b { 0b11111101 } else { 0xFF }
-                            & if self.joypad.select { 0b11111011 } else { 0xFF }
-                            & if self.joypad.start { 0b11110111 } else { 0xFF }
-                    }
-                    (false, false) => {
-                        0b11001111
-                            & if self.joypad.right || self.joypad.a {
-                                0b11111110
-                            } else {
-                                0xFF
-                            }
-                            & if self.joypad.left || self.joypad.b {
-                                0b11111101
-                            } else {
-                                0xFF
-                            }
-                            & if self.joypad.up || self.joypad.select {
-                                0b11111011
-                            } else {
-                                0xFF
-                            }
-                            & if self.joypad.down || self.joypad.start {
-                                0b11110111
-                            } else {
-                                0xFF
-                            }
-                    }
+                    return Err(Error::SegFault {
+                        addr,
+                        access: Access::Write,
+                    });
                 };
+                if let Some(sgb) = &mut self.sgb {
+                    sgb.write_joypad(selection);
+                }
+                self.select_joypad(selection);
                 return Ok(());
             }
 
             SERIAL_0_REG => &mut self.serial_transfer,
-            SERIAL_1_REG => &mut self.serial_transfer[1..],
+            SERIAL_1_REG => {
+                // a transfer on the external clock never runs, as there's no link partner
+                self.serial_dots = (data[0] & 0b10000001 == 0b10000001).then_some(0);
+                &mut self.serial_transfer[1..]
+            }
 
             DIVIDER_REG => {
-                self.timer.write_div();
+                let result = self.timer.write_div(self.double_speed());
+                self.handle_timer(result);
+                return Ok(());
+            }
+            TIMER_COUNT_REG | TIMER_MOD_REG | TIMER_CTRL_REG => {
+                let &[data] = data else {
+                    return Err(Error::SegFault {
+                        addr,
+                        access: Access::Write,
+                    });
+                };
+                match addr {
+                    TIMER_COUNT_REG => self.timer.write_tima(data),
+                    TIMER_MOD_REG => self.timer.write_tma(data),
+                    _ => self.timer.write_tac(data, self.double_speed()),
+                }
                 return Ok(());
             }
-            TIMER_COUNT_REG => as_slice(&mut self.timer.tima),
-            TIMER_MOD_REG => as_slice(&mut self.timer.tma),
-            TIMER_CTRL_REG => as_slice(&mut self.timer.tac),
 
             IF_REG => as_slice(&mut self.interrupts),
 
@@ -813,7 +1040,7 @@ impl Memory {
             LCD_CTRL_REG => as_slice(&mut self.lcd.ctrl),
             LCD_STAT_REG if ppu => as_slice(&mut self.lcd.stat),
             LCD_STAT_REG => {
-                self.lcd.stat = (data[0] & 0b11111100) | (self.lcd.stat & 0b00000011);
+                self.lcd.stat = (data[0] & 0b01111000) | (self.lcd.stat & 0b10000111);
                 return Ok(());
             }
             SCROLL_Y_REG => as_slice(&mut self.lcd.scroll_y),
@@ -823,7 +1050,11 @@ impl Memory {
             LYC_REG => as_slice(&mut self.lcd.lyc),
 
             OAM_DMA_REG => {
-                self.oam_dma_ticks = Some(640);
+                self.oam_dma_transfer = Some(OamDma {
+                    dots: 0,
+                    restarted: self.oam_dma_blocking(),
+                    byte: self.oam_dma_transfer.map_or(0xFF, |dma| dma.byte),
+                });
                 as_slice(&mut self.oam_dma)
             }
 
@@ -836,9 +1067,17 @@ impl Memory {
             KEY0_REG => as_slice(&mut self.cgb_key0),
             KEY1_REG => as_slice(&mut self.cgb_key1),
 
-            VRAM_BANK_REG => as_slice(&mut self.cgb_vram_bank),
+            VRAM_BANK_REG => {
+                self.cgb_vram_bank = data[0];
+                self.map_vram();
+                return Ok(());
+            }
 
-            BOOT_ROM_CTRL_REG => as_slice(&mut self.boot_rom_ctrl),
+            BOOT_ROM_CTRL_REG => {
+                self.boot_rom_ctrl = data[0];
+                self.map_cart();
+                return Ok(());
+            }
 
             VRAM_DMA_SRC_0_REG => &mut self.cgb_vram_dma_src,
             VRAM_DMA_SRC_1_REG => &mut self.cgb_vram_dma_src[1..],
@@ -879,22 +1118,25 @@ impl Memory {
 
             OBJ_PRIORITY_MODE_REG => as_slice(&mut self.cgb_obj_priority),
 
-            WRAM_BANK_REG => as_slice(&mut self.cgb_wram_bank),
+            WRAM_BANK_REG => {
+                self.cgb_wram_bank = data[0];
+                self.map_wram();
+                return Ok(());
+            }
 
             HRAM_START..HRAM_END => &mut self.hram[(addr - HRAM_START).into()..],
 
             IE_REG => as_slice(&mut self.ie),
 
-            _ if self.strict_mem_access => return Err(Error::OutOfBounds(addr)),
+            _ if self.strict_mem_access => {
+                return Err(Error::OutOfBounds {
+                    addr,
+                    access: Access::Write,
+                });
+            }
             _ => return Ok(()),
         };
-
-        if slice.len() < data.len() {
-            Err(Error::SegFault)
-        } else {
-            slice[..data.len()].copy_from_slice(data);
-            Ok(())
-        }
+        copy_to(slice, addr, data)
     }
 
     pub fn log_registers(&self) {
@@ -945,7 +1187,7 @@ impl Memory {
         let mut prev = None;
         for (r#for, name, addr) in registers {
             match (self.mode, r#for) {
-                (Mode::Dmg, For::Only(Mode::Dmg))
+                (Mode::Dmg | Mode::Sgb, For::Only(Mode::Dmg))
                 | (Mode::Cgb, For::Only(Mode::Cgb))
                 | (_, For::Both) => {
                     if let Some((prev_name, prev_addr)) = prev.take() {
@@ -1054,3 +1296,203 @@ impl Memory {
         })
     }
 }
+
+/// Decode the op read from `addr`, returning its length
+fn decode(mem: &[u8], addr: u16) -> Result<(Op, u8), Error> {
+    Op::decode(mem)
+        .map(|(op, rest)| (op, (mem.len() - rest.len()) as u8))
+        .map_err(|source| Error::Op { addr, source })
+}
+
+fn copy_to(slice: &mut [u8], addr: u16, data: &[u8]) -> Result<(), Error> {
+    if slice.len() < data.len() {
+        Err(Error::SegFault {
+            addr,
+            access: Access::Write,
+        })
+    } else {
+        slice[..data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory(header: impl FnOnce(&mut Vec<u8>)) -> Memory {
+        Memory::init(vec![], Cart::test(&[], header), Mode::Dmg, false).unwrap()
+    }
+
+    /// Fetch all of the op's bytes
+    fn fetch_op(memory: &mut Memory, pc: u16, halt_bug: bool) -> (Op, u16) {
+        let mut fetch = Fetch::new(pc, halt_bug);
+        loop {
+            if let Some(op) = memory.fetch_op(&mut fetch).unwrap() {
+                return (op, fetch.end());
+            }
+        }
+    }
+
+    #[test]
+    fn halt_bug_fetch_wraps() {
+        let mut memory = memory(|_| {});
+        // ld a, d8 in IE, its operand read twice
+        memory.write(IE_REG, 0x3E).unwrap();
+        let (op, end) = fetch_op(&mut memory, 0xFFFF, true);
+        assert!(matches!(op, Op::LdR8N8(opcode::R8::A, opcode::N8(0x3E))));
+        assert_eq!(end, 0x0000);
+    }
+
+    #[test]
+    fn operands_fetched_during_oam_dma() {
+        // ld hl, $1234
+        let mut memory = Memory::init(
+            vec![],
+            Cart::test(&[0x21, 0x34, 0x12], |_| {}),
+            Mode::Dmg,
+            false,
+        )
+        .unwrap();
+        for addr in 0xC100..0xC1A0 {
+            memory.write(addr, 0x06).unwrap();
+        }
+        let mut fetch = Fetch::new(0x0150, false);
+        assert!(memory.fetch_op(&mut fetch).unwrap().is_none());
+        memory.write(OAM_DMA_REG, 0xC1).unwrap();
+        for _ in 0..12 {
+            memory.tick().unwrap();
+        }
+        // the operands come from the byte being copied
+        let op = loop {
+            if let Some(op) = memory.fetch_op(&mut fetch).unwrap() {
+                break op;
+            }
+        };
+        assert!(matches!(
+            op,
+            Op::LdR16N16(opcode::R16::Hl, opcode::N16(0x0606))
+        ));
+
+        // and the op isn't cached with them
+        for _ in 0..OAM_DMA_END {
+            memory.tick().unwrap();
+        }
+        let (op, end) = fetch_op(&mut memory, 0x0150, false);
+        assert!(matches!(
+            op,
+            Op::LdR16N16(opcode::R16::Hl, opcode::N16(0x1234))
+        ));
+        assert_eq!(end, 0x0153);
+        let (op, _) = fetch_op(&mut memory, 0x0150, false);
+        assert!(matches!(
+            op,
+            Op::LdR16N16(opcode::R16::Hl, opcode::N16(0x1234))
+        ));
+        assert_eq!(memory.op_cache_stats().hits, 1);
+    }
+
+    #[test]
+    fn oam_dma() {
+        let mut memory = memory(|_| {});
+        for offset in 0..0xA0 {
+            memory.write(0xC100 + offset, offset as u8).unwrap();
+            memory.write(OAM_START + offset, 0x55).unwrap();
+        }
+        memory.write(0x8000, 0x2A).unwrap();
+        memory.write(0xFF80, 0x2B).unwrap();
+        memory.write(OAM_DMA_REG, 0xC1).unwrap();
+        // the transfer starts an M-cycle after the write
+        for _ in 0..4 {
+            memory.tick().unwrap();
+        }
+        assert_eq!(memory.read(OAM_START).unwrap(), 0x55);
+        for _ in 0..8 {
+            memory.tick().unwrap();
+        }
+        // the external bus reads the byte being copied, OAM is blocked, VRAM and HRAM aren't
+        assert_eq!(memory.read(0x0150).unwrap(), 0x01);
+        assert_eq!(memory.read(0xC000).unwrap(), 0x01);
+        assert_eq!(memory.read(OAM_START).unwrap(), 0xFF);
+        assert_eq!(memory.read(0x8000).unwrap(), 0x2A);
+        assert_eq!(memory.read(0xFF80).unwrap(), 0x2B);
+
+        for _ in 12..=OAM_DMA_END {
+            memory.tick().unwrap();
+        }
+        let oam: Vec<_> = (0..0xA0).map(|offset| offset as u8).collect();
+        assert_eq!(memory.oam()[..], oam[..]);
+        assert_eq!(memory.read(0xC000).unwrap(), 0x00);
+    }
+
+    #[test]
+    fn serial_transfer_without_partner() {
+        let mut memory = memory(|_| {});
+        memory.write(SERIAL_0_REG, 0x0F).unwrap();
+        memory.write(SERIAL_1_REG, 0x81).unwrap();
+        for _ in 0..4 * SERIAL_BIT_DOTS {
+            memory.tick().unwrap();
+        }
+        assert_eq!(memory.read(SERIAL_0_REG).unwrap(), 0xFF);
+        assert_eq!(memory.read(IF_REG).unwrap() & 0b1000, 0);
+        for _ in 0..4 * SERIAL_BIT_DOTS {
+            memory.tick().unwrap();
+        }
+        assert_eq!(memory.read(SERIAL_1_REG).unwrap(), 0x01);
+        assert_ne!(memory.read(IF_REG).unwrap() & 0b1000, 0);
+
+        // on the external clock, the transfer waits for a partner
+        memory.write(SERIAL_1_REG, 0x80).unwrap();
+        for _ in 0..8 * SERIAL_BIT_DOTS {
+            memory.tick().unwrap();
+        }
+        assert_eq!(memory.read(SERIAL_1_REG).unwrap(), 0x80);
+    }
+
+    #[test]
+    fn rom_banks_wrap() {
+        // MBC5 with 4 banks, the last one marked
+        let mut memory = memory(|data| {
+            data[0x0147] = 0x19;
+            data[0x0148] = 0x01;
+            data.resize(0x10000, 0);
+            data[0xC000] = 0x2A;
+        });
+        memory.write(0x2000, 0xFF).unwrap();
+        memory.write(0x3000, 0x01).unwrap();
+        assert_eq!(memory.read(0x4000).unwrap(), 0x2A);
+    }
+
+    #[test]
+    fn truncated_rom_reads_open_bus() {
+        // only whole pages are mapped
+        let mut memory = memory(|data| data.truncate(0x7F80));
+        assert_eq!(memory.read(0x7EFF).unwrap(), 0x00);
+        assert_eq!(memory.read(0x7F00).unwrap(), 0xFF);
+        assert_eq!(memory.read(0x7FFF).unwrap(), 0xFF);
+        let mut fetch = Fetch::new(0x7FFF, false);
+        assert!(matches!(
+            memory.fetch_op(&mut fetch).unwrap(),
+            Some(Op::Rst(opcode::Tgt3(7)))
+        ));
+    }
+
+    #[test]
+    fn mbc3_unmapped_sram_select() {
+        // MBC3 with a timer and 32 KiB of SRAM
+        let mut memory = memory(|data| {
+            data[0x0147] = 0x10;
+            data[0x0149] = 0x03;
+        });
+        memory.write(0x0000, 0x0A).unwrap();
+        memory.write(0x4000, 0x08).unwrap();
+        memory.write(0xA000, 0x2A).unwrap();
+        assert_eq!(memory.read(0xA000).unwrap(), 0x2A);
+
+        memory.write(0x4000, 0x0D).unwrap();
+        memory.write(0xA000, 0x11).unwrap();
+        assert_eq!(memory.read(0xA000).unwrap(), 0xFF);
+        memory.write(0x4000, 0x08).unwrap();
+        assert_eq!(memory.read(0xA000).unwrap(), 0x2A);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::cart::Cart;
+use crate::cart::{self, Cart};
 use crate::mem;
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteArray;
@@ -52,17 +52,16 @@ pub enum Mbc1ExtBank {
 }
 
 impl Mbc {
-    pub fn from_cart(cart: &Cart) -> Self {
+    pub fn from_cart(cart: &Cart) -> Result<Self, cart::Error> {
         for feature in cart.features() {
             match feature {
                 crate::cart::Feature::Mbc1 => {
-                    let bank_count: u8 = cart
-                        .data()
-                        .len()
-                        .div_ceil(16 * 1024)
-                        .try_into()
-                        .expect("cart isn't too large");
-                    return Self::One {
+                    // the 5-bit bank register and the 2-bit upper register address 128 banks
+                    let bank_count = match cart.data().len().div_ceil(16 * 1024) {
+                        count @ 0..=128 => count as u8,
+                        _ => return Err(cart::Error("MBC1 carts can't be larger than 2 MiB")),
+                    };
+                    return Ok(Self::One {
                         rom_bank_reg: 0,
                         rom_bank_reg_mask: bank_count.next_power_of_two() - 1,
                         sram_enabled: false,
@@ -79,42 +78,42 @@ impl Mbc {
                                 sram: Default::default(),
                             }
                         },
-                    };
+                    });
                 }
                 crate::cart::Feature::Mbc2 => {
-                    return Self::Two {
+                    return Ok(Self::Two {
                         rom_bank_reg: 0,
                         sram_4bit: Default::default(),
                         sram_enabled: false,
-                    };
+                    });
                 }
                 crate::cart::Feature::Mbc3 => {
-                    return Self::Three {
+                    return Ok(Self::Three {
                         rom_bank_reg: 0,
                         sram_bank_or_rtc_reg: 0,
                         sram_and_rtc_enabled: false,
                         sram: Default::default(),
                         latching: false,
                         rtc: [0; _],
-                    };
+                    });
                 }
                 crate::cart::Feature::Mbc5 => {
-                    return Self::Five {
+                    return Ok(Self::Five {
                         rom_bank_reg: 1,
                         sram_enabled: false,
                         sram_bank_reg: 0,
                         sram: Default::default(),
-                    };
+                    });
                 }
                 crate::cart::Feature::Mbc6 | crate::cart::Feature::Mbc7 => {
-                    unimplemented!("very rare cart")
+                    return Err(cart::Error("MBC6 and MBC7 carts aren't supported"));
                 }
                 _ => {}
             }
         }
-        Self::None {
+        Ok(Self::None {
             sram: Default::default(),
-        }
+        })
     }
 
     pub fn bank_and_cart_addr(&self, addr: u16) -> Option<(u16, usize)> {
@@ -188,4 +187,154 @@ impl Mbc {
             _ => None,
         }
     }
+
+    /// The SRAM bank mapped at A000-BFFF, unless SRAM is disabled, 4-bit, or the RTC is mapped
+    pub fn sram_bank(&self) -> Option<usize> {
+        match self {
+            Self::None { .. }
+            | Self::One {
+                sram_enabled: true,
+                extended_bank: Mbc1ExtBank::Rom { .. },
+                ..
+            } => Some(0),
+            Self::One {
+                sram_enabled: true,
+                extended_bank:
+                    Mbc1ExtBank::Ram {
+                        advanced,
+                        sram_bank_reg,
+                        ..
+                    },
+                ..
+            } => Some(if *advanced {
+                *sram_bank_reg as usize
+            } else {
+                0
+            }),
+            Self::Three {
+                sram_and_rtc_enabled: true,
+                sram_bank_or_rtc_reg: sram_bank @ 0x00..=0x07,
+                ..
+            } => Some(*sram_bank as usize),
+            Self::Five {
+                sram_enabled: true,
+                sram_bank_reg,
+                ..
+            } => Some(*sram_bank_reg as usize),
+            _ => None,
+        }
+    }
+
+    pub fn sram(&self, bank: usize) -> &[u8] {
+        match self {
+            Self::None { sram }
+            | Self::One {
+                extended_bank: Mbc1ExtBank::Rom { sram, .. },
+                ..
+            } => &sram[..],
+            Self::One {
+                extended_bank: Mbc1ExtBank::Ram { sram, .. },
+                ..
+            } => &sram[bank][..],
+            Self::Three { sram, .. } => &sram[bank][..],
+            Self::Five { sram, .. } => &sram[bank][..],
+            Self::Two { sram_4bit, .. } => &sram_4bit[..],
+        }
+    }
+
+    pub fn sram_mut(&mut self, bank: usize) -> &mut [u8] {
+        match self {
+            Self::None { sram }
+            | Self::One {
+                extended_bank: Mbc1ExtBank::Rom { sram, .. },
+                ..
+            } => &mut sram[..],
+            Self::One {
+                extended_bank: Mbc1ExtBank::Ram { sram, .. },
+                ..
+            } => &mut sram[bank][..],
+            Self::Three { sram, .. } => &mut sram[bank][..],
+            Self::Five { sram, .. } => &mut sram[bank][..],
+            Self::Two { sram_4bit, .. } => &mut sram_4bit[..],
+        }
+    }
+
+    /// Write to the registers mapped over the ROM
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match self {
+            Self::One { sram_enabled, .. } if addr < 0x2000 => {
+                *sram_enabled = data & 0b00001111 == 0x0A;
+            }
+            Self::One { rom_bank_reg, .. } if addr < 0x4000 => {
+                *rom_bank_reg = data & 0b00011111;
+            }
+            Self::One {
+                extended_bank:
+                    Mbc1ExtBank::Ram {
+                        sram_bank_reg: target,
+                        ..
+                    }
+                    | Mbc1ExtBank::Rom {
+                        rom_bank_upper_reg: target,
+                        ..
+                    },
+                ..
+            } if addr < 0x6000 => {
+                *target = data & 0b00000011;
+            }
+            Self::One {
+                extended_bank: Mbc1ExtBank::Ram { advanced, .. } | Mbc1ExtBank::Rom { advanced, .. },
+                ..
+            } => {
+                *advanced = data % 2 == 1;
+            }
+            Self::Two {
+                rom_bank_reg,
+                sram_enabled,
+                ..
+            } if addr < 0x4000 => {
+                if addr & 0x0100 == 0 {
+                    *sram_enabled = data & 0b00001111 == 0x0A;
+                } else {
+                    *rom_bank_reg = data & 0b00001111;
+                }
+            }
+            Self::Three {
+                sram_and_rtc_enabled,
+                ..
+            } if addr < 0x2000 => {
+                *sram_and_rtc_enabled = data & 0b00001111 == 0x0A;
+            }
+            Self::Three { rom_bank_reg, .. } if addr < 0x4000 => {
+                *rom_bank_reg = data & 0b01111111;
+            }
+            Self::Three {
+                sram_bank_or_rtc_reg,
+                ..
+            } if addr < 0x6000 => {
+                // values selecting neither a bank nor an RTC register leave SRAM unmapped
+                *sram_bank_or_rtc_reg = data;
+            }
+            Self::Three { latching, .. } => {
+                if data == 0x00 {
+                    *latching = true;
+                } else if data == 0x01 {
+                    *latching = false;
+                }
+            }
+            Self::Five { sram_enabled, .. } if addr < 0x2000 => {
+                *sram_enabled = data & 0b00001111 == 0x0A;
+            }
+            Self::Five { rom_bank_reg, .. } if addr < 0x3000 => {
+                *rom_bank_reg = (*rom_bank_reg & 0xFF00) + data as u16;
+            }
+            Self::Five { rom_bank_reg, .. } if addr < 0x4000 => {
+                *rom_bank_reg = ((data as u16 & 0x0001) << 8) + (*rom_bank_reg & 0x00FF);
+            }
+            Self::Five { sram_bank_reg, .. } if addr < 0x6000 => {
+                *sram_bank_reg = data & 0x0F;
+            }
+            _ => {}
+        }
+    }
 }
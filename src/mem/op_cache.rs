@@ -0,0 +1,223 @@
+use super::pages::{PAGE_SIZE, Page};
+use crate::opcode::Op;
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const WRAM_BANK_SIZE: usize = 0x1000;
+const WRAM_BANKS: usize = 8;
+
+/// An op and its length
+type Entry = Option<(Op, u8)>;
+
+/// Ops decoded from ROM and WRAM. ROM banks are allocated when code first runs from them
+#[derive(Default)]
+pub struct OpCache {
+    rom: Vec<Option<Box<[Entry]>>>,
+    wram: Option<Box<[Entry]>>,
+    stats: OpCacheStats,
+}
+
+#[derive(Copy, Clone, Default, Debug)]
+pub struct OpCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Ops fetched from regions that aren't cached, like HRAM or the boot ROM
+    pub uncached: u64,
+    /// Cached ops dropped because their bytes were written
+    pub invalidations: u64,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum Key {
+    /// Offset in the cart
+    Rom(usize),
+    /// Offset in the WRAM banks laid out one after the other
+    Wram(usize),
+}
+
+impl Key {
+    pub fn new(page: Page, addr: u16) -> Option<Self> {
+        let offset = addr as usize % PAGE_SIZE;
+        match page {
+            Page::Rom(start) => Some(Self::Rom(start + offset)),
+            Page::Wram { bank, start } => Some(Self::Wram(bank * WRAM_BANK_SIZE + start + offset)),
+            _ => None,
+        }
+    }
+}
+
+impl OpCache {
+    pub fn stats(&self) -> OpCacheStats {
+        self.stats
+    }
+
+    pub fn get(&mut self, key: Option<Key>) -> Option<(Op, u8)> {
+        let entry = match key {
+            None => {
+                self.stats.uncached += 1;
+                return None;
+            }
+            Some(Key::Rom(offset)) => self
+                .rom
+                .get(offset / ROM_BANK_SIZE)
+                .and_then(|bank| bank.as_ref()?[offset % ROM_BANK_SIZE]),
+            Some(Key::Wram(offset)) => self.wram.as_ref().and_then(|wram| wram[offset]),
+        };
+        if entry.is_some() {
+            self.stats.hits += 1;
+        } else {
+            self.stats.misses += 1;
+        }
+        entry
+    }
+
+    pub fn insert(&mut self, key: Key, op: Op, len: u8) {
+        let entry = match key {
+            Key::Rom(offset) => {
+                let bank = offset / ROM_BANK_SIZE;
+                if self.rom.len() <= bank {
+                    self.rom.resize_with(bank + 1, || None);
+                }
+                &mut self.rom[bank].get_or_insert_with(|| vec![None; ROM_BANK_SIZE].into())
+                    [offset % ROM_BANK_SIZE]
+            }
+            // an op running into the next bank would go stale when that bank is written
+            Key::Wram(offset) if offset % WRAM_BANK_SIZE + len as usize > WRAM_BANK_SIZE => return,
+            Key::Wram(offset) => &mut self
+                .wram
+                .get_or_insert_with(|| vec![None; WRAM_BANKS * WRAM_BANK_SIZE].into())[offset],
+        };
+        *entry = Some((op, len));
+    }
+
+    /// Drop the ops overlapping WRAM written from `offset`
+    pub fn invalidate_wram(&mut self, offset: usize, len: usize) {
+        let Some(wram) = &mut self.wram else {
+            return;
+        };
+        let bank_start = offset - offset % WRAM_BANK_SIZE;
+        // ops are up to 3 bytes long, so they can start 2 bytes before the write
+        let start = offset.saturating_sub(2).max(bank_start);
+        let end = (offset + len).min(bank_start + WRAM_BANK_SIZE);
+        for (i, entry) in (start..end).zip(&mut wram[start..end]) {
+            if entry.is_some_and(|(_, op_len)| i + op_len as usize > offset) {
+                *entry = None;
+                self.stats.invalidations += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        Mode,
+        cart::Cart,
+        mem::{Fetch, Memory, WRAM_BANK_REG},
+        opcode::{N8, R8},
+    };
+
+    const OP: Op = Op::Nop;
+
+    fn with_ops(offsets: impl IntoIterator<Item = usize>) -> OpCache {
+        let mut cache = OpCache::default();
+        for offset in offsets {
+            cache.insert(Key::Wram(offset), OP, 3);
+        }
+        cache
+    }
+
+    fn cached(cache: &mut OpCache, offset: usize) -> bool {
+        cache.get(Some(Key::Wram(offset))).is_some()
+    }
+
+    #[test]
+    fn writes_into_an_op_invalidate_it() {
+        for (offset, len) in [(0x10, 1), (0x11, 1), (0x12, 1), (0x0F, 2), (0x11, 2)] {
+            let mut cache = with_ops([0x10]);
+            cache.invalidate_wram(offset, len);
+            assert!(!cached(&mut cache, 0x10), "{len} bytes at {offset:X}");
+            assert_eq!(cache.stats().invalidations, 1);
+        }
+        for (offset, len) in [(0x0F, 1), (0x0E, 2), (0x13, 1), (0x13, 2)] {
+            let mut cache = with_ops([0x10]);
+            cache.invalidate_wram(offset, len);
+            assert!(cached(&mut cache, 0x10), "{len} bytes at {offset:X}");
+        }
+    }
+
+    #[test]
+    fn ops_stay_in_their_bank() {
+        let end = WRAM_BANK_SIZE;
+        let mut cache = with_ops([end - 3, end - 2, end]);
+        assert!(cached(&mut cache, end - 3));
+        // an op running into the next bank isn't cached
+        assert!(!cached(&mut cache, end - 2));
+
+        // writes at either side of the boundary only invalidate ops in their own bank
+        cache.invalidate_wram(end, 1);
+        assert!(cached(&mut cache, end - 3));
+        assert!(!cached(&mut cache, end));
+        let mut cache = with_ops([end - 3, end]);
+        cache.invalidate_wram(end - 1, 1);
+        assert!(!cached(&mut cache, end - 3));
+        assert!(cached(&mut cache, end));
+    }
+
+    fn memory(mode: Mode) -> Memory {
+        Memory::init(vec![], Cart::test(&[], |_| {}), mode, false).unwrap()
+    }
+
+    fn fetch(memory: &mut Memory, pc: u16) -> Op {
+        let mut fetch = Fetch::new(pc, false);
+        loop {
+            if let Some(op) = memory.fetch_op(&mut fetch).unwrap() {
+                return op;
+            }
+        }
+    }
+
+    #[test]
+    fn echo_ram_writes_invalidate() {
+        let mut memory = memory(Mode::Dmg);
+        // ld a, 5
+        memory.write(0xC000, 0x3E).unwrap();
+        memory.write(0xC001, 0x05).unwrap();
+        assert!(matches!(
+            fetch(&mut memory, 0xC000),
+            Op::LdR8N8(R8::A, N8(5))
+        ));
+        memory.write(0xE001, 0x07).unwrap();
+        assert!(matches!(
+            fetch(&mut memory, 0xC000),
+            Op::LdR8N8(R8::A, N8(7))
+        ));
+        assert_eq!(memory.op_cache_stats().invalidations, 1);
+    }
+
+    #[test]
+    fn banks_are_cached_separately() {
+        let mut memory = memory(Mode::Cgb);
+        for (bank, n8) in [(1, 5), (2, 7)] {
+            memory.write(WRAM_BANK_REG, bank).unwrap();
+            memory.write(0xD000, 0x3E).unwrap();
+            memory.write(0xD001, n8).unwrap();
+        }
+        for (bank, n8) in [(1, 5), (2, 7), (1, 5), (2, 7)] {
+            memory.write(WRAM_BANK_REG, bank).unwrap();
+            let op = fetch(&mut memory, 0xD000);
+            assert!(matches!(op, Op::LdR8N8(R8::A, N8(n)) if n == n8));
+        }
+        let stats = memory.op_cache_stats();
+        assert_eq!((stats.hits, stats.misses), (2, 2));
+
+        // writing one bank leaves the other's op cached
+        memory.write(0xD001, 0x09).unwrap();
+        memory.write(WRAM_BANK_REG, 1).unwrap();
+        assert!(matches!(
+            fetch(&mut memory, 0xD000),
+            Op::LdR8N8(R8::A, N8(5))
+        ));
+        assert_eq!(memory.op_cache_stats().invalidations, 1);
+    }
+}
@@ -0,0 +1,131 @@
+use super::{
+    ERAM_START, Memory, Mode, OAM_START, ROM_BANK_0_START, ROM_BANK_N_START, SRAM_START,
+    VRAM_START, WRAM_BANK_0_START, WRAM_BANK_N_START,
+};
+
+pub const PAGE_SIZE: usize = 0x100;
+const ROM_BANK_SIZE: usize = 0x4000;
+
+/// Where a page of the address space is stored. Accesses to pages outside the direct regions
+/// are handled by address
+#[derive(Copy, Clone, Default, PartialEq, Debug)]
+pub enum Page {
+    BootRom,
+    /// Offset of the page in the cart
+    Rom(usize),
+    Vram {
+        bank: usize,
+        start: usize,
+    },
+    Sram {
+        bank: usize,
+        start: usize,
+    },
+    /// Banks 0 and 1 are always present, the CGB adds banks 2-7
+    Wram {
+        bank: usize,
+        start: usize,
+    },
+    #[default]
+    Handler,
+}
+
+#[derive(Debug)]
+pub struct Pages([Page; 256]);
+
+impl Default for Pages {
+    fn default() -> Self {
+        Self([Page::Handler; 256])
+    }
+}
+
+impl Pages {
+    pub fn get(&self, addr: u16) -> Page {
+        self.0[addr as usize / PAGE_SIZE]
+    }
+
+    /// Map the pages from `start` to `end` to the pages `page` returns for their offsets
+    fn map(&mut self, start: u16, end: u16, page: impl Fn(usize) -> Page) {
+        let pages = start as usize / PAGE_SIZE..end as usize / PAGE_SIZE;
+        for (i, entry) in self.0[pages].iter_mut().enumerate() {
+            *entry = page(i * PAGE_SIZE);
+        }
+    }
+}
+
+impl Memory {
+    pub(super) fn map_pages(&mut self) {
+        self.map_cart();
+        self.map_vram();
+        self.map_wram();
+    }
+
+    /// Remap the ROM and SRAM, after the boot ROM or the MBC's registers change. Banks past the
+    /// end of the ROM wrap around like the unused bank bits do on hardware, and pages missing
+    /// from a ROM shorter than its header says read as open bus
+    pub(super) fn map_cart(&mut self) {
+        let rom_len = self.cart.data().len();
+        let banks = rom_len.div_ceil(ROM_BANK_SIZE);
+        let [bank_0, bank_n] = [ROM_BANK_0_START, ROM_BANK_N_START].map(|addr| {
+            let base = self.mbc.bank_and_cart_addr(addr).expect("ROM address").1;
+            base / ROM_BANK_SIZE % banks * ROM_BANK_SIZE
+        });
+        let rom = |start: usize| {
+            if start + PAGE_SIZE <= rom_len {
+                Page::Rom(start)
+            } else {
+                Page::Handler
+            }
+        };
+        let boot_rom_len = if self.boot_rom_ctrl == 0 {
+            self.boot_rom.len()
+        } else {
+            0
+        };
+        self.pages
+            .map(ROM_BANK_0_START, ROM_BANK_N_START, |offset| {
+                if offset + PAGE_SIZE <= boot_rom_len {
+                    Page::BootRom
+                } else {
+                    rom(bank_0 + offset)
+                }
+            });
+        self.pages
+            .map(ROM_BANK_N_START, VRAM_START, |offset| rom(bank_n + offset));
+
+        let sram_bank = self.mbc.sram_bank();
+        self.pages
+            .map(SRAM_START, WRAM_BANK_0_START, |start| match sram_bank {
+                Some(bank) => Page::Sram { bank, start },
+                None => Page::Handler,
+            });
+    }
+
+    /// Remap VRAM after the CGB's bank register changes
+    pub(super) fn map_vram(&mut self) {
+        let bank = (self.mode == Mode::Cgb && self.cgb_vram_bank != 0).into();
+        self.pages
+            .map(VRAM_START, SRAM_START, |start| Page::Vram { bank, start });
+    }
+
+    /// Remap WRAM and its echo after the CGB's bank register changes
+    pub(super) fn map_wram(&mut self) {
+        let bank = match self.mode {
+            Mode::Dmg | Mode::Sgb => 1,
+            // only the lower 3 bits are used, and bank 0 can't be mapped twice
+            _ => (self.cgb_wram_bank as usize & 0b111).max(1),
+        };
+        let wram = |start: usize| {
+            if start < (WRAM_BANK_N_START - WRAM_BANK_0_START) as usize {
+                Page::Wram { bank: 0, start }
+            } else {
+                Page::Wram {
+                    bank,
+                    start: start - (WRAM_BANK_N_START - WRAM_BANK_0_START) as usize,
+                }
+            }
+        };
+        self.pages.map(WRAM_BANK_0_START, ERAM_START, wram);
+        self.pages.map(ERAM_START, OAM_START, wram);
+    }
+}
@@ -0,0 +1,387 @@
+use crate::{
+    Input, Joypad, Model, Options, Renderer, Scheduling,
+    cart::Cart,
+    system::{self, System},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Display, Formatter},
+    io::{Read, Write},
+};
+
+/// A state hash is stored every N frames, so playback can detect desyncs
+const CHECKPOINT_INTERVAL: usize = 60;
+
+/// Per-frame input log, replayable from a known starting state
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Movie {
+    start: Start,
+    cart_hash: String,
+    settings: Settings,
+    inputs: Vec<Joypad>,
+    checkpoints: Vec<Checkpoint>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Start {
+    PowerOn { skip_boot: bool },
+    SaveState(#[serde(with = "serde_bytes")] Vec<u8>),
+}
+
+/// The model and options a movie only replays the same under
+#[derive(Copy, Clone, PartialEq, Serialize, Deserialize, Debug)]
+pub struct Settings {
+    pub model: Model,
+    pub renderer: Renderer,
+    pub scheduling: Scheduling,
+    pub strict_mem_access: bool,
+    pub lock_up: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Checkpoint {
+    frame: usize,
+    state_hash: String,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Load(rmp_serde::decode::Error),
+    Save(rmp_serde::encode::Error),
+    System(system::Error),
+    WrongCart,
+    WrongSettings {
+        recorded: Settings,
+        requested: Settings,
+    },
+    Desync {
+        frame: usize,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Load(_) => write!(f, "couldn't load movie"),
+            Self::Save(_) => write!(f, "couldn't save movie"),
+            Self::System(_) => write!(f, "system failed during movie"),
+            Self::WrongCart => write!(f, "movie was recorded with a different cart"),
+            Self::WrongSettings {
+                recorded,
+                requested,
+            } => write!(
+                f,
+                "movie was recorded with {recorded}, but played with {requested}"
+            ),
+            Self::Desync {
+                frame,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "movie desynced at frame {frame}, expected state hash {expected}, got {actual}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Load(err) => Some(err),
+            Self::Save(err) => Some(err),
+            Self::System(err) => Some(err),
+            Self::WrongCart | Self::WrongSettings { .. } | Self::Desync { .. } => None,
+        }
+    }
+}
+
+impl From<system::Error> for Error {
+    fn from(err: system::Error) -> Self {
+        Self::System(err)
+    }
+}
+
+pub struct Player {
+    movie: Movie,
+    frame: usize,
+    checkpoint: usize,
+}
+
+impl Movie {
+    /// Begin recording from a freshly initialized system, which skipped the boot ROM if it
+    /// was asked to or didn't have one
+    pub fn power_on(system: &System) -> Self {
+        let skip_boot = system.options().skip_boot;
+        Self::new(system, Start::PowerOn { skip_boot })
+    }
+
+    /// Begin recording from the system's current state. The boot ROM must already be unmapped
+    pub fn from_state(system: &System) -> Result<Self, Error> {
+        let mut state = vec![];
+        system.save_state(&mut state)?;
+        Ok(Self::new(system, Start::SaveState(state)))
+    }
+
+    fn new(system: &System, start: Start) -> Self {
+        Self {
+            start,
+            cart_hash: system.cart_hash().into(),
+            settings: Settings::new(system.model(), system.options()),
+            inputs: vec![],
+            checkpoints: vec![],
+        }
+    }
+
+    pub fn read(reader: impl Read) -> Result<Self, Error> {
+        rmp_serde::from_read(reader).map_err(Error::Load)
+    }
+
+    pub fn write(&self, mut writer: impl Write) -> Result<(), Error> {
+        rmp_serde::encode::write(&mut writer, self).map_err(Error::Save)
+    }
+
+    pub fn start(&self) -> &Start {
+        &self.start
+    }
+
+    pub fn settings(&self) -> Settings {
+        self.settings
+    }
+
+    pub fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    /// Log the joypad state used for the frame just emulated
+    pub fn record(&mut self, joypad: Joypad, system: &System) -> Result<(), Error> {
+        self.inputs.push(joypad);
+        if self.inputs.len().is_multiple_of(CHECKPOINT_INTERVAL) {
+            self.checkpoints.push(Checkpoint {
+                frame: self.inputs.len(),
+                state_hash: system.state_hash()?,
+            });
+        }
+        Ok(())
+    }
+
+    /// Create the system this movie was recorded from. The model and options have to match the
+    /// ones it was recorded with
+    pub fn init_system(
+        &self,
+        boot_rom: Vec<u8>,
+        cart: Cart,
+        model: Model,
+        options: Options,
+    ) -> Result<System, Error> {
+        if cart.hash() != self.cart_hash {
+            return Err(Error::WrongCart);
+        }
+        let requested = Settings::new(model, &options);
+        if requested != self.settings {
+            return Err(Error::WrongSettings {
+                recorded: self.settings,
+                requested,
+            });
+        }
+        match &self.start {
+            &Start::PowerOn { skip_boot } => Ok(System::init_options(
+                boot_rom,
+                cart,
+                model,
+                Options {
+                    skip_boot,
+                    ..options
+                },
+            )?),
+            Start::SaveState(state) => Ok(System::load_options(&state[..], cart, options)?),
+        }
+    }
+
+    pub fn play(self) -> Player {
+        Player {
+            movie: self,
+            frame: 0,
+            checkpoint: 0,
+        }
+    }
+}
+
+impl Settings {
+    fn new(model: Model, options: &Options) -> Self {
+        Self {
+            model,
+            renderer: options.renderer,
+            scheduling: options.scheduling,
+            strict_mem_access: options.strict_mem_access,
+            lock_up: options.lock_up,
+        }
+    }
+}
+
+impl Display for Settings {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "model - {}, renderer - {}, scheduling - {}, strict_mem_access - {}, lock_up - {}",
+            self.model, self.renderer, self.scheduling, self.strict_mem_access, self.lock_up
+        )
+    }
+}
+
+impl Player {
+    /// Input for the next frame, or `None` once the movie has finished
+    pub fn next_input(&self) -> Option<Input> {
+        self.movie.inputs.get(self.frame).map(|&joypad| Input {
+            joypad,
+            ..Default::default()
+        })
+    }
+
+    /// Advance past the frame just emulated, checking the system against any recorded state hash
+    pub fn advance(&mut self, system: &System) -> Result<(), Error> {
+        self.frame += 1;
+        match self.movie.checkpoints.get(self.checkpoint) {
+            Some(Checkpoint { frame, state_hash }) if *frame == self.frame => {
+                self.checkpoint += 1;
+                let actual = system.state_hash()?;
+                if actual != *state_hash {
+                    return Err(Error::Desync {
+                        frame: self.frame,
+                        expected: state_hash.clone(),
+                        actual,
+                    });
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn finished(&self) -> bool {
+        self.frame >= self.movie.inputs.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Adds up the button bits read from the joypad register in HRAM, so a changed input stays
+    /// in the state
+    #[rustfmt::skip]
+    const CODE: &[u8] = &[
+        0x3E, 0x10,             // ld a, $10
+        0xE0, 0x00,             // ldh [JOYP], a
+        0xF0, 0x00,             // ldh a, [JOYP]
+        0x47,                   // ld b, a
+        0xF0, 0x80,             // ldh a, [$FF80]
+        0x80,                   // add b
+        0xE0, 0x80,             // ldh [$FF80], a
+        0x18, 0xF2,             // jr -14
+    ];
+
+    fn record(frames: usize) -> (Movie, String) {
+        let cart = Cart::test(CODE, |_| {});
+        let mut system = System::init(vec![], cart, Model::Dmg).expect("system initialized");
+        let mut movie = Movie::power_on(&system);
+        for frame in 0..frames {
+            let joypad = Joypad {
+                a: frame % 3 == 0,
+                b: frame % 7 == 0,
+                ..Default::default()
+            };
+            let input = Input {
+                joypad,
+                ..Default::default()
+            };
+            system.next_frame(input).expect("no error");
+            movie.record(joypad, &system).expect("recorded");
+        }
+        (movie, system.state_hash().expect("hashed"))
+    }
+
+    fn replay(movie: Movie, model: Model, options: Options) -> Result<String, Error> {
+        let cart = Cart::test(CODE, |_| {});
+        let mut system = movie.init_system(vec![], cart, model, options)?;
+        let mut player = movie.play();
+        while let Some(input) = player.next_input() {
+            system.next_frame(input)?;
+            player.advance(&system)?;
+        }
+        assert!(player.finished());
+        Ok(system.state_hash()?)
+    }
+
+    #[test]
+    fn replays_to_recorded_state() {
+        let (movie, state_hash) = record(121);
+        let mut file = vec![];
+        movie.write(&mut file).expect("written");
+        let movie = Movie::read(&file[..]).expect("read");
+        assert_eq!(movie.len(), 121);
+        let replayed = replay(movie, Model::Dmg, Options::default()).expect("no desync");
+        assert_eq!(replayed, state_hash);
+    }
+
+    #[test]
+    fn skips_boot_like_recording() {
+        // recorded without a boot ROM, so replaying with one still skips it
+        let (movie, state_hash) = record(61);
+        let cart = Cart::test(CODE, |_| {});
+        let mut system = movie
+            .init_system(vec![0; 0x100], cart, Model::Dmg, Options::default())
+            .expect("system initialized");
+        assert!(system.options().skip_boot);
+        let mut player = movie.play();
+        while let Some(input) = player.next_input() {
+            system.next_frame(input).expect("no error");
+            player.advance(&system).expect("no desync");
+        }
+        assert_eq!(system.state_hash().expect("hashed"), state_hash);
+    }
+
+    #[test]
+    fn detects_desync() {
+        let (mut movie, _) = record(121);
+        movie.inputs[70].b = !movie.inputs[70].b;
+        let result = replay(movie, Model::Dmg, Options::default());
+        assert!(matches!(result, Err(Error::Desync { frame: 120, .. })));
+    }
+
+    #[test]
+    fn refuses_other_settings() {
+        let (movie, _) = record(1);
+        let result = replay(movie, Model::Mgb, Options::default());
+        assert!(matches!(
+            result,
+            Err(Error::WrongSettings {
+                recorded: Settings {
+                    model: Model::Dmg,
+                    ..
+                },
+                requested: Settings {
+                    model: Model::Mgb,
+                    ..
+                },
+            })
+        ));
+        let (movie, _) = record(1);
+        let options = Options {
+            lock_up: true,
+            ..Default::default()
+        };
+        let result = replay(movie, Model::Dmg, options);
+        assert!(matches!(result, Err(Error::WrongSettings { .. })));
+    }
+}
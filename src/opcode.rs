@@ -1,11 +1,25 @@
 use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
 
 #[derive(Debug)]
 pub enum Error {
+    /// The bytes ran out before the end of the op
     Exhausted,
-    Invalid,
+    /// The opcode isn't used by any op
+    Invalid(u8),
 }
 
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Exhausted => write!(f, "op runs past the end of memory"),
+            Self::Invalid(opcode) => write!(f, "invalid opcode {opcode:02X}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 #[derive(Copy, Clone, Serialize, Deserialize, Debug)]
 #[repr(u8)]
 pub enum R8 {
@@ -368,7 +382,7 @@ impl Op {
                                 let (e8, rest) = E8::read(rest)?;
                                 Ok((Self::JrCondE8(Cond::from_43(opcode), e8), rest))
                             } else {
-                                Err(Error::Invalid)
+                                Err(Error::Invalid(opcode))
                             }
                         }
                     },
@@ -485,13 +499,17 @@ impl Op {
                                 let (a16, rest) = A16::read(rest)?;
                                 Ok((Self::JpCondA16(Cond::from_43(opcode), a16), rest))
                             }
-                            0b100 => {
+                            // $E4, $EC, $F4 and $FC are illegal
+                            0b100 if opcode & 0b00100000 == 0 => {
                                 let (a16, rest) = A16::read(rest)?;
                                 Ok((Self::CallCondA16(Cond::from_43(opcode), a16), rest))
                             }
-                            0b101 => Ok((Self::Push(R16Stk::from_54(opcode)), rest)),
+                            // $DD, $ED and $FD are illegal
+                            0b101 if opcode & 0b00001000 == 0 => {
+                                Ok((Self::Push(R16Stk::from_54(opcode)), rest))
+                            }
                             0b111 => Ok((Self::Rst(Tgt3::from_543(opcode)), rest)),
-                            _ => Err(Error::Invalid),
+                            _ => Err(Error::Invalid(opcode)),
                         },
                     }
                 }
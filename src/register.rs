@@ -1,3 +1,4 @@
+use crate::{Mode, Model, cart::Cart};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Default)]
@@ -16,6 +17,53 @@ pub struct RegisterSet {
 }
 
 impl RegisterSet {
+    /// Registers as the model's boot ROM leaves them when jumping to the cart at $0100.
+    /// Games tell models apart through A (and B on the AGB)
+    pub fn post_boot(model: Model, cart: &Cart, mode: Mode) -> Self {
+        // the DMG boot ROMs finish by comparing the header checksum
+        let header_flags = if cart.header_checksum() == 0 {
+            0b10000000
+        } else {
+            0b10110000
+        };
+        // DMG carts on color models get the title checksum used to pick their palettes
+        let title_checksum = if cart.nintendo_licensed() {
+            cart.title_checksum()
+        } else {
+            0
+        };
+        let (a, f, b, c, d, e, h, l) = match (model, mode) {
+            (Model::Dmg0, _) => (0x01, 0x00, 0xFF, 0x13, 0x00, 0xC1, 0x84, 0x03),
+            (Model::Dmg, _) => (0x01, header_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D),
+            (Model::Mgb, _) => (0xFF, header_flags, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D),
+            (Model::Sgb, _) => (0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60),
+            (Model::Sgb2, _) => (0xFF, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60),
+            (Model::Cgb, Mode::Cgb) => (0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D),
+            (Model::Cgb, _) => (0x11, 0x80, title_checksum, 0x00, 0x00, 0x08, 0x00, 0x7C),
+            // the AGB boot ROM ends with an extra `inc b`
+            (Model::Agb, Mode::Cgb) => (0x11, 0x00, 0x01, 0x00, 0xFF, 0x56, 0x00, 0x0D),
+            (Model::Agb, _) => {
+                let b = title_checksum.wrapping_add(1);
+                let f = if b == 0 { 0b10000000 } else { 0 }
+                    | if b & 0x0F == 0 { 0b00100000 } else { 0 };
+                (0x11, f, b, 0x00, 0x00, 0x08, 0x00, 0x7C)
+            }
+        };
+        Self {
+            a,
+            f,
+            b,
+            c,
+            d,
+            e,
+            h,
+            l,
+            sp: 0xFFFE,
+            pc: 0x0100,
+            next_pc: 0x0100,
+        }
+    }
+
     pub fn af(&self) -> u16 {
         u16::from_be_bytes([self.a, self.f])
     }
@@ -3,10 +3,44 @@ pub mod ppu;
 
 use crate::mem;
 use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display, Formatter};
 
 #[derive(Debug)]
 pub enum Error {
     Memory(mem::Error),
+    Mismatch {
+        x: usize,
+        y: usize,
+    },
+    /// The FIFO took `fifo` dots for line `ly`'s mode 3, where the scanline renderer takes
+    /// `scanline`
+    TimingMismatch {
+        ly: u8,
+        fifo: u16,
+        scanline: u16,
+    },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Memory(_) => write!(f, "PPU memory access failed"),
+            Self::Mismatch { x, y } => write!(f, "renderers disagree at pixel ({x}, {y})"),
+            Self::TimingMismatch { ly, fifo, scanline } => write!(
+                f,
+                "renderers disagree on mode 3 of line {ly}, {fifo} dots instead of {scanline}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Memory(err) => Some(err),
+            Self::Mismatch { .. } | Self::TimingMismatch { .. } => None,
+        }
+    }
 }
 
 impl From<mem::Error> for Error {
@@ -21,6 +55,16 @@ struct OamBuf {
     len: usize,
 }
 
+impl OamBuf {
+    /// Objects keep their OAM order
+    fn remove(&mut self, i: usize) -> Object {
+        let obj = self.buffer[i];
+        self.buffer.copy_within(i + 1..self.len, i);
+        self.len -= 1;
+        obj
+    }
+}
+
 #[derive(Copy, Clone, Default, Serialize, Deserialize, Debug)]
 struct Object {
     y: u8,
@@ -64,6 +108,15 @@ impl Fifo {
         }
     }
 
+    /// Push transparent pixels until there are at least `len`
+    fn pad(&mut self, len: usize) {
+        while self.len < len {
+            self.buffer[self.back] = Pixel::default();
+            self.back = (self.back + 1) % self.buffer.len();
+            self.len += 1;
+        }
+    }
+
     fn pop(&mut self) -> Option<Pixel> {
         if self.len == 0 {
             None
@@ -5,6 +5,10 @@ use crate::{
 use serde::{Deserialize, Serialize};
 
 pub const FETCH_STEPS: u8 = 6;
+/// Dots an object fetch stalls the pipeline for, besides waiting on the background fetch
+pub const OBJ_FETCH_DOTS: u8 = 6;
+/// Longest an object fetch waits on the background fetcher
+const OBJ_WAIT_DOTS: u8 = 5;
 
 pub fn fetch_tile_pixels(memory: &Memory, addr: u16) -> Result<[Pixel; 8], Error> {
     let lo = memory.read_ppu(addr)?;
@@ -15,48 +19,27 @@ pub fn fetch_tile_pixels(memory: &Memory, addr: u16) -> Result<[Pixel; 8], Error
     }))
 }
 
+/// Dots an object starting on background `column` waits for the background fetcher to finish
+/// that tile, unless the last object fetched started on the same tile. Both renderers stall
+/// for this, so their timings agree
+pub fn obj_wait(column: u16, last_tile: Option<u16>) -> u8 {
+    if last_tile == Some(column / 8) {
+        0
+    } else {
+        OBJ_WAIT_DOTS - (column % 8).min(OBJ_WAIT_DOTS.into()) as u8
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Fetcher {
     Bg {
         tile_x: u8,
         progress: u8,
         cached: Option<[Pixel; 8]>,
-        obj_queued: Option<usize>,
     },
     Window {
         tile_x: u8,
         progress: u8,
         cached: Option<[Pixel; 8]>,
-        obj_queued: Option<usize>,
-    },
-    Object {
-        tile_x: u8,
-        progress: u8,
-        index: usize,
     },
 }
-
-impl Fetcher {
-    pub fn tile_x(&self) -> u8 {
-        match self {
-            Fetcher::Bg { tile_x, .. } => *tile_x,
-            Fetcher::Window { tile_x, .. } => *tile_x,
-            Fetcher::Object { tile_x, .. } => *tile_x,
-        }
-    }
-
-    pub fn fetching_obj(&self) -> bool {
-        matches!(
-            self,
-            Self::Object { .. }
-                | Self::Bg {
-                    obj_queued: Some(_),
-                    ..
-                }
-                | Self::Window {
-                    obj_queued: Some(_),
-                    ..
-                }
-        )
-    }
-}
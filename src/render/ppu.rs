@@ -1,6 +1,8 @@
+mod scanline;
+
 use crate::{
-    Mode,
-    frame::{self, Frame, Theme},
+    Mode, Renderer,
+    frame::{Frame, Palettes},
     mem::{self, Memory},
     render::{
         self, Error, Fifo, OamBuf, Object,
@@ -15,6 +17,11 @@ const DOT_END: u16 = 456;
 const VBLANK_LY_START: u8 = 144;
 const OAM_SCAN_DOT_START: u16 = 0;
 const OAM_SCAN_DOT_END: u16 = 79;
+// LY reads 153 only at the start of the last line, and 0 for the rest of it
+const LY_153_DOTS: u16 = 4;
+const WX_OFFSET: u8 = 7;
+// the window then covers all of the next line
+const WX_WRAP: u8 = 166;
 
 const MAP_LOWER_START: u16 = 0x9800;
 const MAP_UPPER_START: u16 = 0x9C00;
@@ -26,14 +33,23 @@ const DATA_2_START: u16 = 0x9000;
 pub struct Ppu {
     mode: Mode,
     #[serde(skip)]
-    theme: Theme,
+    palettes: Palettes,
+    #[serde(skip)]
+    renderer: Renderer,
     state: State,
+    // objects found by the OAM scan of the current line
+    objects: OamBuf,
+    // fetched object pixels, mixed with the background or window as pixels are shifted out
+    obj_fifo: Fifo,
     ly: u8,
     dot: u16,
     enabled: bool,
     window_enabled: bool,
     window_latched: bool,
     window_counter: u16,
+    window_wraps: bool,
+    // the window covers the whole line, from WX=166 on the line before
+    wrapped_line: bool,
     obj_enabled: bool,
     bg_w_priority: bool,
     w_map_addr: u16,
@@ -43,7 +59,14 @@ pub struct Ppu {
     lyc_int_enable: bool,
     mode_int_enable: [bool; 3],
     prev_stat: u8,
-    frame: Frame,
+    // STAT interrupts are only requested on a rising edge of the OR of the enabled conditions
+    stat_line: bool,
+    // the first frame after the LCD is enabled isn't displayed
+    blank_frame: bool,
+    // drawn into while `front` holds the last finished frame
+    back: Frame,
+    #[serde(skip)]
+    front: Frame,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -65,23 +88,38 @@ enum State {
         in_window: bool,
         fetcher: Fetcher,
         discard: u8,
+        // background tile the last fetched object started on
+        obj_tile: Option<u16>,
+        // object being fetched and the dots left of the stall, which pixels wait on
+        obj_fetch: Option<(Object, u8)>,
+    },
+    // the scanline renderer draws the line once mode 3 is over
+    Scanline {
+        dots: u16,
     },
 }
 
 impl Ppu {
-    pub fn init(mode: Mode, theme: Theme) -> Self {
+    pub fn init(mode: Mode, palettes: Palettes, renderer: Renderer) -> Self {
+        let mut frame = Frame::default();
+        frame.set_palette(palettes.entries());
         Self {
             mode,
-            theme,
+            palettes,
+            renderer,
             state: State::OamScan {
                 oam: Default::default(),
             },
+            objects: Default::default(),
+            obj_fifo: Fifo::new(),
             ly: 0,
             dot: 0,
             enabled: false,
             window_enabled: false,
             window_latched: false,
             window_counter: 0,
+            window_wraps: false,
+            wrapped_line: false,
             obj_enabled: false,
             bg_w_priority: false,
             w_map_addr: MAP_LOWER_START,
@@ -91,21 +129,70 @@ impl Ppu {
             lyc_int_enable: false,
             mode_int_enable: [false; _],
             prev_stat: 0,
-            frame: Default::default(),
+            stat_line: false,
+            blank_frame: false,
+            back: frame.clone(),
+            front: frame,
         }
     }
 
-    pub fn set_theme(&mut self, theme: Theme) {
-        self.theme = theme;
+    /// The frame being drawn switches to the new colors, while the last finished one keeps its own
+    pub fn set_palettes(&mut self, palettes: Palettes) {
+        self.palettes = palettes;
+        self.back.set_palette(palettes.entries());
+    }
+
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.renderer = renderer;
     }
 
-    pub fn tick(&mut self, memory: &mut Memory) -> Result<Option<Frame>, Error> {
+    /// The last finished frame
+    pub fn frame(&self) -> &Frame {
+        &self.front
+    }
+
+    /// Dots before one that does more than count, unless the registers are written
+    pub fn idle_dots(&self) -> u64 {
+        if !self.enabled {
+            return u64::MAX;
+        }
+        let event_dot = match self.state {
+            State::Hblank => DOT_END - 1,
+            State::Vblank if self.ly == LY_END - 1 && self.dot < LY_153_DOTS => LY_153_DOTS - 1,
+            State::Vblank => DOT_END - 1,
+            State::OamScan { .. } if self.dot == OAM_SCAN_DOT_START => OAM_SCAN_DOT_START,
+            State::OamScan { .. } => OAM_SCAN_DOT_END,
+            State::Scanline { dots } => return dots.into(),
+            State::FirstFetch { .. } | State::Drawing { .. } => return 0,
+        };
+        (event_dot - self.dot).into()
+    }
+
+    /// Catch up on dots that only counted
+    pub fn skip(&mut self, dots: u64) {
+        if !self.enabled || dots == 0 {
+            return;
+        }
+        self.dot += dots as u16;
+        if let State::Scanline { dots: left } = &mut self.state {
+            *left -= dots as u16;
+        }
+    }
+
+    /// Returns whether a frame was finished
+    pub fn tick(&mut self, memory: &mut Memory) -> Result<bool, Error> {
+        let was_enabled = self.enabled;
         self.read_lcdc_stat(memory)?;
         if !self.enabled {
-            return Ok(None);
+            if was_enabled {
+                self.disable(memory)?;
+            }
+            return Ok(false);
+        }
+        if !was_enabled {
+            self.blank_frame = true;
         }
-        let mut frame = None;
-        let mut lyc_match = false;
+        let mut frame = false;
 
         match &mut self.state {
             State::Hblank => {
@@ -113,14 +200,21 @@ impl Ppu {
                     self.ly += 1;
                     memory.write_ppu(mem::LY_REG, self.ly)?;
                     if self.ly < VBLANK_LY_START {
-                        if !self.window_latched {
-                            self.window_latched = self.ly == memory.read(mem::WINDOW_Y_REG)?;
-                        }
                         self.state = State::OamScan {
                             oam: Default::default(),
                         };
                     } else {
-                        frame = Some(self.frame.clone());
+                        if std::mem::take(&mut self.blank_frame) {
+                            for y in 0..self.back.height() {
+                                for x in 0..self.back.width() {
+                                    self.back.set(x, y, 0, Palettes::BG);
+                                }
+                            }
+                        }
+                        std::mem::swap(&mut self.back, &mut self.front);
+                        self.front.finish();
+                        self.back.set_palette(self.palettes.entries());
+                        frame = true;
                         memory.write_ppu(mem::IF_REG, memory.read(mem::IF_REG)? | 0b00000001)?;
                         self.state = State::Vblank;
                     };
@@ -134,24 +228,29 @@ impl Ppu {
                         self.ly = 0;
                         self.window_latched = false;
                         self.window_counter = 0;
+                        self.window_wraps = false;
                         self.state = State::OamScan {
                             oam: Default::default(),
                         };
                     }
                     memory.write_ppu(mem::LY_REG, self.ly)?;
+                } else if self.ly == LY_END - 1 && self.dot == LY_153_DOTS - 1 {
+                    memory.write_ppu(mem::LY_REG, 0)?;
                 }
             }
 
             State::OamScan { oam } => {
                 match self.dot {
                     OAM_SCAN_DOT_START => {
-                        lyc_match = self.ly == memory.read(mem::LYC_REG)?;
+                        // WY is compared on every line, and a match shows the window for the
+                        // rest of the frame
+                        self.window_latched |= self.ly == memory.read(mem::WINDOW_Y_REG)?;
                         memory.set_lock(mem::Lock::Oam);
 
                         for &[y, x, tile, flags] in memory.oam().as_chunks::<4>().0 {
-                            let y_lower = y.saturating_sub(16) as u16;
-                            let y_upper = y_lower + self.obj_height as u16;
-                            if (y_lower..y_upper).contains(&(self.ly.into())) {
+                            // Y is offset by 16, so objects can be partially above the screen
+                            let y_upper = y as u16 + self.obj_height as u16;
+                            if (y as u16..y_upper).contains(&(self.ly as u16 + 16)) {
                                 // This object is within the current scanline, add to OAM buffer
                                 oam.buffer[oam.len] = Object {
                                     y,
@@ -176,9 +275,18 @@ impl Ppu {
                     }
 
                     OAM_SCAN_DOT_END => {
-                        self.state = State::FirstFetch {
-                            oam: *oam,
-                            progress: fetcher::FETCH_STEPS,
+                        let oam = *oam;
+                        self.objects = oam;
+                        self.wrapped_line = std::mem::take(&mut self.window_wraps);
+                        self.state = if self.renderer == Renderer::Scanline {
+                            State::Scanline {
+                                dots: self.mode_3_dots(memory)?,
+                            }
+                        } else {
+                            State::FirstFetch {
+                                oam,
+                                progress: fetcher::FETCH_STEPS,
+                            }
                         };
                         memory.set_lock(mem::Lock::VramOam);
                     }
@@ -188,18 +296,39 @@ impl Ppu {
             }
 
             State::FirstFetch { oam, progress: 0 } => {
+                let wx = memory.read(mem::WINDOW_X_REG)?;
+                let in_window = self.window_enabled
+                    && (self.wrapped_line || self.window_latched && window_starts(wx, 0));
+                let (fetcher, discard) = if in_window {
+                    (
+                        Fetcher::Window {
+                            tile_x: 0,
+                            progress: fetcher::FETCH_STEPS,
+                            cached: None,
+                        },
+                        // columns left of the screen
+                        WX_OFFSET.saturating_sub(wx),
+                    )
+                } else {
+                    (
+                        Fetcher::Bg {
+                            tile_x: 0,
+                            progress: fetcher::FETCH_STEPS,
+                            cached: None,
+                        },
+                        memory.read(mem::SCROLL_X_REG)? % 8,
+                    )
+                };
+                self.obj_fifo = Fifo::new();
                 self.state = State::Drawing {
                     oam: *oam,
                     fifo: Fifo::new(),
                     px: 0,
-                    in_window: false,
-                    fetcher: Fetcher::Bg {
-                        tile_x: 0,
-                        progress: fetcher::FETCH_STEPS,
-                        cached: None,
-                        obj_queued: None,
-                    },
-                    discard: memory.read(mem::SCROLL_X_REG)? % 8,
+                    in_window,
+                    fetcher,
+                    discard,
+                    obj_tile: None,
+                    obj_fetch: None,
                 }
             }
 
@@ -207,18 +336,26 @@ impl Ppu {
                 *progress -= 1;
             }
 
-            State::Drawing {
-                px: X_END,
-                in_window,
-                ..
-            } => {
-                if *in_window {
+            // lines end below, on the dot their last pixel is drawn
+            State::Drawing { px: X_END, .. } => {}
+
+            State::Scanline { dots: 0 } => {
+                let (line, in_window) = self.draw_line(memory)?;
+                for (x, (shade, color)) in line.into_iter().enumerate() {
+                    self.back.set(x, self.ly as usize, shade, color);
+                }
+                if in_window {
                     self.window_counter += 1;
+                    self.window_wraps = memory.read(mem::WINDOW_X_REG)? == WX_WRAP;
                 }
                 self.state = State::Hblank;
                 memory.set_lock(mem::Lock::Unlocked);
             }
 
+            State::Scanline { dots } => {
+                *dots -= 1;
+            }
+
             State::Drawing {
                 oam,
                 fifo,
@@ -226,6 +363,8 @@ impl Ppu {
                 in_window,
                 fetcher,
                 discard,
+                obj_tile,
+                obj_fetch,
             } => {
                 let scroll_x = memory.read(mem::SCROLL_X_REG)?;
                 let scroll_y = memory.read(mem::SCROLL_Y_REG)?;
@@ -234,53 +373,32 @@ impl Ppu {
                     &mut Fetcher::Bg {
                         tile_x,
                         cached: Some(pixels),
-                        obj_queued,
                         ..
                     } => {
                         if fifo.push_8(pixels).is_ok() {
-                            *fetcher = if let Some(index) = obj_queued {
-                                Fetcher::Object {
-                                    tile_x,
-                                    progress: fetcher::FETCH_STEPS,
-                                    index,
-                                }
-                            } else {
-                                Fetcher::Bg {
-                                    tile_x: tile_x + 1,
-                                    progress: fetcher::FETCH_STEPS,
-                                    cached: None,
-                                    obj_queued: None,
-                                }
+                            *fetcher = Fetcher::Bg {
+                                tile_x: tile_x + 1,
+                                progress: fetcher::FETCH_STEPS,
+                                cached: None,
                             };
                         }
                     }
                     &mut Fetcher::Window {
                         tile_x,
                         cached: Some(pixels),
-                        obj_queued,
                         ..
                     } => {
                         if fifo.push_8(pixels).is_ok() {
-                            *fetcher = if let Some(index) = obj_queued {
-                                Fetcher::Object {
-                                    tile_x: tile_x + 1,
-                                    progress: fetcher::FETCH_STEPS,
-                                    index,
-                                }
-                            } else {
-                                Fetcher::Window {
-                                    tile_x: tile_x + 1,
-                                    progress: fetcher::FETCH_STEPS,
-                                    cached: None,
-                                    obj_queued: None,
-                                }
+                            *fetcher = Fetcher::Window {
+                                tile_x: tile_x + 1,
+                                progress: fetcher::FETCH_STEPS,
+                                cached: None,
                             };
                         }
                     }
                     &mut Fetcher::Bg {
                         tile_x,
                         progress: 0,
-                        obj_queued,
                         ..
                     } => {
                         //TODO CGB reads BG tilemap attrs
@@ -289,251 +407,193 @@ impl Ppu {
                         let col = ((scroll_x >> 3) + tile_x) as u16 % 32;
                         let bg_tile_addr = self.bg_map_addr + (row << 5) + col;
                         let bg_tile = memory.read_ppu(bg_tile_addr)?;
-                        let data_addr = if self.bg_w_data_addr == DATA_0_START {
-                            DATA_0_START + 16 * (bg_tile as u16)
-                        } else if bg_tile >= 0x80 {
-                            DATA_1_START + 16 * ((bg_tile - 0x80) as u16)
-                        } else {
-                            DATA_2_START + 16 * (bg_tile as u16)
-                        } + 2 * (y as u16 % 8);
+                        let data_addr =
+                            bg_w_tile_addr(self.bg_w_data_addr, bg_tile) + 2 * (y as u16 % 8);
                         let pixels = fetcher::fetch_tile_pixels(memory, data_addr)?;
-                        if fifo.push_8(pixels).is_ok() {
-                            *fetcher = if let Some(index) = obj_queued {
-                                Fetcher::Object {
-                                    tile_x: tile_x + 1,
-                                    progress: fetcher::FETCH_STEPS,
-                                    index,
-                                }
-                            } else {
-                                Fetcher::Bg {
-                                    tile_x: tile_x + 1,
-                                    progress: fetcher::FETCH_STEPS,
-                                    cached: None,
-                                    obj_queued: None,
-                                }
-                            };
+                        *fetcher = if fifo.push_8(pixels).is_ok() {
+                            Fetcher::Bg {
+                                tile_x: tile_x + 1,
+                                progress: fetcher::FETCH_STEPS,
+                                cached: None,
+                            }
                         } else {
-                            *fetcher = Fetcher::Bg {
+                            Fetcher::Bg {
                                 tile_x,
                                 progress: 0,
                                 cached: Some(pixels),
-                                obj_queued: None,
-                            };
-                        }
+                            }
+                        };
                     }
                     &mut Fetcher::Window {
                         tile_x,
                         progress: 0,
-                        obj_queued,
                         ..
                     } => {
                         //TODO CGB reads window tilemap attrs
                         let w_tile_addr =
-                            self.w_map_addr + 32 * self.window_counter + tile_x as u16;
+                            self.w_map_addr + 32 * (self.window_counter / 8) + tile_x as u16;
                         let w_tile = memory.read_ppu(w_tile_addr)?;
-                        let data_addr = if self.bg_w_data_addr == DATA_0_START {
-                            DATA_0_START + 16 * (w_tile as u16)
-                        } else if w_tile >= 0x80 {
-                            DATA_1_START + 16 * ((w_tile - 0x80) as u16)
-                        } else {
-                            DATA_2_START + 16 * (w_tile as u16)
-                        } + 2 * (self.window_counter % 8);
+                        let data_addr = bg_w_tile_addr(self.bg_w_data_addr, w_tile)
+                            + 2 * (self.window_counter % 8);
                         let pixels = fetcher::fetch_tile_pixels(memory, data_addr)?;
-                        if fifo.push_8(pixels).is_ok() {
-                            *fetcher = if let Some(index) = obj_queued {
-                                Fetcher::Object {
-                                    tile_x: tile_x + 1,
-                                    progress: fetcher::FETCH_STEPS,
-                                    index,
-                                }
-                            } else {
-                                Fetcher::Window {
-                                    tile_x: tile_x + 1,
-                                    progress: fetcher::FETCH_STEPS,
-                                    cached: None,
-                                    obj_queued: None,
-                                }
-                            };
+                        *fetcher = if fifo.push_8(pixels).is_ok() {
+                            Fetcher::Window {
+                                tile_x: tile_x + 1,
+                                progress: fetcher::FETCH_STEPS,
+                                cached: None,
+                            }
                         } else {
-                            *fetcher = Fetcher::Window {
+                            Fetcher::Window {
                                 tile_x,
                                 progress: 0,
                                 cached: Some(pixels),
-                                obj_queued: None,
-                            };
-                        }
+                            }
+                        };
                     }
-                    &mut Fetcher::Object {
-                        progress: 0,
-                        index,
-                        tile_x,
-                    } => {
-                        let obj = oam.buffer[index];
+                    Fetcher::Bg { progress, .. } | Fetcher::Window { progress, .. } => {
+                        *progress -= 1
+                    }
+                }
+
+                // pixels stall while an object is fetched, its pixels are mixed in at the end
+                let stalled = match obj_fetch {
+                    Some((_, dots)) if *dots > 0 => {
+                        *dots -= 1;
+                        true
+                    }
+                    Some((obj, _)) => {
+                        let obj = *obj;
+                        *obj_fetch = None;
                         if self.mode == Mode::Cgb && obj.bank == 1 {
                             todo!("read tile from cgb bank 1")
                         }
-                        let pixels =
-                            {
-                                let data_addr_offset = if obj.y_flip {
-                                    ((self.obj_height - 1) as i16)
-                                        - ((self.ly as i16) - (obj.y as i16) + 16)
-                                } else {
-                                    (self.ly as i16) - (obj.y as i16) + 16
-                                };
-                                let tile = if self.obj_height == 8 {
-                                    obj.tile
-                                } else {
-                                    obj.tile & 0b11111110
-                                } as u16;
-                                let data_addr =
-                                    DATA_0_START + 16 * tile + 2 * (data_addr_offset as u16);
-                                let mut pixels = fetcher::fetch_tile_pixels(memory, data_addr)?
-                                    .map(|pixel| render::Pixel {
-                                        color: pixel.color,
-                                        palette: obj.palette,
-                                        priority: obj.priority.into(),
-                                        from_obj: true,
-                                    });
-                                if obj.x_flip {
-                                    pixels.reverse();
+                        let data_addr_offset = if obj.y_flip {
+                            ((self.obj_height - 1) as i16)
+                                - ((self.ly as i16) - (obj.y as i16) + 16)
+                        } else {
+                            (self.ly as i16) - (obj.y as i16) + 16
+                        };
+                        let tile = if self.obj_height == 8 {
+                            obj.tile
+                        } else {
+                            obj.tile & 0b11111110
+                        } as u16;
+                        let data_addr = DATA_0_START + 16 * tile + 2 * (data_addr_offset as u16);
+                        let mut pixels =
+                            fetcher::fetch_tile_pixels(memory, data_addr)?.map(|pixel| {
+                                render::Pixel {
+                                    color: pixel.color,
+                                    palette: obj.palette,
+                                    priority: obj.priority.into(),
+                                    from_obj: true,
                                 }
-                                pixels
-                            };
+                            });
+                        if obj.x_flip {
+                            pixels.reverse();
+                        }
 
-                        for (i, &obj_pixel) in pixels.iter().enumerate() {
-                            let fifo_pixel = &mut fifo.buffer[(fifo.front + i) % fifo.buffer.len()];
-                            // https://gbdev.io/pandocs/Tile_Maps.html#bg-to-obj-priority-in-cgb-mode
-                            if (!self.bg_w_priority
-                                || obj_pixel.priority + fifo_pixel.priority == 0
-                                || fifo_pixel.color == 0)
-                                && obj_pixel.color > 0
-                            {
+                        // columns left of the screen are dropped
+                        let hidden = 8usize.saturating_sub(obj.x.into());
+                        let obj_fifo = &mut self.obj_fifo;
+                        obj_fifo.pad(8);
+                        for (i, &obj_pixel) in pixels.iter().enumerate().skip(hidden) {
+                            let fifo_pixel = &mut obj_fifo.buffer
+                                [(obj_fifo.front + i - hidden) % obj_fifo.buffer.len()];
+                            // objects fetched earlier have priority over later ones
+                            if fifo_pixel.color == 0 {
                                 *fifo_pixel = obj_pixel;
                             }
                         }
-
-                        if *in_window {
-                            *fetcher = Fetcher::Window {
-                                tile_x,
-                                progress: fetcher::FETCH_STEPS,
-                                cached: None,
-                                obj_queued: None,
-                            };
-                        } else {
-                            *fetcher = Fetcher::Bg {
-                                tile_x,
-                                progress: fetcher::FETCH_STEPS,
-                                cached: None,
-                                obj_queued: None,
-                            };
-                        }
+                        false
                     }
-                    Fetcher::Bg { progress, .. }
-                    | Fetcher::Window { progress, .. }
-                    | Fetcher::Object { progress, .. } => *progress -= 1,
-                }
+                    None => false,
+                };
 
-                // first SCX%8 columns of the scanline
-                while fifo.len > 0 && *discard > 0 {
+                if stalled || fifo.len == 0 {
+                } else if *discard > 0 {
+                    // first SCX%8 columns of the scanline, one per dot
                     fifo.pop();
                     *discard -= 1;
-                }
-
-                // postpone fifo popping until fetcher is done with object
-                if !fetcher.fetching_obj()
-                    && let Some(pixel) = fifo.pop()
+                } else if self.obj_enabled
+                    && let Some((i, _)) = oam.buffer[..oam.len]
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, obj)| obj.x.saturating_sub(8) == *px)
+                        .min_by_key(|(_, obj)| obj.x)
                 {
-                    let frame_pixel = match (pixel, self.mode) {
-                        (
-                            render::Pixel {
-                                color,
-                                palette,
-                                from_obj: true,
-                                ..
-                            },
-                            Mode::Dmg,
-                        ) => {
-                            let objp = if palette == 0 {
-                                memory.read(mem::OBJ_PALETTE_0_REG)?
-                            } else {
-                                memory.read(mem::OBJ_PALETTE_1_REG)?
-                            };
-                            let color = (objp >> (color * 2)) & 0b00000011;
-                            frame::Pixel::from_2bit(color, self.theme)
-                        }
-
-                        #[expect(unused)]
-                        (
-                            render::Pixel {
-                                color,
-                                palette,
-                                from_obj: true,
-                                ..
-                            },
-                            Mode::Cgb,
-                        ) => todo!("read from cgb obj palette"),
-
-                        (_, Mode::Dmg) if !self.bg_w_priority => {
-                            frame::Pixel::from_2bit(0, self.theme)
-                        }
-
-                        (render::Pixel { color, .. }, Mode::Dmg) => {
-                            let bgp = memory.read(mem::BG_PALETTE_REG)?;
-                            let color = (bgp >> (color * 2)) & 0b00000011;
-                            frame::Pixel::from_2bit(color, self.theme)
-                        }
-
-                        #[expect(unused)]
-                        (render::Pixel { color, palette, .. }, Mode::Cgb) => {
-                            todo!("read from cgb bg palette")
-                        }
+                    let obj = oam.remove(i);
+                    let fine_x = if *in_window {
+                        255 - memory.read(mem::WINDOW_X_REG)?
+                    } else {
+                        scroll_x
                     };
-                    self.frame.0[self.ly as usize][*px as usize].set(frame_pixel);
-
-                    if self.window_enabled
-                        && self.window_latched
-                        && !*in_window
-                        && memory.read(mem::WINDOW_X_REG)? == px.saturating_add(7)
+                    let column = obj.x as u16 + fine_x as u16;
+                    let wait = fetcher::obj_wait(column, *obj_tile);
+                    *obj_tile = Some(column / 8);
+                    // this dot is the first of the stall
+                    *obj_fetch = Some((obj, fetcher::OBJ_FETCH_DOTS + wait - 1));
+                } else if let Some(mut pixel) = fifo.pop() {
+                    if let Some(obj) = self.obj_fifo.pop()
+                        && obj_shows(self.bg_w_priority, obj, pixel)
                     {
+                        pixel = obj;
+                    }
+                    let (shade, color) = pixel_shade(memory, self.mode, self.bg_w_priority, pixel)?;
+                    self.back.set(*px as usize, self.ly as usize, shade, color);
+
+                    *px += 1;
+
+                    // WX is compared on every pixel, so matching it again later in the line
+                    // restarts the window
+                    let wx = memory.read(mem::WINDOW_X_REG)?;
+                    if self.window_enabled && self.window_latched && window_starts(wx, *px) {
+                        // the background pixels left are dropped while the window is fetched
+                        *fifo = Fifo::new();
                         *fetcher = Fetcher::Window {
                             tile_x: 0,
                             progress: fetcher::FETCH_STEPS,
                             cached: None,
-                            obj_queued: None,
                         };
                         *in_window = true;
+                        self.window_wraps = wx == WX_WRAP;
                     }
-                    if self.obj_enabled {
-                        if let Some(i) = oam.buffer[..oam.len]
-                            .iter()
-                            .position(|obj| obj.x.saturating_sub(8) == *px)
-                        {
-                            if fifo.len >= 8 {
-                                *fetcher = Fetcher::Object {
-                                    tile_x: fetcher.tile_x(),
-                                    progress: fetcher::FETCH_STEPS,
-                                    index: i,
-                                };
-                            } else {
-                                match fetcher {
-                                    Fetcher::Bg { obj_queued, .. }
-                                    | Fetcher::Window { obj_queued, .. } => {
-                                        *obj_queued = Some(i);
-                                    }
-                                    Fetcher::Object { .. } => {
-                                        unreachable!("can't pop pixels during object fetch")
-                                    }
-                                }
-                            }
-                        }
-                    }
+                }
+            }
+        }
 
-                    *px += 1;
+        // mode 3 ends as the last pixel is drawn
+        if let State::Drawing {
+            px: X_END,
+            in_window,
+            ..
+        } = self.state
+        {
+            if self.renderer == Renderer::Differential {
+                let (line, _) = self.draw_line(memory)?;
+                let y = self.ly as usize;
+                if let Some(x) = (0..line.len()).find(|&x| self.back.color(x, y) != line[x].1) {
+                    return Err(Error::Mismatch { x, y });
+                }
+                // mode 3 started on the dot after the OAM scan, and ends with this one
+                let fifo = self.dot - OAM_SCAN_DOT_END - 1;
+                let scanline = self.mode_3_dots(memory)?;
+                if fifo != scanline {
+                    return Err(Error::TimingMismatch {
+                        ly: self.ly,
+                        fifo,
+                        scanline,
+                    });
                 }
             }
+            if in_window {
+                self.window_counter += 1;
+            }
+            self.state = State::Hblank;
+            memory.set_lock(mem::Lock::Unlocked);
         }
 
         self.dot = (self.dot + 1) % DOT_END;
+        let lyc_match = memory.read(mem::LY_REG)? == memory.read(mem::LYC_REG)?;
         let stat_bits = [
             true,
             self.lyc_int_enable,
@@ -543,11 +603,17 @@ impl Ppu {
             lyc_match,
             matches!(
                 self.state,
-                State::OamScan { .. } | State::FirstFetch { .. } | State::Drawing { .. }
+                State::OamScan { .. }
+                    | State::FirstFetch { .. }
+                    | State::Drawing { .. }
+                    | State::Scanline { .. }
             ),
             matches!(
                 self.state,
-                State::Vblank | State::FirstFetch { .. } | State::Drawing { .. }
+                State::Vblank
+                    | State::FirstFetch { .. }
+                    | State::Drawing { .. }
+                    | State::Scanline { .. }
             ),
         ];
         let stat = stat_bits
@@ -558,19 +624,42 @@ impl Ppu {
             memory.write_ppu(mem::LCD_STAT_REG, stat)?;
             self.prev_stat = stat;
         }
-        // if LY=LYC or a mode interrupt is enabled, and the condition is met, set LCD IF
-        match stat_bits {
+        // if LY=LYC or a mode interrupt is enabled, and the condition is met, set LCD IF.
+        // A condition being met while another already is doesn't request another interrupt
+        let stat_line = matches!(
+            stat_bits,
             [_, true, _, _, _, true, _, _]
-            | [_, _, true, _, _, _, true, false]
-            | [_, _, _, true, _, _, false, true]
-            | [_, _, _, _, true, _, false, false] => {
-                memory.write_ppu(mem::IF_REG, memory.read(mem::IF_REG)? | 0b00000010)?
-            }
-            _ => {}
+                | [_, _, true, _, _, _, true, false]
+                | [_, _, _, true, _, _, false, true]
+                | [_, _, _, _, true, _, false, false]
+        );
+        if stat_line && !self.stat_line {
+            memory.write_ppu(mem::IF_REG, memory.read(mem::IF_REG)? | 0b00000010)?
         }
+        self.stat_line = stat_line;
         Ok(frame)
     }
 
+    /// LY and the STAT mode read 0 while the LCD is off, and drawing restarts from the first
+    /// line once it's enabled again
+    fn disable(&mut self, memory: &mut Memory) -> Result<(), Error> {
+        self.ly = 0;
+        self.dot = 0;
+        self.window_latched = false;
+        self.window_counter = 0;
+        self.window_wraps = false;
+        self.wrapped_line = false;
+        self.stat_line = false;
+        self.state = State::OamScan {
+            oam: Default::default(),
+        };
+        memory.write_ppu(mem::LY_REG, 0)?;
+        self.prev_stat = memory.read(mem::LCD_STAT_REG)? & 0b11111100;
+        memory.write_ppu(mem::LCD_STAT_REG, self.prev_stat)?;
+        memory.set_lock(mem::Lock::Unlocked);
+        Ok(())
+    }
+
     fn read_lcdc_stat(&mut self, memory: &Memory) -> Result<(), Error> {
         let lcdc = memory.read(mem::LCD_CTRL_REG)?;
         self.enabled = lcdc & 0b10000000 != 0;
@@ -659,6 +748,10 @@ impl Ppu {
                 );
                 Some(oam)
             }
+            State::Scanline { dots } => {
+                log::info!("state: scanline, dots left: {dots}");
+                Some(&self.objects)
+            }
         };
         if let Some(OamBuf { buffer, len }) = oam {
             for (
@@ -683,3 +776,77 @@ impl Ppu {
         }
     }
 }
+
+/// Whether the window starts at pixel `px`. WX below 7 starts it at the left edge, with the
+/// columns left of the screen cut off
+fn window_starts(wx: u8, px: u8) -> bool {
+    px < X_END && (wx as u16 == px as u16 + WX_OFFSET as u16 || px == 0 && wx < WX_OFFSET)
+}
+
+/// Tile data address of a background or window tile, which is signed in the $8800 addressing mode
+fn bg_w_tile_addr(data_addr: u16, tile: u8) -> u16 {
+    if data_addr == DATA_0_START {
+        DATA_0_START + 16 * (tile as u16)
+    } else if tile >= 0x80 {
+        DATA_1_START + 16 * ((tile - 0x80) as u16)
+    } else {
+        DATA_2_START + 16 * (tile as u16)
+    }
+}
+
+/// The shade a pixel is displayed with through the palette registers, and its frame palette entry
+fn pixel_shade(
+    memory: &Memory,
+    mode: Mode,
+    bg_w_priority: bool,
+    pixel: render::Pixel,
+) -> Result<(u8, u8), Error> {
+    let (shade, palette) = match (pixel, mode) {
+        (
+            render::Pixel {
+                color,
+                palette,
+                from_obj: true,
+                ..
+            },
+            Mode::Dmg | Mode::Sgb,
+        ) => {
+            let (objp, palette) = if palette == 0 {
+                (memory.read(mem::OBJ_PALETTE_0_REG)?, Palettes::OBJ0)
+            } else {
+                (memory.read(mem::OBJ_PALETTE_1_REG)?, Palettes::OBJ1)
+            };
+            ((objp >> (color * 2)) & 0b00000011, palette)
+        }
+
+        #[expect(unused)]
+        (
+            render::Pixel {
+                color,
+                palette,
+                from_obj: true,
+                ..
+            },
+            Mode::Cgb,
+        ) => todo!("read from cgb obj palette"),
+
+        (_, Mode::Dmg | Mode::Sgb) if !bg_w_priority => (0, Palettes::BG),
+
+        (render::Pixel { color, .. }, Mode::Dmg | Mode::Sgb) => {
+            let bgp = memory.read(mem::BG_PALETTE_REG)?;
+            ((bgp >> (color * 2)) & 0b00000011, Palettes::BG)
+        }
+
+        #[expect(unused)]
+        (render::Pixel { color, palette, .. }, Mode::Cgb) => {
+            todo!("read from cgb bg palette")
+        }
+    };
+    Ok((shade, palette + shade))
+}
+
+/// Whether an object pixel is drawn over a background or window pixel
+/// https://gbdev.io/pandocs/Tile_Maps.html#bg-to-obj-priority-in-cgb-mode
+fn obj_shows(bg_w_priority: bool, obj: render::Pixel, under: render::Pixel) -> bool {
+    obj.color > 0 && (!bg_w_priority || obj.priority + under.priority == 0 || under.color == 0)
+}
@@ -0,0 +1,143 @@
+use super::{DATA_0_START, Ppu, WX_OFFSET, WX_WRAP, X_END, bg_w_tile_addr, obj_shows, pixel_shade};
+use crate::{
+    mem::{self, Memory},
+    render::{self, Error, fetcher},
+};
+
+// mode 3 length before any penalties
+const MODE_3_DOTS: u16 = 172;
+
+/// Shade and frame palette entry of each pixel of a line
+type Line = [(u8, u8); X_END as usize];
+
+impl Ppu {
+    /// Mode 3 length, with the penalties the FIFO would stall for
+    pub(super) fn mode_3_dots(&self, memory: &Memory) -> Result<u16, Error> {
+        let scroll_x = memory.read(mem::SCROLL_X_REG)?;
+        let wx = memory.read(mem::WINDOW_X_REG)?;
+        let window = self.window_start(memory)?;
+        let mut dots = MODE_3_DOTS
+            + match window {
+                // the window replaces the fine scroll
+                Some((0, hidden)) => hidden as u16,
+                Some(_) => (scroll_x % 8 + fetcher::FETCH_STEPS) as u16,
+                None => (scroll_x % 8) as u16,
+            };
+
+        if self.obj_enabled {
+            let mut objects = self.objects;
+            objects.buffer[..objects.len].sort_by_key(|obj| obj.x);
+            let mut last_tile = None;
+            // the FIFO reaches objects as it draws their leftmost column
+            let drawn = objects.buffer[..objects.len]
+                .iter()
+                .filter(|obj| obj.x < X_END + 8);
+            for obj in drawn {
+                let in_window = window.is_some_and(|(start, _)| obj.x.saturating_sub(8) >= start);
+                let fine_x = if in_window { 255 - wx } else { scroll_x };
+                let column = obj.x as u16 + fine_x as u16;
+                dots += (fetcher::OBJ_FETCH_DOTS + fetcher::obj_wait(column, last_tile)) as u16;
+                last_tile = Some(column / 8);
+            }
+        }
+        Ok(dots)
+    }
+
+    /// Draw the current line from the registers as they are, returning whether the window was
+    /// drawn on it
+    pub(super) fn draw_line(&self, memory: &Memory) -> Result<(Line, bool), Error> {
+        let scroll_x = memory.read(mem::SCROLL_X_REG)?;
+        let y = memory.read(mem::SCROLL_Y_REG)?.wrapping_add(self.ly);
+        let window = self.window_start(memory)?;
+        // lower X first, then OAM order
+        let mut objects = self.objects;
+        objects.buffer[..objects.len].sort_by_key(|obj| obj.x);
+
+        let mut line = [(0, 0); X_END as usize];
+        // tile data address and pixels of the last tile fetched
+        let mut row = None;
+        let mut fetch_row = |data_addr: u16| -> Result<[render::Pixel; 8], Error> {
+            match row {
+                Some((addr, pixels)) if addr == data_addr => Ok(pixels),
+                _ => {
+                    let pixels = fetcher::fetch_tile_pixels(memory, data_addr)?;
+                    row = Some((data_addr, pixels));
+                    Ok(pixels)
+                }
+            }
+        };
+        for (px, out) in (0..X_END).zip(&mut line) {
+            let color = match window {
+                Some((start, hidden)) if px >= start => {
+                    let column = (px - start + hidden) as u16;
+                    let tile_addr = self.w_map_addr + 32 * (self.window_counter / 8) + column / 8;
+                    let tile = memory.read_ppu(tile_addr)?;
+                    let data_addr =
+                        bg_w_tile_addr(self.bg_w_data_addr, tile) + 2 * (self.window_counter % 8);
+                    fetch_row(data_addr)?[column as usize % 8].color
+                }
+                _ => {
+                    let x = scroll_x.wrapping_add(px);
+                    let tile_addr = self.bg_map_addr + 32 * (y as u16 / 8) + x as u16 / 8;
+                    let tile = memory.read_ppu(tile_addr)?;
+                    let data_addr = bg_w_tile_addr(self.bg_w_data_addr, tile) + 2 * (y as u16 % 8);
+                    fetch_row(data_addr)?[x as usize % 8].color
+                }
+            };
+            let mut pixel = render::Pixel {
+                color,
+                ..Default::default()
+            };
+
+            for obj in objects.buffer[..objects.len].iter().filter(|obj| {
+                self.obj_enabled && (obj.x..obj.x.saturating_add(8)).contains(&(px + 8))
+            }) {
+                let mut column = px + 8 - obj.x;
+                if obj.x_flip {
+                    column = 7 - column;
+                }
+                let mut row = self.ly + 16 - obj.y;
+                if obj.y_flip {
+                    row = self.obj_height - 1 - row;
+                }
+                let tile = if self.obj_height == 8 {
+                    obj.tile
+                } else {
+                    obj.tile & 0b11111110
+                } as u16;
+                let data_addr = DATA_0_START + 16 * tile + 2 * row as u16;
+                let obj_pixel = render::Pixel {
+                    color: fetcher::fetch_tile_pixels(memory, data_addr)?[column as usize].color,
+                    palette: obj.palette,
+                    priority: obj.priority.into(),
+                    from_obj: true,
+                };
+                // the first opaque object pixel hides the others, even behind the background
+                if obj_pixel.color > 0 {
+                    if obj_shows(self.bg_w_priority, obj_pixel, pixel) {
+                        pixel = obj_pixel;
+                    }
+                    break;
+                }
+            }
+
+            *out = pixel_shade(memory, self.mode, self.bg_w_priority, pixel)?;
+        }
+        Ok((line, window.is_some()))
+    }
+
+    /// The first pixel the window is drawn on this line, and how many of its columns are left
+    /// of the screen
+    fn window_start(&self, memory: &Memory) -> Result<Option<(u8, u8)>, Error> {
+        let wx = memory.read(mem::WINDOW_X_REG)?;
+        Ok(if !self.window_enabled {
+            None
+        } else if self.wrapped_line {
+            Some((0, 0))
+        } else if self.window_latched && wx <= WX_WRAP {
+            Some((wx.saturating_sub(WX_OFFSET), WX_OFFSET.saturating_sub(wx)))
+        } else {
+            None
+        })
+    }
+}
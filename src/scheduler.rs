@@ -0,0 +1,73 @@
+/// Subsystems ticked when they have something to do. Between its events, a subsystem only
+/// counts dots, and catches up on them before it's next ticked or seen by the CPU
+#[derive(Copy, Clone, Debug)]
+pub enum Event {
+    /// A falling edge of the timer's input or of DIV-APU's bit, or a step of TIMA's reload
+    Timer,
+    /// A bit shifted by a serial transfer
+    Serial,
+    /// A byte copied by OAM DMA
+    OamDma,
+    /// A PPU mode transition, or any dot of mode 3 for the FIFO
+    Ppu,
+}
+
+impl Event {
+    /// In the order they're ticked within a dot
+    pub const ALL: [Self; 4] = [Self::Timer, Self::Serial, Self::OamDma, Self::Ppu];
+}
+
+#[derive(Default, Debug)]
+pub struct Scheduler {
+    /// Dots since the scheduler started
+    now: u64,
+    /// The dot each event is next due on
+    due: [u64; Event::ALL.len()],
+    /// The dot each subsystem has caught up to
+    synced: [u64; Event::ALL.len()],
+}
+
+impl Scheduler {
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub fn advance(&mut self, dots: u64) {
+        self.now += dots;
+    }
+
+    pub fn is_due(&self, event: Event) -> bool {
+        self.due[event as usize] <= self.now
+    }
+
+    /// The dot the next event is due on
+    pub fn next_due(&self) -> u64 {
+        self.due.into_iter().min().expect("events are scheduled")
+    }
+
+    /// Mark the subsystem as ticked this dot, returning the dots it skipped before it
+    pub fn tick(&mut self, event: Event) -> u64 {
+        let skipped = self.now - self.synced[event as usize] - 1;
+        self.synced[event as usize] = self.now;
+        skipped
+    }
+
+    /// Make the subsystem due again once it's idled for `idle` dots
+    pub fn schedule(&mut self, event: Event, idle: u64) {
+        self.due[event as usize] = self.now.saturating_add(idle).saturating_add(1);
+    }
+
+    /// Returns the dots the subsystem skipped up to and including this one
+    pub fn catch_up(&mut self, event: Event) -> u64 {
+        let skipped = self.now - self.synced[event as usize];
+        self.synced[event as usize] = self.now;
+        skipped
+    }
+
+    /// Tick every subsystem on the next dot, as a register write may have changed their plans
+    pub fn wake(&mut self) {
+        for due in &mut self.due {
+            *due = (*due).min(self.now + 1);
+        }
+    }
+}
@@ -0,0 +1,458 @@
+use crate::frame::{self, Frame, Pixel};
+use serde::{Deserialize, Serialize};
+
+const PACKET_LEN: usize = 16;
+const PACKET_BITS: usize = PACKET_LEN * 8;
+const TRANSFER_LEN: usize = 4096;
+
+const CELLS_X: usize = frame::WIDTH / 8;
+const CELLS_Y: usize = frame::HEIGHT / 8;
+const SCREEN_X: usize = (frame::SGB_WIDTH - frame::WIDTH) / 2;
+const SCREEN_Y: usize = (frame::SGB_HEIGHT - frame::HEIGHT) / 2;
+
+const SYSTEM_PALETTES: usize = 512;
+const ATTR_FILES: usize = 45;
+const ATTR_FILE_LEN: usize = CELLS_X * CELLS_Y / 4;
+const BORDER_TILES: usize = 256;
+const BORDER_TILE_LEN: usize = 32;
+const BORDER_MAP_X: usize = frame::SGB_WIDTH / 8;
+const BORDER_MAP_Y: usize = frame::SGB_HEIGHT / 8;
+const BORDER_PALETTES_START: usize = 0x800;
+// the rendered frame's palette holds the game's 4 palettes, the border's 4 and black
+const BORDER_ENTRIES: usize = 16;
+const BLACK_ENTRY: usize = BORDER_ENTRIES + 4 * 16;
+
+/// Palette 0 set by the SGB BIOS, as RGB555
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const ATTR_LIN: u8 = 0x05;
+const ATTR_DIV: u8 = 0x06;
+const ATTR_CHR: u8 = 0x07;
+const PAL_SET: u8 = 0x0A;
+const PAL_TRN: u8 = 0x0B;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const ATTR_TRN: u8 = 0x15;
+const ATTR_SET: u8 = 0x16;
+const MASK_EN: u8 = 0x17;
+
+/// The SNES side of a Super Game Boy, receiving command packets through the joypad register
+/// and colorizing the game screen within a border
+#[derive(Serialize, Deserialize)]
+pub struct Sgb {
+    // commands are ignored unless the cart header enables SGB features
+    enabled: bool,
+    joypad_select: u8,
+    packet: [u8; PACKET_LEN],
+    bit: Option<usize>,
+    // packets received so far for a command spanning several
+    command: Vec<u8>,
+    transfer: Option<Transfer>,
+    mask: Mask,
+    players: u8,
+    player: u8,
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<[u16; 4]>,
+    #[serde(with = "serde_bytes")]
+    attributes: [u8; CELLS_X * CELLS_Y],
+    #[serde(with = "serde_bytes")]
+    attr_files: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    border_tiles: Vec<u8>,
+    border_map: Vec<u16>,
+    border_palettes: [[u16; 16]; 4],
+    // the last game screen shown, kept while the screen is frozen
+    #[serde(skip)]
+    screen: Frame,
+    #[serde(skip, default = "sgb_frame")]
+    frame: Frame,
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize, Debug)]
+enum Transfer {
+    Palettes,
+    BorderTiles { upper: bool },
+    Border,
+    AttrFiles,
+}
+
+#[derive(Copy, Clone, PartialEq, Serialize, Deserialize, Debug)]
+enum Mask {
+    Cancel,
+    Freeze,
+    Black,
+    Color0,
+}
+
+fn sgb_frame() -> Frame {
+    Frame::new(frame::SGB_WIDTH, frame::SGB_HEIGHT)
+}
+
+fn color(rgb555: u16) -> Pixel {
+    Pixel::from_rgb555(rgb555.to_le_bytes())
+}
+
+fn u16_at(data: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([data[i], data[i + 1]])
+}
+
+impl Sgb {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            joypad_select: 0b00110000,
+            packet: [0; _],
+            bit: None,
+            command: vec![],
+            transfer: None,
+            mask: Mask::Cancel,
+            players: 1,
+            player: 0,
+            palettes: [DEFAULT_PALETTE; _],
+            system_palettes: vec![[0; 4]; SYSTEM_PALETTES],
+            attributes: [0; _],
+            attr_files: vec![0; ATTR_FILES * ATTR_FILE_LEN],
+            border_tiles: vec![0; BORDER_TILES * BORDER_TILE_LEN],
+            border_map: vec![0; BORDER_MAP_X * BORDER_MAP_Y],
+            border_palettes: [[0; 16]; 4],
+            screen: Frame::default(),
+            frame: sgb_frame(),
+        }
+    }
+
+    /// The joypad currently read through the joypad register, when several are connected
+    pub fn player(&self) -> u8 {
+        self.player
+    }
+
+    /// Packets start with a reset pulse (P14 and P15 low), followed by 128 bits sent as a
+    /// P14 (1) or P15 (0) pulse each, and a 0 stop bit
+    pub fn write_joypad(&mut self, value: u8) {
+        let select = value & 0b00110000;
+        let prev = std::mem::replace(&mut self.joypad_select, select);
+        match (select, self.bit) {
+            (0b00000000, _) => {
+                self.packet = [0; _];
+                self.bit = Some(0);
+            }
+            (0b00010000 | 0b00100000, Some(bit)) if prev == 0b00110000 => {
+                let one = select == 0b00010000;
+                if bit < PACKET_BITS {
+                    self.packet[bit / 8] |= (one as u8) << (bit % 8);
+                    self.bit = Some(bit + 1);
+                } else {
+                    self.bit = None;
+                    if !one {
+                        self.receive_packet();
+                    }
+                }
+            }
+            // the next joypad is selected when P15 goes high
+            (0b00110000, None) if prev & 0b00100000 == 0 && self.players > 1 => {
+                self.player = (self.player + 1) % self.players;
+            }
+            _ => {}
+        }
+    }
+
+    fn receive_packet(&mut self) {
+        self.command.extend_from_slice(&self.packet);
+        let packets = (self.command[0] & 0b00000111).max(1) as usize;
+        if self.command.len() < packets * PACKET_LEN {
+            return;
+        }
+        let command = std::mem::take(&mut self.command);
+        if self.enabled {
+            self.execute(&command);
+        }
+    }
+
+    fn execute(&mut self, data: &[u8]) {
+        let command = data[0] >> 3;
+        log::debug!(command; "SGB command");
+        match command {
+            PAL01 => self.set_palettes(0, 1, data),
+            PAL23 => self.set_palettes(2, 3, data),
+            PAL03 => self.set_palettes(0, 3, data),
+            PAL12 => self.set_palettes(1, 2, data),
+            ATTR_BLK => {
+                let sets = (data[1] as usize).min((data.len() - 2) / 6);
+                for set in data[2..].chunks_exact(6).take(sets) {
+                    self.attr_block(set);
+                }
+            }
+            ATTR_LIN => {
+                let lines = (data[1] as usize).min(data.len() - 2);
+                for &line in &data[2..2 + lines] {
+                    let (n, palette) = ((line & 0b00011111) as usize, (line >> 5) & 0b11);
+                    let horizontal = line & 0b10000000 != 0;
+                    self.set_cells(palette, |x, y| if horizontal { y == n } else { x == n });
+                }
+            }
+            ATTR_DIV => {
+                let [below, above, on] = [0, 2, 4].map(|shift| (data[1] >> shift) & 0b11);
+                let horizontal = data[1] & 0b01000000 != 0;
+                let n = data[2] as usize;
+                for y in 0..CELLS_Y {
+                    for x in 0..CELLS_X {
+                        let i = if horizontal { y } else { x };
+                        self.attributes[y * CELLS_X + x] = match i.cmp(&n) {
+                            std::cmp::Ordering::Less => above,
+                            std::cmp::Ordering::Equal => on,
+                            std::cmp::Ordering::Greater => below,
+                        };
+                    }
+                }
+            }
+            ATTR_CHR => {
+                let (mut x, mut y) = (data[1] as usize, data[2] as usize);
+                let cells = (u16_at(data, 3) as usize).min((data.len() - 6) * 4);
+                let vertical = data[5] != 0;
+                for i in 0..cells {
+                    if x >= CELLS_X || y >= CELLS_Y {
+                        break;
+                    }
+                    let palette = (data[6 + i / 4] >> (6 - 2 * (i % 4))) & 0b11;
+                    self.attributes[y * CELLS_X + x] = palette;
+                    if vertical {
+                        y += 1;
+                        if y == CELLS_Y {
+                            (x, y) = (x + 1, 0);
+                        }
+                    } else {
+                        x += 1;
+                        if x == CELLS_X {
+                            (x, y) = (0, y + 1);
+                        }
+                    }
+                }
+            }
+            PAL_SET => {
+                for (i, palette) in self.palettes.iter_mut().enumerate() {
+                    let index = u16_at(data, 1 + i * 2) as usize % SYSTEM_PALETTES;
+                    *palette = self.system_palettes[index];
+                }
+                self.share_color_0();
+                if data[9] & 0b10000000 != 0 {
+                    self.apply_attr_file((data[9] & 0b00111111) as usize);
+                }
+                if data[9] & 0b01000000 != 0 {
+                    self.mask = Mask::Cancel;
+                }
+            }
+            PAL_TRN => self.transfer = Some(Transfer::Palettes),
+            MLT_REQ => {
+                self.players = match data[1] & 0b11 {
+                    0b01 => 2,
+                    0b11 => 4,
+                    _ => 1,
+                };
+                self.player = 0;
+            }
+            CHR_TRN => {
+                self.transfer = Some(Transfer::BorderTiles {
+                    upper: data[1] & 0b1 != 0,
+                })
+            }
+            PCT_TRN => self.transfer = Some(Transfer::Border),
+            ATTR_TRN => self.transfer = Some(Transfer::AttrFiles),
+            ATTR_SET => {
+                self.apply_attr_file((data[1] & 0b00111111) as usize);
+                if data[1] & 0b01000000 != 0 {
+                    self.mask = Mask::Cancel;
+                }
+            }
+            MASK_EN => {
+                self.mask = match data[1] & 0b11 {
+                    0 => Mask::Cancel,
+                    1 => Mask::Freeze,
+                    2 => Mask::Black,
+                    _ => Mask::Color0,
+                }
+            }
+            _ => log::debug!(command; "unsupported SGB command"),
+        }
+    }
+
+    fn set_palettes(&mut self, a: usize, b: usize, data: &[u8]) {
+        self.palettes[0][0] = u16_at(data, 1);
+        for color in 1..4 {
+            self.palettes[a][color] = u16_at(data, 1 + color * 2);
+            self.palettes[b][color] = u16_at(data, 7 + color * 2);
+        }
+        self.share_color_0();
+    }
+
+    /// Color 0 of palette 0 is used by every palette
+    fn share_color_0(&mut self) {
+        let color_0 = self.palettes[0][0];
+        for palette in &mut self.palettes {
+            palette[0] = color_0;
+        }
+    }
+
+    fn set_cells(&mut self, palette: u8, mut within: impl FnMut(usize, usize) -> bool) {
+        for y in 0..CELLS_Y {
+            for x in 0..CELLS_X {
+                if within(x, y) {
+                    self.attributes[y * CELLS_X + x] = palette;
+                }
+            }
+        }
+    }
+
+    fn attr_block(&mut self, set: &[u8]) {
+        let &[control, palettes, x1, y1, x2, y2] = set else {
+            return;
+        };
+        let [x1, y1, x2, y2] = [x1, y1, x2, y2].map(usize::from);
+        let [inside, border, outside] = [0, 2, 4].map(|shift| (palettes >> shift) & 0b11);
+        // when only the inside or outside changes, the border changes with it
+        let (change_border, border) = match control & 0b111 {
+            0b001 => (true, inside),
+            0b100 => (true, outside),
+            control => (control & 0b010 != 0, border),
+        };
+        for y in 0..CELLS_Y {
+            for x in 0..CELLS_X {
+                let within = (x1..=x2).contains(&x) && (y1..=y2).contains(&y);
+                let on_border = within && (x == x1 || x == x2 || y == y1 || y == y2);
+                let palette = if on_border {
+                    change_border.then_some(border)
+                } else if within {
+                    (control & 0b001 != 0).then_some(inside)
+                } else {
+                    (control & 0b100 != 0).then_some(outside)
+                };
+                if let Some(palette) = palette {
+                    self.attributes[y * CELLS_X + x] = palette;
+                }
+            }
+        }
+    }
+
+    fn apply_attr_file(&mut self, file: usize) {
+        if file >= ATTR_FILES {
+            return;
+        }
+        let data = &self.attr_files[file * ATTR_FILE_LEN..][..ATTR_FILE_LEN];
+        for (i, attribute) in self.attributes.iter_mut().enumerate() {
+            *attribute = (data[i / 4] >> (6 - 2 * (i % 4))) & 0b11;
+        }
+    }
+
+    /// VRAM transfers read the tiles shown on screen, as 2bpp tile data
+    fn receive_transfer(&mut self, transfer: Transfer, screen: &Frame) {
+        let mut data = vec![0; TRANSFER_LEN];
+        for (i, row) in data.chunks_exact_mut(2).enumerate() {
+            let (tile, y) = (i / 8, i % 8);
+            let (x, y) = ((tile % CELLS_X) * 8, (tile / CELLS_X) * 8 + y);
+            for px in 0..8 {
+                let shade = screen.as_indices()[y * frame::WIDTH + x + px];
+                row[0] |= (shade & 0b01) << (7 - px);
+                row[1] |= ((shade & 0b10) >> 1) << (7 - px);
+            }
+        }
+        log::debug!(transfer:?; "SGB VRAM transfer");
+        match transfer {
+            Transfer::Palettes => {
+                for (palette, colors) in self.system_palettes.iter_mut().zip(data.chunks(8)) {
+                    *palette = std::array::from_fn(|i| u16_at(colors, i * 2));
+                }
+            }
+            Transfer::BorderTiles { upper } => {
+                let start = if upper { TRANSFER_LEN } else { 0 };
+                self.border_tiles[start..start + TRANSFER_LEN].copy_from_slice(&data);
+            }
+            Transfer::Border => {
+                for (i, entry) in self.border_map.iter_mut().enumerate() {
+                    *entry = u16_at(&data, i * 2);
+                }
+                for (i, palette) in self.border_palettes.iter_mut().enumerate() {
+                    *palette = std::array::from_fn(|color| {
+                        u16_at(&data, BORDER_PALETTES_START + (i * 16 + color) * 2)
+                    });
+                }
+            }
+            Transfer::AttrFiles => {
+                self.attr_files
+                    .copy_from_slice(&data[..ATTR_FILES * ATTR_FILE_LEN]);
+            }
+        }
+    }
+
+    /// The border's color index at a pixel, where 0 is transparent, and its palette
+    fn border_pixel(&self, x: usize, y: usize) -> (usize, usize) {
+        let entry = self.border_map[(y / 8) * BORDER_MAP_X + x / 8];
+        let tile = (entry & 0xFF) as usize;
+        let palette = ((entry >> 10) & 0b11) as usize;
+        let px = if entry & 0x4000 != 0 {
+            7 - x % 8
+        } else {
+            x % 8
+        };
+        let row = if entry & 0x8000 != 0 {
+            7 - y % 8
+        } else {
+            y % 8
+        };
+        let data = &self.border_tiles[tile * BORDER_TILE_LEN..][..BORDER_TILE_LEN];
+        let planes = [
+            data[row * 2],
+            data[row * 2 + 1],
+            data[16 + row * 2],
+            data[17 + row * 2],
+        ];
+        let index = planes.iter().enumerate().fold(0, |acc, (plane, bits)| {
+            acc | (((bits >> (7 - px)) & 1) as usize) << plane
+        });
+        (index, palette)
+    }
+
+    /// Colorize the game screen and surround it with the border
+    pub fn render(&mut self, screen: &Frame) -> &Frame {
+        if let Some(transfer) = self.transfer.take() {
+            self.receive_transfer(transfer, screen);
+        }
+        if self.mask != Mask::Freeze {
+            self.screen.clone_from(screen);
+        }
+        let palette = self.palettes.iter().flatten();
+        let border_palette = self.border_palettes.iter().flatten();
+        self.frame.set_palette(
+            palette
+                .chain(border_palette)
+                .map(|&rgb555| color(rgb555))
+                .chain([Pixel(0, 0, 0)]),
+        );
+        for y in 0..frame::SGB_HEIGHT {
+            for x in 0..frame::SGB_WIDTH {
+                let (index, palette) = self.border_pixel(x, y);
+                let (sx, sy) = (x.wrapping_sub(SCREEN_X), y.wrapping_sub(SCREEN_Y));
+                let (shade, entry) = if index != 0 {
+                    (0, BORDER_ENTRIES + palette * 16 + index)
+                } else if sx < frame::WIDTH && sy < frame::HEIGHT {
+                    let shade = self.screen.as_indices()[sy * frame::WIDTH + sx];
+                    let palette = self.attributes[(sy / 8) * CELLS_X + sx / 8] as usize;
+                    let entry = match self.mask {
+                        Mask::Black => BLACK_ENTRY,
+                        Mask::Color0 => 0,
+                        Mask::Cancel | Mask::Freeze => palette * 4 + shade as usize,
+                    };
+                    (shade, entry)
+                } else {
+                    (0, 0)
+                };
+                self.frame.set(x, y, shade, entry as u8);
+            }
+        }
+        self.frame.finish();
+        &self.frame
+    }
+}
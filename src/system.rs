@@ -1,16 +1,22 @@
 use crate::{
-    Input, Mode, Options, SymbolError,
+    Input, Mode, Model, OpCacheStats, Options, Scheduling, SymbolError,
     audio::Apu,
-    cart::Cart,
+    cart::{self, Cart},
     frame::Frame,
-    mem::{self, Memory, Tile},
-    opcode::*,
+    mem::{self, Fetch, Memory, Tile},
+    opcode::{self, *},
     register::RegisterSet,
     render::{self, ppu::Ppu},
+    scheduler::{Event, Scheduler},
     util::{self, Hex, ScreenPos},
 };
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fmt::Debug, io::Read};
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug, Display, Formatter},
+    io::{Read, Write},
+    sync::LazyLock,
+};
 
 #[derive(Serialize, Deserialize)]
 pub struct System {
@@ -19,12 +25,27 @@ pub struct System {
     reg_set: RegisterSet,
     memory: Memory,
     current_op: Op,
-    op_duration: Duration,
+    /// The current op's bytes, which are fetched before it runs
+    fetch: Fetch,
+    /// Dots since the current instruction (or interrupt dispatch) started
+    dots: u8,
+    /// Byte read by an earlier M-cycle of the current instruction
+    latch: Option<u8>,
     ppu: Ppu,
     apu: Apu,
+    #[serde(skip)]
+    scheduler: Scheduler,
+    /// Dots emulated since the system was created or loaded
+    #[serde(skip)]
+    elapsed_dots: u64,
     state: State,
     ime: bool,
+    /// EI enables interrupts once the instruction after it is done
+    enabling_ime: bool,
+    /// HALT didn't increment pc, so the next op's first byte is read twice
+    halt_bug: bool,
     cart_hash: String,
+    model: Model,
     #[serde(skip)]
     stack_frames: Vec<Address>,
     #[serde(skip)]
@@ -35,14 +56,95 @@ pub struct System {
 
 #[derive(Debug)]
 pub enum Error {
+    /// A memory access failed while fetching or running the op at `pc`
+    Cpu {
+        pc: u16,
+        bank: Option<u16>,
+        /// The op's bytes, or the bytes it could start with if it couldn't be decoded
+        op: Vec<u8>,
+        source: mem::Error,
+    },
     Memory(mem::Error),
     Render(render::Error),
+    Cart(cart::Error),
     Load(rmp_serde::decode::Error),
     WrongCart,
+    /// The cart would run in CGB mode, which isn't emulated yet
+    CgbMode,
     Save(rmp_serde::encode::Error),
     ShortCircuit,
+    /// The renderers drew a different pixel, in differential mode
+    RendererMismatch {
+        x: usize,
+        y: usize,
+    },
+    /// The renderers' mode 3 took a different number of dots, in differential mode
+    RendererTimingMismatch {
+        ly: u8,
+        fifo: u16,
+        scanline: u16,
+    },
     Symbol(SymbolError),
     Breakpoint(String),
+    Trace(std::io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cpu { pc, bank, op, .. } => {
+                write!(f, "CPU fault at {pc:04X}")?;
+                if let Some(bank) = bank {
+                    write!(f, " in bank {bank:02}")?;
+                }
+                if !op.is_empty() {
+                    write!(f, " running op")?;
+                    for byte in op {
+                        write!(f, " {byte:02X}")?;
+                    }
+                }
+                Ok(())
+            }
+            Self::Memory(_) => write!(f, "memory access failed"),
+            Self::Render(_) => write!(f, "rendering failed"),
+            Self::Cart(_) => write!(f, "cart can't be emulated"),
+            Self::Load(_) => write!(f, "couldn't load state"),
+            Self::WrongCart => write!(f, "state was saved with a different cart"),
+            Self::CgbMode => write!(f, "CGB mode isn't supported, run CGB carts as a DMG or SGB"),
+            Self::Save(_) => write!(f, "couldn't save state"),
+            Self::ShortCircuit => write!(f, "short-circuited"),
+            Self::RendererMismatch { x, y } => {
+                write!(f, "renderers disagree at pixel ({x}, {y})")
+            }
+            Self::RendererTimingMismatch { ly, fifo, scanline } => write!(
+                f,
+                "renderers disagree on mode 3 of line {ly}, {fifo} dots instead of {scanline}"
+            ),
+            Self::Symbol(_) => write!(f, "couldn't set up symbols"),
+            Self::Breakpoint(breakpoint) => write!(f, "reached breakpoint {breakpoint}"),
+            Self::Trace(_) => write!(f, "couldn't write trace"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Cpu { source, .. } | Self::Memory(source) => Some(source),
+            Self::Render(err) => Some(err),
+            Self::Cart(err) => Some(err),
+            Self::Load(err) => Some(err),
+            Self::Save(err) => Some(err),
+            Self::Symbol(err) => Some(err),
+            Self::Trace(err) => Some(err),
+            Self::WrongCart
+            | Self::CgbMode
+            | Self::ShortCircuit
+            | Self::RendererMismatch { .. }
+            | Self::RendererTimingMismatch { .. }
+            | Self::Breakpoint(_) => None,
+        }
+    }
 }
 
 impl From<mem::Error> for Error {
@@ -53,16 +155,45 @@ impl From<mem::Error> for Error {
 
 impl From<render::Error> for Error {
     fn from(err: render::Error) -> Self {
-        Self::Render(err)
+        match err {
+            render::Error::Mismatch { x, y } => Self::RendererMismatch { x, y },
+            render::Error::TimingMismatch { ly, fifo, scanline } => {
+                Self::RendererTimingMismatch { ly, fifo, scanline }
+            }
+            err => Self::Render(err),
+        }
     }
 }
 
 #[derive(Copy, Clone, Serialize, Deserialize, Debug)]
 enum State {
     Running,
-    CondDelay(u8),
+    /// Calling an interrupt handler
+    Interrupt,
     Halted,
     Stopped,
+    /// Frozen by an illegal opcode, until the system is reset
+    LockedUp {
+        opcode: u8,
+    },
+}
+
+/// Where the CPU locked up, on an illegal opcode
+#[derive(Copy, Clone, Debug)]
+pub struct LockUp {
+    pub pc: u16,
+    pub bank: Option<u16>,
+    pub opcode: u8,
+}
+
+impl Display for LockUp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "CPU locked up at {:04X}", self.pc)?;
+        if let Some(bank) = self.bank {
+            write!(f, " in bank {bank:02}")?;
+        }
+        write!(f, " on illegal opcode {:02X}", self.opcode)
+    }
 }
 
 #[derive(Debug)]
@@ -72,6 +203,12 @@ pub struct Address {
     pub latest_symbol: Option<String>,
 }
 
+#[derive(Debug)]
+enum NewFrame {
+    Ppu,
+    Stopped,
+}
+
 #[derive(Debug)]
 enum HandleOp {
     Handled,
@@ -83,25 +220,32 @@ pub(crate) struct Symbol {
     pub r#break: bool,
 }
 
-const POSTBOOT_STATE: &[u8] = include_bytes!("../postboot.yokoistate");
+/// The DMG clock runs at 4194304 Hz, and a frame takes 70224 dots (~59.73 Hz)
+pub const CLOCK_HZ: u64 = 4194304;
+pub const FRAME_DOTS: u64 = 70224;
 
-thread_local! {
-    static STOPPED_FRAME: Frame = Frame::default();
-}
+static STOPPED_FRAME: LazyLock<Frame> = LazyLock::new(Frame::default);
+
+const MAX_OP_LEN: u16 = 3;
 
 impl System {
-    pub fn init(boot_rom: Vec<u8>, cart: Cart, mode: Mode) -> Result<Self, Error> {
-        Self::init_options(boot_rom, cart, mode, Default::default())
+    pub fn init(boot_rom: Vec<u8>, cart: Cart, model: Model) -> Result<Self, Error> {
+        Self::init_options(boot_rom, cart, model, Default::default())
     }
 
+    /// Without a boot ROM, the system starts in the state the model's boot ROM leaves behind
     pub fn init_options(
         boot_rom: Vec<u8>,
         cart: Cart,
-        mode: Mode,
+        model: Model,
         mut options: Options,
     ) -> Result<Self, Error> {
         let cart_hash = cart.hash();
-        let theme = options.theme;
+        let mode = model.mode(&cart);
+        if mode == Mode::Cgb {
+            return Err(Error::CgbMode);
+        }
+        let ppu = Ppu::init(mode, options.theme.palettes(&cart), options.renderer);
         let breakpoints = std::mem::take(&mut options.breakpoints);
         let symbol_map = options
             .symbols
@@ -109,44 +253,55 @@ impl System {
             .map(|symbols| util::read_symbols(symbols, breakpoints))
             .transpose()
             .map_err(Error::Symbol)?;
+        if let Some(trace) = &options.trace {
+            for name in trace.symbols() {
+                let map = symbol_map
+                    .as_ref()
+                    .ok_or(Error::Symbol(SymbolError::NoneLoaded))?;
+                if !map.values().any(|symbol| symbol.name == name) {
+                    return Err(Error::Symbol(SymbolError::BreakpointNotFound(name.into())));
+                }
+            }
+        }
+        options.skip_boot |= boot_rom.is_empty();
 
-        if options.skip_boot {
-            log::info!(options:%; "system initialized");
-            let mut system = Self {
-                options,
-                cart_hash,
-                symbol_map,
-                ..rmp_serde::from_slice(POSTBOOT_STATE).map_err(Error::Load)?
-            };
-            system.memory.set_cart(cart);
-            system.memory.reset_mbc();
-            system.ppu.set_theme(theme);
-            Ok(system)
+        let reg_set = if options.skip_boot {
+            RegisterSet::post_boot(model, &cart, mode)
         } else {
-            let memory = Memory::init(boot_rom, cart, mode, options.strict_mem_access);
-            let (current_op, next_pc) = memory.read_op(0)?;
-            let op_duration = current_op.properties().duration;
-            log::info!(options:%; "system initialized");
-
-            Ok(Self {
-                options,
-                reg_set: RegisterSet {
-                    next_pc,
-                    ..Default::default()
-                },
-                memory,
-                current_op,
-                op_duration,
-                ppu: Ppu::init(mode, theme),
-                apu: Apu::init(),
-                state: State::Running,
-                ime: false,
-                cart_hash,
-                stack_frames: vec![],
-                symbol_map,
-                breaking: None,
-            })
+            RegisterSet::default()
+        };
+        let mut memory =
+            Memory::init(boot_rom, cart, mode, options.strict_mem_access).map_err(Error::Cart)?;
+        if options.skip_boot {
+            memory.post_boot(model);
         }
+        let fetch = Fetch::new(reg_set.pc, false);
+        log::info!(model:%, options:%; "system initialized");
+
+        let mut system = Self {
+            options,
+            reg_set,
+            memory,
+            current_op: Op::Nop,
+            fetch,
+            dots: 0,
+            latch: None,
+            ppu,
+            apu: Apu::init(),
+            scheduler: Default::default(),
+            elapsed_dots: 0,
+            state: State::Running,
+            ime: false,
+            enabling_ime: false,
+            halt_bug: false,
+            cart_hash,
+            model,
+            stack_frames: vec![],
+            symbol_map,
+            breaking: None,
+        };
+        system.fetch_op()?;
+        Ok(system)
     }
 
     pub fn load(reader: impl Read, cart: Cart) -> Result<Self, Error> {
@@ -158,34 +313,75 @@ impl System {
         if system.cart_hash != cart.hash() {
             return Err(Error::WrongCart);
         }
+        system.ppu.set_palettes(options.theme.palettes(&cart));
+        system.ppu.set_renderer(options.renderer);
         system.memory.set_cart(cart);
-        system.ppu.set_theme(options.theme);
         log::info!(options:%; "system loaded from save state");
         system.options = options;
         Ok(system)
     }
 
-    pub fn next_frame(&mut self, input: Input) -> Result<Frame, Error> {
+    /// The returned frame is reused, and is overwritten by the next call
+    pub fn next_frame(&mut self, input: Input) -> Result<&Frame, Error> {
         self.memory.set_joypad(input.joypad);
-        if let Some(mut writer) = input.save_state
+        if let Some(writer) = input.save_state
             && self.memory.read(mem::BOOT_ROM_CTRL_REG)? != 0
         {
-            rmp_serde::encode::write(&mut writer, self).map_err(Error::Save)?;
+            self.save_state(writer)?;
             log::info!("saved state");
         }
         loop {
             if let Some(frame) = self.tick()? {
                 log::debug!("new frame");
-                break Ok(frame);
+                self.catch_up();
+                if let Some(trace) = &mut self.options.trace {
+                    trace.flush().map_err(Error::Trace)?;
+                }
+                let frame = match frame {
+                    NewFrame::Ppu => self.ppu.frame(),
+                    NewFrame::Stopped => &STOPPED_FRAME,
+                };
+                break Ok(match self.memory.sgb_mut() {
+                    Some(sgb) => sgb.render(frame),
+                    None => frame,
+                });
             }
         }
     }
 
+    pub fn save_state(&self, mut writer: impl Write) -> Result<(), Error> {
+        rmp_serde::encode::write(&mut writer, self).map_err(Error::Save)
+    }
+
+    pub fn state_hash(&self) -> Result<String, Error> {
+        let state = rmp_serde::to_vec(self).map_err(Error::Save)?;
+        Ok(sha256::digest(state))
+    }
+
+    pub fn cart_hash(&self) -> &str {
+        &self.cart_hash
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Dots emulated since the system was created or loaded, which frames with the LCD off or
+    /// the CPU stopped don't last a full frame's worth of
+    pub fn elapsed_dots(&self) -> u64 {
+        self.elapsed_dots
+    }
+
     pub fn step_in(&mut self) -> Result<(), Error> {
         let prev_pc = self.reg_set.pc;
-        while self.reg_set.pc == prev_pc {
+        while self.reg_set.pc == prev_pc && self.lock_up().is_none() {
             self.tick()?;
         }
+        self.catch_up();
         Ok(())
     }
 
@@ -193,15 +389,28 @@ impl System {
         let depth = self.stack_frames.len();
         loop {
             let prev_pc = self.reg_set.pc;
-            while self.reg_set.pc == prev_pc {
+            while self.reg_set.pc == prev_pc && self.lock_up().is_none() {
                 self.tick()?;
             }
-            if self.stack_frames.len() <= depth {
+            if self.stack_frames.len() <= depth || self.lock_up().is_some() {
+                self.catch_up();
                 break Ok(());
             }
         }
     }
 
+    /// Where the CPU locked up, if it ran an illegal opcode with lock-ups enabled
+    pub fn lock_up(&self) -> Option<LockUp> {
+        match self.state {
+            State::LockedUp { opcode } => Some(LockUp {
+                pc: self.reg_set.pc,
+                bank: self.memory.bank(self.reg_set.pc),
+                opcode,
+            }),
+            _ => None,
+        }
+    }
+
     pub fn address(&self) -> Address {
         Address {
             bank: self.memory.bank(self.reg_set.next_pc),
@@ -247,6 +456,11 @@ impl System {
         self.ppu.log_state();
     }
 
+    /// How often fetched ops were already decoded
+    pub fn op_cache_stats(&self) -> OpCacheStats {
+        self.memory.op_cache_stats()
+    }
+
     pub fn vram_tiles(&self) -> [Tile; 384] {
         self.memory.tiles()
     }
@@ -264,7 +478,7 @@ impl System {
         [top_left, bottom_right]
     }
 
-    fn tick(&mut self) -> Result<Option<Frame>, Error> {
+    fn tick(&mut self) -> Result<Option<NewFrame>, Error> {
         match &mut self.options.short_circuit {
             Some(0) => {
                 self.options.short_circuit = None;
@@ -273,82 +487,80 @@ impl System {
             Some(sc) => *sc -= 1,
             _ => {}
         }
-        self.memory.tick()?;
-        let frame = self.ppu.tick(&mut self.memory)?;
-        match (self.state, self.op_duration) {
-            (State::Running, Duration::Const(1)) => {
-                self.handle_op()?;
-                self.reg_set.pc = self.reg_set.next_pc;
-                (self.current_op, self.reg_set.next_pc) = self.memory.read_op(self.reg_set.pc)?;
-                self.op_duration = self.current_op.properties().duration;
-            }
-            (State::Running, Duration::Const(ticks)) => {
-                self.op_duration = Duration::Const(ticks - 1);
-            }
-            (State::Running, Duration::Cond(ticks, 1)) => match self.handle_op()? {
-                HandleOp::Handled => {
-                    self.state = State::CondDelay(ticks - 1);
-                }
-                HandleOp::FalseCond => {
-                    self.reg_set.pc = self.reg_set.next_pc;
-                    (self.current_op, self.reg_set.next_pc) =
-                        self.memory.read_op(self.reg_set.pc)?;
-                    self.op_duration = self.current_op.properties().duration;
+        let mut dots = 1;
+        let frame = match self.options.scheduling {
+            Scheduling::Events => {
+                // skip the dots before the CPU's next bus access or the next event, whichever
+                // comes first
+                let until_due = (self.scheduler.next_due() - self.scheduler.now()).max(1);
+                dots = match self.state {
+                    State::Running | State::Interrupt => until_due.min((4 - self.dots % 4).into()),
+                    // the CPU waits for an interrupt, which only an event can request
+                    State::Halted if self.pending_interrupt()?.is_none() => until_due,
+                    State::LockedUp { .. } => until_due,
+                    _ => 1,
+                };
+                if let Some(sc) = &mut self.options.short_circuit {
+                    dots = dots.min(*sc + 1);
+                    *sc -= dots - 1;
                 }
-            },
-            (State::Running, Duration::Cond(true_ticks, false_ticks)) => {
-                self.op_duration = Duration::Cond(true_ticks - 1, false_ticks - 1);
+                self.tick_events(dots)?
             }
-            (State::CondDelay(1), _) => {
-                self.state = State::Running;
-                self.reg_set.pc = self.reg_set.next_pc;
-                (self.current_op, self.reg_set.next_pc) = self.memory.read_op(self.reg_set.pc)?;
-                self.op_duration = self.current_op.properties().duration;
+            Scheduling::Dots => {
+                self.memory.tick()?;
+                self.ppu.tick(&mut self.memory)?
             }
-            (State::CondDelay(ticks), _) => {
-                self.state = State::CondDelay(ticks - 1);
+        }
+        .then_some(NewFrame::Ppu);
+        self.elapsed_dots += dots;
+        if let Some(trace) = &mut self.options.trace {
+            trace.tick(dots);
+        }
+        match self.state {
+            State::Running | State::Interrupt => {
+                // the CPU accesses the bus once at the end of each M-cycle
+                self.dots += dots as u8;
+                while self.dots.is_multiple_of(4) {
+                    self.catch_up();
+                    let op_len = self.reg_set.next_pc.wrapping_sub(self.reg_set.pc);
+                    let done = match self.state {
+                        State::Interrupt => self.dispatch(),
+                        _ => self.step(),
+                    }
+                    .map_err(|err| match err {
+                        Error::Memory(source) => {
+                            cpu_error(&self.memory, self.reg_set.pc, op_len, source)
+                        }
+                        err => err,
+                    })?;
+                    if done {
+                        self.reg_set.pc = self.reg_set.next_pc;
+                        self.next_op()?;
+                    }
+                    if self.memory.take_io_written() {
+                        self.scheduler.wake();
+                    }
+                    if frame.is_some() || !self.batch_m_cycle(done) {
+                        break;
+                    }
+                }
             }
-            (State::Halted, _) => {
-                let ie = self.memory.read(mem::IE_REG)?;
-                let interrupts = self.memory.read(mem::IF_REG)?;
-                if (interrupts & ie) != 0 {
+            State::Halted => {
+                if self.pending_interrupt()?.is_some() {
                     self.state = State::Running;
-                    self.op_duration = Duration::Const(1);
+                    self.next_op()?;
                 }
             }
-            (State::Stopped, _)
-                if self.memory.read(mem::JOYPAD_REG)? & 0b00001111 != 0b00001111 =>
-            {
+            State::Stopped if self.memory.read(mem::JOYPAD_REG)? & 0b00001111 != 0b00001111 => {
                 self.state = State::Running;
-                self.op_duration = Duration::Const(1);
-            }
-            (State::Stopped, _) => return Ok(Some(STOPPED_FRAME.with(Clone::clone))),
-        }
-
-        if self.ime {
-            let ie = self.memory.read(mem::IE_REG)?;
-            let interrupts = self.memory.read(mem::IF_REG)?;
-            let handlers = [
-                (0b00000001, 0x40), //VBlank
-                (0b00000010, 0x48), //LCD STAT
-                (0b00000100, 0x50), //Timer
-                (0b00001000, 0x58), //Serial
-                (0b00010000, 0x60), //Joypad
-            ];
-            for (mask, address) in handlers {
-                if (ie & mask) != 0 && (interrupts & mask) != 0 {
-                    self.memory.write(mem::IF_REG, interrupts & !mask)?;
-                    self.ime = false;
-                    self.reg_set.next_pc = self.reg_set.pc;
-                    self.call(A16(address))?;
-                    self.reg_set.pc = self.reg_set.next_pc;
-                    (self.current_op, self.reg_set.next_pc) =
-                        self.memory.read_op(self.reg_set.pc)?;
-                    self.state = State::Running;
-                    self.op_duration = Duration::Const(20);
-                    break;
-                }
+                self.next_op()?;
+            }
+            State::Stopped => return Ok(Some(NewFrame::Stopped)),
+            // the screen stays blank if the LCD was off
+            State::LockedUp { .. } if self.memory.read(mem::LCD_CTRL_REG)? & 0b10000000 == 0 => {
+                return Ok(Some(NewFrame::Stopped));
             }
+            State::LockedUp { .. } => {}
         }
 
         if let Some(breakpoint) = self.breaking.take() {
@@ -358,6 +570,280 @@ impl System {
         }
     }
 
+    /// With event scheduling, run the op's next M-cycle without returning to the loop when no
+    /// event is due before its bus access. Stops at the end of each op, so debugger steps and
+    /// breakpoints see every instruction, and after a finished frame so it's returned on time
+    fn batch_m_cycle(&mut self, done: bool) -> bool {
+        let running = matches!(self.state, State::Running | State::Interrupt);
+        if self.options.scheduling != Scheduling::Events
+            || done
+            || !running
+            || self.breaking.is_some()
+            || self.scheduler.next_due() <= self.scheduler.now() + 4
+            || self.options.short_circuit.is_some_and(|sc| sc < 4)
+        {
+            return false;
+        }
+        if let Some(sc) = &mut self.options.short_circuit {
+            *sc -= 4;
+        }
+        self.scheduler.advance(4);
+        self.elapsed_dots += 4;
+        if let Some(trace) = &mut self.options.trace {
+            trace.tick(4);
+        }
+        self.dots += 4;
+        true
+    }
+
+    /// Move on `dots` and tick the subsystems with an event due, after catching them up on the
+    /// dots they skipped. Returns whether a frame was finished
+    fn tick_events(&mut self, dots: u64) -> Result<bool, Error> {
+        self.scheduler.advance(dots);
+        let mut frame = false;
+        for event in Event::ALL {
+            if !self.scheduler.is_due(event) {
+                continue;
+            }
+            let skipped = self.scheduler.tick(event);
+            let idle = match event {
+                Event::Timer => {
+                    self.memory.skip_timer(skipped);
+                    self.memory.tick_timer();
+                    self.memory.timer_idle_dots()
+                }
+                Event::Serial => {
+                    self.memory.skip_serial(skipped);
+                    self.memory.tick_serial();
+                    self.memory.serial_idle_dots()
+                }
+                Event::OamDma => {
+                    self.memory.tick_oam_dma()?;
+                    self.memory.oam_dma_idle_dots()
+                }
+                Event::Ppu => {
+                    self.ppu.skip(skipped);
+                    frame = self.ppu.tick(&mut self.memory)?;
+                    self.ppu.idle_dots()
+                }
+            };
+            self.scheduler.schedule(event, idle);
+        }
+        Ok(frame)
+    }
+
+    /// Bring the subsystems up to the current dot, before their state can be seen
+    fn catch_up(&mut self) {
+        for event in Event::ALL {
+            let skipped = self.scheduler.catch_up(event);
+            match event {
+                Event::Timer => self.memory.skip_timer(skipped),
+                Event::Serial => self.memory.skip_serial(skipped),
+                Event::OamDma => {}
+                Event::Ppu => self.ppu.skip(skipped),
+            }
+        }
+    }
+
+    /// Start the instruction at pc, or dispatch an interrupt before it
+    fn next_op(&mut self) -> Result<(), Error> {
+        self.dots = 0;
+        self.latch = None;
+        let dispatch = self.ime
+            && !matches!(self.state, State::Stopped)
+            && self.pending_interrupt()?.is_some();
+        self.ime |= std::mem::take(&mut self.enabling_ime);
+        if dispatch {
+            self.ime = false;
+            self.state = State::Interrupt;
+        } else {
+            self.fetch = Fetch::new(self.reg_set.pc, std::mem::take(&mut self.halt_bug));
+            self.fetch_op()?;
+        }
+        Ok(())
+    }
+
+    /// Fetch the current op's next byte. The opcode is fetched while the previous op finishes,
+    /// and each operand takes an M-cycle of its own
+    fn fetch_op(&mut self) -> Result<(), Error> {
+        match self.memory.fetch_op(&mut self.fetch) {
+            Ok(op) => {
+                self.reg_set.next_pc = self.fetch.end();
+                if let Some(op) = op {
+                    self.current_op = op;
+                }
+            }
+            Err(mem::Error::Op {
+                source: opcode::Error::Invalid(opcode),
+                ..
+            }) if self.options.lock_up => {
+                self.state = State::LockedUp { opcode };
+                log::warn!("{}", self.lock_up().expect("locked up"));
+            }
+            Err(err) => return Err(cpu_error(&self.memory, self.reg_set.pc, MAX_OP_LEN, err)),
+        }
+        Ok(())
+    }
+
+    /// The highest priority interrupt that's both enabled and requested
+    fn pending_interrupt(&self) -> Result<Option<(u8, u16)>, Error> {
+        let ie = self.memory.read(mem::IE_REG)?;
+        let interrupts = self.memory.read(mem::IF_REG)?;
+        let handlers = [
+            (0b00000001, 0x40), //VBlank
+            (0b00000010, 0x48), //LCD STAT
+            (0b00000100, 0x50), //Timer
+            (0b00001000, 0x58), //Serial
+            (0b00010000, 0x60), //Joypad
+        ];
+        Ok(handlers
+            .into_iter()
+            .find(|(mask, _)| ie & interrupts & mask != 0))
+    }
+
+    /// Run an M-cycle of an interrupt dispatch, returning whether it's done.
+    /// The handler is picked between pushing pc's bytes, so pushing onto IE can cancel it
+    fn dispatch(&mut self) -> Result<bool, Error> {
+        let [pc_upper, pc_lower] = self.reg_set.pc.to_be_bytes();
+        match self.dots / 4 {
+            3 => self.push(pc_upper)?,
+            4 => {
+                let address = match self.pending_interrupt()? {
+                    Some((mask, address)) => {
+                        let interrupts = self.memory.read(mem::IF_REG)?;
+                        self.memory.write(mem::IF_REG, interrupts & !mask)?;
+                        address
+                    }
+                    None => 0x0000,
+                };
+                self.push(pc_lower)?;
+                self.jump(address);
+            }
+            5 => {
+                self.state = State::Running;
+                return Ok(true);
+            }
+            _ => {}
+        }
+        Ok(false)
+    }
+
+    /// Run an M-cycle of the current instruction, returning whether it's done.
+    /// Most instructions only access memory in their last M-cycle, and are handled all at once
+    fn step(&mut self) -> Result<bool, Error> {
+        let cycle = self.dots / 4;
+        if cycle == 1 {
+            self.begin_op()?;
+        }
+        if self.fetch.pending() {
+            self.fetch_op()?;
+            return Ok(false);
+        }
+        let [pc_upper, pc_lower] = self.reg_set.next_pc.to_be_bytes();
+        match (self.current_op, cycle) {
+            // read-modify-write instructions read a cycle before writing
+            (Op::IncR8(R8::HlDeref) | Op::DecR8(R8::HlDeref), 2) => {
+                self.latch = Some(self.memory.read(self.reg_set.hl())?);
+            }
+            (Op::Prefix(prefixed, R8::HlDeref), 3) if !matches!(prefixed, Prefixed::Bit(_)) => {
+                self.latch = Some(self.memory.read(self.reg_set.hl())?);
+            }
+            (Op::LdA16Sp(A16(a16)), 4) => {
+                self.memory.write(a16, self.reg_set.sp.to_le_bytes()[0])?;
+            }
+            (Op::LdA16Sp(A16(a16)), 5) => {
+                let upper = self.reg_set.sp.to_le_bytes()[1];
+                self.memory.write(a16.wrapping_add(1), upper)?;
+                return Ok(true);
+            }
+            (Op::Push(r16_stk), 3 | 4) => {
+                let [upper, lower] = match r16_stk {
+                    R16Stk::Bc => self.reg_set.bc(),
+                    R16Stk::De => self.reg_set.de(),
+                    R16Stk::Hl => self.reg_set.hl(),
+                    R16Stk::Af => self.reg_set.af(),
+                }
+                .to_be_bytes();
+                self.push(if cycle == 3 { upper } else { lower })?;
+                return Ok(cycle == 4);
+            }
+            (Op::Pop(_), 2) => self.latch = Some(self.pop()?),
+            (Op::Pop(r16_stk), 3) => {
+                let lower = self.latch.take().expect("popped in the previous cycle");
+                let popped = u16::from_le_bytes([lower, self.pop()?]);
+                match r16_stk {
+                    R16Stk::Bc => self.reg_set.set_bc(popped),
+                    R16Stk::De => self.reg_set.set_de(popped),
+                    R16Stk::Hl => self.reg_set.set_hl(popped),
+                    R16Stk::Af => self.reg_set.set_af(popped),
+                }
+                return Ok(true);
+            }
+            (Op::CallCondA16(cond, _), 3) if !self.condition(cond) => return Ok(true),
+            (Op::CallA16(_) | Op::CallCondA16(..), 5) | (Op::Rst(_), 3) => self.push(pc_upper)?,
+            (Op::CallA16(A16(a16)) | Op::CallCondA16(_, A16(a16)), 6) => {
+                self.push(pc_lower)?;
+                self.jump(a16);
+                return Ok(true);
+            }
+            (Op::Rst(Tgt3(tgt3)), 4) => {
+                self.push(pc_lower)?;
+                self.jump(u16::from_be_bytes([0x00, tgt3 * 8]));
+                return Ok(true);
+            }
+            // stop takes one M-cycle by the table, but its second byte is fetched like an operand
+            (Op::Stop(_), _) => {
+                self.handle_op()?;
+                return Ok(true);
+            }
+            (Op::RetCond(cond), 2) if !self.condition(cond) => return Ok(true),
+            (Op::Ret | Op::Reti, 2) | (Op::RetCond(_), 3) => self.latch = Some(self.pop()?),
+            (Op::Ret | Op::Reti, 3) | (Op::RetCond(_), 4) => {
+                let lower = self.latch.take().expect("popped in the previous cycle");
+                let address = u16::from_le_bytes([lower, self.pop()?]);
+                self.ret(address);
+            }
+            (Op::Ret, 4) | (Op::RetCond(_), 5) => return Ok(true),
+            (Op::Reti, 4) => {
+                self.ime = true;
+                return Ok(true);
+            }
+            (
+                Op::Push(_)
+                | Op::Pop(_)
+                | Op::CallA16(_)
+                | Op::CallCondA16(..)
+                | Op::Rst(_)
+                | Op::Ret
+                | Op::Reti
+                | Op::RetCond(_),
+                _,
+            ) => {}
+            _ => {
+                let (length, handled_at) = match self.current_op.properties().duration {
+                    Duration::Const(dots) => (dots / 4, dots / 4),
+                    Duration::Cond(true_dots, false_dots) => (true_dots / 4, false_dots / 4),
+                };
+                if cycle == handled_at
+                    && let HandleOp::FalseCond = self.handle_op()?
+                {
+                    return Ok(true);
+                }
+                return Ok(cycle == length);
+            }
+        }
+        Ok(false)
+    }
+
+    fn condition(&self, cond: Cond) -> bool {
+        match cond {
+            Cond::Z => self.reg_set.zero(),
+            Cond::Nz => !self.reg_set.zero(),
+            Cond::C => self.reg_set.carry(),
+            Cond::Nc => !self.reg_set.carry(),
+        }
+    }
+
     fn read_r16(&self, r16: R16) -> u16 {
         match r16 {
             R16::Bc => self.reg_set.bc(),
@@ -384,7 +870,10 @@ impl System {
             R8::E => Ok(self.reg_set.e),
             R8::H => Ok(self.reg_set.h),
             R8::L => Ok(self.reg_set.l),
-            R8::HlDeref => Ok(self.memory.read(self.reg_set.hl())?),
+            R8::HlDeref => match self.latch {
+                Some(data) => Ok(data),
+                None => Ok(self.memory.read(self.reg_set.hl())?),
+            },
             R8::A => Ok(self.reg_set.a),
         }
     }
@@ -403,27 +892,30 @@ impl System {
         Ok(())
     }
 
-    fn ret(&mut self) -> Result<(), Error> {
-        let sp = self.reg_set.sp;
-        self.reg_set.next_pc =
-            u16::from_le_bytes([self.memory.read(sp)?, self.memory.read(sp + 1)?]);
-        self.reg_set.sp += 2;
+    fn push(&mut self, data: u8) -> Result<(), Error> {
+        self.reg_set.sp = self.reg_set.sp.wrapping_sub(1);
+        Ok(self.memory.write(self.reg_set.sp, data)?)
+    }
+
+    fn pop(&mut self) -> Result<u8, Error> {
+        let data = self.memory.read(self.reg_set.sp)?;
+        self.reg_set.sp = self.reg_set.sp.wrapping_add(1);
+        Ok(data)
+    }
+
+    fn ret(&mut self, address: u16) {
+        self.reg_set.next_pc = address;
 
         if self.options.debug {
             self.stack_frames.pop();
         }
 
         log::trace!(pc:? = Hex(self.reg_set.next_pc); "return");
-        Ok(())
     }
 
-    fn call(&mut self, A16(a16): A16) -> Result<(), Error> {
-        let [pc_upper, pc_lower] = self.reg_set.next_pc.to_be_bytes();
-        self.reg_set.sp -= 1;
-        self.memory.write(self.reg_set.sp, pc_upper)?;
-        self.reg_set.sp -= 1;
-        self.memory.write(self.reg_set.sp, pc_lower)?;
-        self.reg_set.next_pc = a16;
+    /// Jump to a call's address, after the return address is pushed
+    fn jump(&mut self, address: u16) {
+        self.reg_set.next_pc = address;
 
         if self.options.debug {
             self.stack_frames.push(Address {
@@ -434,14 +926,23 @@ impl System {
         }
 
         log::trace!(pc:? = Hex(self.reg_set.next_pc); "call");
-        Ok(())
     }
 
-    fn handle_op(&mut self) -> Result<HandleOp, Error> {
-        if let Some(map) = &self.symbol_map
-            && let Some(bank) = self.memory.bank(self.reg_set.pc)
-            && let Some(Symbol { name, r#break }) = map.get(&(bank, self.reg_set.pc))
-        {
+    fn begin_op(&mut self) -> Result<(), Error> {
+        let symbol = self
+            .symbol_map
+            .as_ref()
+            .zip(self.memory.bank(self.reg_set.pc))
+            .and_then(|(map, bank)| map.get(&(bank, self.reg_set.pc)));
+        if let Some(trace) = &mut self.options.trace {
+            let pc = self.reg_set.pc;
+            let pcmem = [0, 1, 2, 3].map(|i| self.memory.read(pc.wrapping_add(i)).unwrap_or(0xFF));
+            let name = symbol.map(|symbol| symbol.name.as_str());
+            trace
+                .log(&self.reg_set, name, pcmem)
+                .map_err(Error::Trace)?;
+        }
+        if let Some(Symbol { name, r#break }) = symbol {
             log::trace!(symbol = name;"");
             self.breaking = r#break.then(|| name.clone());
             if let Some(Address { latest_symbol, .. }) = self.stack_frames.last_mut() {
@@ -455,8 +956,10 @@ impl System {
         if let Some(Address { addr, .. }) = self.stack_frames.last_mut() {
             *addr = self.reg_set.pc;
         }
-        log::trace!(op:? = Hex(self.current_op), registers:? = self.reg_set;"");
+        Ok(())
+    }
 
+    fn handle_op(&mut self) -> Result<HandleOp, Error> {
         match self.current_op {
             Op::Nop => {}
             Op::LdR16N16(r16, N16(n16)) => self.write_r16(r16, n16),
@@ -490,9 +993,15 @@ impl System {
                     }
                 }
             }
-            Op::LdA16Sp(A16(a16)) => self
-                .memory
-                .write_slice(a16, &self.reg_set.sp.to_le_bytes())?,
+            Op::LdA16Sp(_)
+            | Op::Push(_)
+            | Op::Pop(_)
+            | Op::CallA16(_)
+            | Op::CallCondA16(..)
+            | Op::Rst(_)
+            | Op::Ret
+            | Op::Reti
+            | Op::RetCond(_) => unreachable!("accesses memory over several M-cycles"),
             Op::IncR16(r16) => self.write_r16(r16, self.read_r16(r16).wrapping_add(1)),
             Op::DecR16(r16) => self.write_r16(r16, self.read_r16(r16).wrapping_sub(1)),
             Op::AddHlR16(r16) => {
@@ -606,6 +1115,7 @@ impl System {
             Op::Stop(_) => self.state = State::Stopped,
             Op::LdR8R8(R8::B, R8::B) if self.options.debug => return Err(Error::ShortCircuit), // common debugging breakpoint command
             Op::LdR8R8(r8_dest, r8_src) => self.write_r8(r8_dest, self.read_r8(r8_src)?)?,
+            Op::Halt if !self.ime && self.pending_interrupt()?.is_some() => self.halt_bug = true,
             Op::Halt => self.state = State::Halted,
             Op::AddR8(r8) => {
                 let operand = self.read_r8(r8)?;
@@ -740,16 +1250,6 @@ impl System {
                     .set_half_carry((self.reg_set.a & 0x0F) < (n8 & 0x0F));
                 self.reg_set.set_carry(carry);
             }
-            Op::RetCond(Cond::Z) if self.reg_set.zero() => self.ret()?,
-            Op::RetCond(Cond::Nz) if !self.reg_set.zero() => self.ret()?,
-            Op::RetCond(Cond::C) if self.reg_set.carry() => self.ret()?,
-            Op::RetCond(Cond::Nc) if !self.reg_set.carry() => self.ret()?,
-            Op::RetCond(_) => return Ok(HandleOp::FalseCond),
-            Op::Ret => self.ret()?,
-            Op::Reti => {
-                self.ret()?;
-                self.ime = true;
-            }
             Op::JpCondA16(Cond::Z, A16(a16)) if self.reg_set.zero() => self.reg_set.next_pc = a16,
             Op::JpCondA16(Cond::Nz, A16(a16)) if !self.reg_set.zero() => self.reg_set.next_pc = a16,
             Op::JpCondA16(Cond::C, A16(a16)) if self.reg_set.carry() => self.reg_set.next_pc = a16,
@@ -759,39 +1259,6 @@ impl System {
             Op::JpCondA16(..) => return Ok(HandleOp::FalseCond),
             Op::JpA16(A16(a16)) => self.reg_set.next_pc = a16,
             Op::JpHl => self.reg_set.next_pc = self.reg_set.hl(),
-            Op::CallCondA16(Cond::Z, a16) if self.reg_set.zero() => self.call(a16)?,
-            Op::CallCondA16(Cond::Nz, a16) if !self.reg_set.zero() => self.call(a16)?,
-            Op::CallCondA16(Cond::C, a16) if self.reg_set.carry() => self.call(a16)?,
-            Op::CallCondA16(Cond::Nc, a16) if !self.reg_set.carry() => self.call(a16)?,
-            Op::CallCondA16(..) => return Ok(HandleOp::FalseCond),
-            Op::CallA16(a16) => self.call(a16)?,
-            Op::Rst(Tgt3(tgt3)) => self.call(A16(u16::from_be_bytes([0x00, tgt3 * 8])))?,
-            Op::Pop(r16_stk) => {
-                let popped = u16::from_le_bytes([
-                    self.memory.read(self.reg_set.sp)?,
-                    self.memory.read(self.reg_set.sp + 1)?,
-                ]);
-                self.reg_set.sp += 2;
-                match r16_stk {
-                    R16Stk::Bc => self.reg_set.set_bc(popped),
-                    R16Stk::De => self.reg_set.set_de(popped),
-                    R16Stk::Hl => self.reg_set.set_hl(popped),
-                    R16Stk::Af => self.reg_set.set_af(popped),
-                }
-            }
-            Op::Push(r16_stk) => {
-                let push = match r16_stk {
-                    R16Stk::Bc => self.reg_set.bc(),
-                    R16Stk::De => self.reg_set.de(),
-                    R16Stk::Hl => self.reg_set.hl(),
-                    R16Stk::Af => self.reg_set.af(),
-                };
-                let [upper, lower] = push.to_be_bytes();
-                self.reg_set.sp -= 1;
-                self.memory.write(self.reg_set.sp, upper)?;
-                self.reg_set.sp -= 1;
-                self.memory.write(self.reg_set.sp, lower)?;
-            }
             Op::Prefix(prefixed, r8) => 'prefixed: {
                 let value = self.read_r8(r8)?;
                 self.reg_set.set_f(0x00);
@@ -864,10 +1331,274 @@ impl System {
                 self.reg_set.set_hl(result);
             }
             Op::LdSpHl => self.reg_set.sp = self.reg_set.hl(),
-            Op::Di => self.ime = false,
-            Op::Ei => self.ime = true,
+            Op::Di => {
+                self.ime = false;
+                self.enabling_ime = false;
+            }
+            Op::Ei => self.enabling_ime = true,
         }
 
         Ok(HandleOp::Handled)
     }
 }
+
+/// Blame a memory error on the op at pc, reading up to `len` of its bytes
+fn cpu_error(memory: &Memory, pc: u16, len: u16, source: mem::Error) -> Error {
+    Error::Cpu {
+        pc,
+        bank: memory.bank(pc),
+        op: (0..len)
+            .map_while(|i| memory.read(pc.wrapping_add(i)).ok())
+            .collect(),
+        source,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operands_fetched_in_own_cycles() {
+        let code = [
+            0x3E, 0xC1, // ld a, $C1
+            0xE0, 0x46, // ldh ($46), a
+            0x21, 0x34, 0x12, // ld hl, $1234
+        ];
+        for scheduling in Scheduling::ALL {
+            let options = Options {
+                scheduling,
+                ..Default::default()
+            };
+            let mut system =
+                System::init_options(vec![], Cart::test(&code, |_| {}), Model::Dmg, options)
+                    .unwrap();
+            for addr in 0xC100..0xC1A0 {
+                system.memory.write(addr, 0x06).unwrap();
+            }
+            // from the entry point: nop, jp $0150, then the code
+            for _ in 0..5 {
+                system.step_in().unwrap();
+            }
+            // OAM DMA blocks the bus from its second M-cycle, so only the last operand is the
+            // byte being copied
+            assert_eq!(system.reg_set.pc, 0x0157);
+            assert_eq!(system.reg_set.hl(), 0x0634);
+        }
+    }
+
+    #[test]
+    fn schedulings_agree() {
+        // halt between timer, serial and LY=LYC interrupts, whose handlers keep DIV as it was
+        // after the last one
+        #[rustfmt::skip]
+        let code = [
+            0x3E, 0x04,             // ld a, $04
+            0xE0, 0x07,             // ldh [TAC], a, ticking TIMA every 1024 dots
+            0x3E, 0xFC,             // ld a, $FC
+            0xE0, 0x06,             // ldh [TMA], a
+            0x3E, 0x3C,             // ld a, 60
+            0xE0, 0x45,             // ldh [LYC], a
+            0x3E, 0x40,             // ld a, $40
+            0xE0, 0x41,             // ldh [STAT], a
+            0x3E, 0x81,             // ld a, $81
+            0xE0, 0x02,             // ldh [SC], a, starting a transfer on the internal clock
+            0x3E, 0x0E,             // ld a, $0E
+            0xE0, 0xFF,             // ldh [IE], a
+            0xAF,                   // xor a
+            0xE0, 0x0F,             // ldh [IF], a
+            0xFB,                   // ei
+            0x76,                   // halt
+            0xF0, 0x04,             // ldh a, [DIV]
+            0x18, 0xFB,             // jr -5
+        ];
+        let [mut dots, mut events] = [Scheduling::Dots, Scheduling::Events].map(|scheduling| {
+            let cart = Cart::test(&code, |data| {
+                // ld b, a; reti, ld c, a; reti and ld d, a; reti
+                data[0x0048..0x004A].copy_from_slice(&[0x47, 0xD9]);
+                data[0x0050..0x0052].copy_from_slice(&[0x4F, 0xD9]);
+                data[0x0058..0x005A].copy_from_slice(&[0x57, 0xD9]);
+            });
+            let options = Options {
+                scheduling,
+                ..Default::default()
+            };
+            System::init_options(vec![], cart, Model::Dmg, options).unwrap()
+        });
+        for frame in 1..=8 {
+            dots.next_frame(Input::default()).unwrap();
+            events.next_frame(Input::default()).unwrap();
+            let registers = |system: &System| {
+                let RegisterSet { a, b, c, d, pc, .. } = system.reg_set;
+                (a, b, c, d, pc)
+            };
+            assert_eq!(
+                registers(&events),
+                registers(&dots),
+                "registers differ after frame {frame}"
+            );
+            assert_eq!(
+                events.state_hash().unwrap(),
+                dots.state_hash().unwrap(),
+                "states differ after frame {frame}"
+            );
+        }
+        assert_ne!((dots.reg_set.b, dots.reg_set.c), (0, 0));
+        // the transfer finished, shifting in 1s
+        assert_eq!(dots.memory.read(mem::SERIAL_0_REG).unwrap(), 0xFF);
+        assert_eq!(dots.memory.read(mem::SERIAL_1_REG).unwrap(), 0x01);
+    }
+
+    #[test]
+    fn counts_elapsed_dots() {
+        let mut system = run(&[0x18, 0xFE]); // jr -2
+        let first = system.elapsed_dots();
+        system.next_frame(Input::default()).unwrap();
+        assert_eq!(system.elapsed_dots() - first, FRAME_DOTS);
+
+        // a stopped CPU ends the frame early
+        let mut system = run(&[
+            0x10, 0x00, // stop
+        ]);
+        let stopped = system.elapsed_dots();
+        system.next_frame(Input::default()).unwrap();
+        assert!(system.elapsed_dots() - stopped < FRAME_DOTS);
+    }
+
+    /// Run `code` from $0150 for a frame, after which it should be looping
+    fn run(code: &[u8]) -> System {
+        let mut system = System::init(vec![], Cart::test(code, |_| {}), Model::Dmg).unwrap();
+        system.next_frame(Input::default()).unwrap();
+        system
+    }
+
+    #[test]
+    fn halt_bug() {
+        #[rustfmt::skip]
+        let system = run(&[
+            0xF3,                   // di
+            0x3E, 0x04,             // ld a, $04
+            0xE0, 0xFF,             // ldh [IE], a
+            0xE0, 0x0F,             // ldh [IF], a
+            0xAF,                   // xor a
+            0x76,                   // halt, which doesn't increment pc with an interrupt pending
+            0x3C,                   // inc a, which runs twice
+            0x18, 0xFE,             // jr -2
+        ]);
+        assert_eq!(system.reg_set.a, 2);
+    }
+
+    #[test]
+    fn ei_delay() {
+        #[rustfmt::skip]
+        let code = [
+            0x3E, 0x04,             // ld a, $04
+            0xE0, 0xFF,             // ldh [IE], a
+            0xE0, 0x0F,             // ldh [IF], a
+            0xAF,                   // xor a
+            0xFB,                   // ei
+            0x3C,                   // inc a, before the timer interrupt is dispatched
+            0x3C,                   // inc a
+            0x18, 0xFE,             // jr -2
+        ];
+        let cart = Cart::test(&code, |data| {
+            // timer handler: ld b, a; reti
+            data[0x0050..0x0052].copy_from_slice(&[0x47, 0xD9]);
+        });
+        let mut system = System::init(vec![], cart, Model::Dmg).unwrap();
+        system.next_frame(Input::default()).unwrap();
+        assert_eq!((system.reg_set.b, system.reg_set.a), (1, 2));
+        assert_eq!(system.memory.read(mem::IF_REG).unwrap() & 0b100, 0);
+    }
+
+    #[test]
+    fn invalid_opcode_error() {
+        #[rustfmt::skip]
+        let code = [
+            0x3E, 0xD3,             // ld a, $D3
+            0xEA, 0x00, 0xC0,       // ld [$C000], a
+            0xC3, 0x00, 0xC0,       // jp $C000
+        ];
+        let mut system = System::init(vec![], Cart::test(&code, |_| {}), Model::Dmg).unwrap();
+        let err = system.next_frame(Input::default()).err().unwrap();
+        let Error::Cpu { pc, bank, op, .. } = &err else {
+            panic!("expected a CPU fault, got {err:?}");
+        };
+        assert_eq!(
+            (*pc, *bank, op.as_slice()),
+            (0xC000, None, &[0xD3, 0, 0][..])
+        );
+        let messages: Vec<_> =
+            std::iter::successors(Some(&err as &dyn std::error::Error), |err| err.source())
+                .map(ToString::to_string)
+                .collect();
+        assert_eq!(
+            messages,
+            [
+                "CPU fault at C000 running op D3 00 00",
+                "couldn't decode the op at C000",
+                "invalid opcode D3",
+            ]
+        );
+    }
+
+    #[test]
+    fn illegal_opcode_lock_up() {
+        #[rustfmt::skip]
+        let code = [
+            0x3E, 0x01,             // ld a, $01
+            0xE0, 0xFF,             // ldh [IE], a
+            0xFB,                   // ei
+            0xDD,                   // illegal, and the vblank interrupt can't wake the CPU from it
+        ];
+        let options = Options {
+            lock_up: true,
+            ..Default::default()
+        };
+        let mut system =
+            System::init_options(vec![], Cart::test(&code, |_| {}), Model::Dmg, options).unwrap();
+        system.next_frame(Input::default()).unwrap();
+        let LockUp { pc, bank, opcode } = system.lock_up().unwrap();
+        assert_eq!((pc, bank, opcode), (0x0155, Some(0), 0xDD));
+        system.memory.write(mem::IF_REG, 0).unwrap();
+        system.next_frame(Input::default()).unwrap();
+        // the PPU keeps requesting vblank interrupts, which the CPU doesn't service
+        assert_eq!(system.memory.read(mem::IF_REG).unwrap() & 1, 1);
+        assert_eq!(system.reg_set.pc, 0x0155);
+        assert!(system.lock_up().is_some());
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        #[rustfmt::skip]
+        let system = run(&[
+            0x3E, 0x3E,             // ld a, $3E
+            0xEA, 0x00, 0xC0,       // ld [$C000], a
+            0x3E, 0x05,             // ld a, 5
+            0xEA, 0x01, 0xC0,       // ld [$C001], a
+            0x3E, 0xC9,             // ld a, $C9
+            0xEA, 0x02, 0xC0,       // ld [$C002], a
+            0xCD, 0x00, 0xC0,       // call $C000, which runs ld a, 5; ret
+            0x47,                   // ld b, a
+            0x3E, 0x07,             // ld a, 7
+            0xEA, 0x01, 0xC0,       // ld [$C001], a
+            0xCD, 0x00, 0xC0,       // call $C000, which now runs ld a, 7; ret
+            0x18, 0xFE,             // jr -2
+        ]);
+        assert_eq!((system.reg_set.b, system.reg_set.a), (5, 7));
+        assert_eq!(system.op_cache_stats().invalidations, 1);
+    }
+
+    #[test]
+    fn refuses_cgb_mode() {
+        let cgb_cart = || Cart::test(&[], |data| data[0x0143] = 0xC0);
+        for model in [Model::Cgb, Model::Agb] {
+            assert!(matches!(
+                System::init(vec![], cgb_cart(), model),
+                Err(Error::CgbMode)
+            ));
+            assert!(System::init(vec![], Cart::test(&[], |_| {}), model).is_ok());
+        }
+        assert!(System::init(vec![], cgb_cart(), Model::Dmg).is_ok());
+    }
+}
@@ -13,7 +13,10 @@ pub struct Timer {
 enum State {
     #[default]
     Ticking,
+    /// TIMA overflowed and reads 0 until it's reloaded, after this many ticks
     Overflow(u8),
+    /// TIMA was just reloaded, and follows TMA writes for this many more ticks
+    Reloaded(u8),
 }
 
 #[derive(Default, Debug)]
@@ -23,51 +26,232 @@ pub struct Result {
 }
 
 impl Timer {
-    pub fn tick(&mut self) -> Result {
+    pub fn tick(&mut self, double_speed: bool) -> Result {
         let mut result = Result::default();
-        let sys_prev = u16::from_be_bytes(self.sys);
-        let sys = sys_prev.wrapping_add(1);
-        self.sys = sys.to_be_bytes();
-        if !(sys_prev >> 12).is_multiple_of(2) && (sys >> 12).is_multiple_of(2) {
-            result.div_apu = true;
-        }
         match &mut self.state {
-            State::Ticking => {
-                // check TAC.enable
-                if (self.tac >> 2) % 2 == 1 {
-                    let shift = match self.tac % 4 {
-                        0 => 9,
-                        1 => 3,
-                        2 => 5,
-                        3 => 7,
-                        _ => unreachable!(),
-                    };
-                    let bit_prev = (sys_prev >> shift) % 2;
-                    let bit_curr = (u16::from_be_bytes(self.sys) >> shift) % 2;
-                    if bit_prev == 1 && bit_curr == 0 {
-                        // falling edge on the bit selected by TAC.freq
-                        self.tima = self.tima.wrapping_add(1);
-                        if self.tima == 0 {
-                            self.state = State::Overflow(4);
-                        }
-                    }
-                }
-            }
+            State::Ticking => {}
             State::Overflow(0) => {
                 self.tima = self.tma;
-                self.state = State::Ticking;
+                self.state = State::Reloaded(3);
                 result.interrupt = true;
             }
-            State::Overflow(tick) => *tick -= 1,
+            State::Reloaded(0) => self.state = State::Ticking,
+            State::Overflow(ticks) | State::Reloaded(ticks) => *ticks -= 1,
         }
+        let sys = u16::from_be_bytes(self.sys).wrapping_add(1);
+        result.div_apu = self.update(sys, self.tac, double_speed);
         result
     }
 
+    /// Ticks before one that does more than count
+    pub fn idle_ticks(&self, double_speed: bool) -> u64 {
+        if !matches!(self.state, State::Ticking) {
+            return 0;
+        }
+        // a bit falls when the counter reaches a multiple of the bit after it
+        let sys = u16::from_be_bytes(self.sys) as u64;
+        let until_falls = |bit: u32| (2 << bit) - sys % (2 << bit);
+        let mut ticks = until_falls(div_apu_bit(double_speed));
+        if enabled(self.tac) {
+            ticks = ticks.min(until_falls(input_bit(self.tac)));
+        }
+        ticks - 1
+    }
+
+    /// Catch up on ticks that only counted
+    pub fn skip(&mut self, ticks: u64) {
+        let sys = u16::from_be_bytes(self.sys).wrapping_add(ticks as u16);
+        self.sys = sys.to_be_bytes();
+    }
+
     pub fn read_div(&self) -> &[u8] {
         &self.sys[..1]
     }
 
-    pub fn write_div(&mut self) {
-        self.sys = [0, 0];
+    /// Set the internal counter, of which DIV is the upper byte
+    pub fn set_counter(&mut self, counter: u16) {
+        self.sys = counter.to_be_bytes();
+    }
+
+    /// Resetting the counter can cause a falling edge, like any other change to it
+    pub fn write_div(&mut self, double_speed: bool) -> Result {
+        Result {
+            interrupt: false,
+            div_apu: self.update(0, self.tac, double_speed),
+        }
+    }
+
+    pub fn write_tima(&mut self, tima: u8) {
+        match self.state {
+            // writing TIMA as it overflows cancels the reload and interrupt
+            State::Overflow(_) => {
+                self.tima = tima;
+                self.state = State::Ticking;
+            }
+            // the reload wins over the write
+            State::Reloaded(_) => {}
+            State::Ticking => self.tima = tima,
+        }
+    }
+
+    pub fn write_tma(&mut self, tma: u8) {
+        self.tma = tma;
+        if let State::Reloaded(_) = self.state {
+            self.tima = tma;
+        }
+    }
+
+    /// Changing the frequency or disabling the timer can cause a falling edge
+    pub fn write_tac(&mut self, tac: u8, double_speed: bool) {
+        let sys = u16::from_be_bytes(self.sys);
+        // the unused upper bits read as 1
+        self.update(sys, tac | 0b11111000, double_speed);
+    }
+
+    /// Move to a new counter and TAC, incrementing TIMA on a falling edge of the timer's input.
+    /// Returns whether DIV-APU ticks, on a falling edge of DIV's bit 4 (bit 5 in double speed)
+    fn update(&mut self, sys: u16, tac: u8, double_speed: bool) -> bool {
+        let sys_prev = u16::from_be_bytes(self.sys);
+        if input(sys_prev, self.tac) && !input(sys, tac) {
+            self.tima = self.tima.wrapping_add(1);
+            if self.tima == 0 {
+                self.state = State::Overflow(3);
+            }
+        }
+        self.sys = sys.to_be_bytes();
+        self.tac = tac;
+        let bit = div_apu_bit(double_speed);
+        !(sys_prev >> bit).is_multiple_of(2) && (sys >> bit).is_multiple_of(2)
+    }
+}
+
+/// TAC's enable bit, ANDed with the counter bit selected by TAC's frequency
+fn input(sys: u16, tac: u8) -> bool {
+    enabled(tac) && (sys >> input_bit(tac)) % 2 == 1
+}
+
+fn enabled(tac: u8) -> bool {
+    (tac >> 2) % 2 == 1
+}
+
+fn input_bit(tac: u8) -> u32 {
+    match tac % 4 {
+        0 => 9,
+        1 => 3,
+        2 => 5,
+        3 => 7,
+        _ => unreachable!(),
+    }
+}
+
+fn div_apu_bit(double_speed: bool) -> u32 {
+    if double_speed { 13 } else { 12 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Enabled at 16 dots per increment, the next tick overflowing TIMA into a reload of `tma`
+    fn overflowing(tma: u8) -> Timer {
+        let mut timer = Timer::default();
+        timer.write_tac(0b101, false);
+        timer.write_tma(tma);
+        timer.tima = 0xFF;
+        timer.set_counter(0x000F);
+        timer
+    }
+
+    /// Tick `ticks` times, returning whether an interrupt was requested
+    fn tick(timer: &mut Timer, ticks: usize) -> bool {
+        (0..ticks).fold(false, |interrupt, _| {
+            timer.tick(false).interrupt || interrupt
+        })
+    }
+
+    #[test]
+    fn overflow_reloads_after_an_m_cycle() {
+        let mut timer = overflowing(0x42);
+        for _ in 0..4 {
+            assert!(!timer.tick(false).interrupt);
+            assert_eq!(timer.tima, 0x00);
+        }
+        assert!(timer.tick(false).interrupt);
+        assert_eq!(timer.tima, 0x42);
+    }
+
+    #[test]
+    fn tima_write_cancels_overflow() {
+        let mut timer = overflowing(0x42);
+        tick(&mut timer, 2);
+        timer.write_tima(0x10);
+        assert!(!tick(&mut timer, 8));
+        assert_eq!(timer.tima, 0x10);
+    }
+
+    #[test]
+    fn tima_write_loses_to_reload() {
+        let mut timer = overflowing(0x42);
+        assert!(tick(&mut timer, 5));
+        timer.write_tima(0x10);
+        assert_eq!(timer.tima, 0x42);
+        // once the reload is over, TIMA can be written again
+        tick(&mut timer, 4);
+        timer.write_tima(0x10);
+        assert_eq!(timer.tima, 0x10);
+    }
+
+    #[test]
+    fn tma_write_during_overflow_is_reloaded() {
+        let mut timer = overflowing(0x42);
+        tick(&mut timer, 2);
+        timer.write_tma(0x55);
+        assert_eq!(timer.tima, 0x00);
+        assert!(tick(&mut timer, 3));
+        assert_eq!(timer.tima, 0x55);
+    }
+
+    #[test]
+    fn tma_write_during_reload_sets_tima() {
+        let mut timer = overflowing(0x42);
+        assert!(tick(&mut timer, 5));
+        timer.write_tma(0x77);
+        assert_eq!(timer.tima, 0x77);
+        tick(&mut timer, 4);
+        timer.write_tma(0x88);
+        assert_eq!((timer.tima, timer.tma), (0x77, 0x88));
+    }
+
+    #[test]
+    fn div_write_falling_edge() {
+        let mut timer = Timer::default();
+        timer.write_tac(0b101, false);
+        // the selected bit is high, so resetting the counter increments TIMA
+        timer.set_counter(0x0008);
+        timer.write_div(false);
+        assert_eq!(timer.tima, 1);
+        assert_eq!(timer.read_div(), [0x00]);
+        // and low, so it doesn't
+        timer.set_counter(0x0010);
+        timer.write_div(false);
+        assert_eq!(timer.tima, 1);
+        // DIV-APU follows bit 12
+        timer.set_counter(0x1000);
+        assert!(timer.write_div(false).div_apu);
+        assert!(!timer.write_div(false).div_apu);
+    }
+
+    #[test]
+    fn tac_disable_falling_edge() {
+        let mut timer = Timer::default();
+        timer.write_tac(0b101, false);
+        timer.set_counter(0x0008);
+        timer.write_tac(0b000, false);
+        assert_eq!(timer.tima, 1);
+        // with the selected bit low, disabling doesn't increment
+        timer.write_tac(0b101, false);
+        timer.set_counter(0x0010);
+        timer.write_tac(0b000, false);
+        assert_eq!(timer.tima, 1);
     }
 }
@@ -0,0 +1,240 @@
+use crate::register::RegisterSet;
+use std::{
+    fmt::{self, Display, Formatter},
+    io::{self, BufWriter, Write},
+    str::FromStr,
+};
+
+/// Per-instruction log of the CPU's registers, in the format Gameboy Doctor compares against
+/// known-good logs
+pub struct Trace {
+    out: BufWriter<Box<dyn Write + Send>>,
+    start: Option<Trigger>,
+    stop: Option<Trigger>,
+    state: State,
+    /// Dots since tracing was set up
+    dots: u64,
+}
+
+/// When a trace starts or stops, checked before each instruction
+#[derive(Clone, PartialEq, Debug)]
+pub enum Trigger {
+    /// M-cycles since tracing was set up
+    Cycle(u64),
+    Address(u16),
+    Symbol(String),
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum State {
+    Waiting,
+    Logging,
+    Done,
+}
+
+impl Trace {
+    /// Without a start trigger, logging starts at the first instruction. Once stopped, it doesn't
+    /// start again
+    pub fn new(
+        out: impl Write + Send + 'static,
+        start: Option<Trigger>,
+        stop: Option<Trigger>,
+    ) -> Self {
+        Self {
+            out: BufWriter::new(Box::new(out)),
+            state: if start.is_some() {
+                State::Waiting
+            } else {
+                State::Logging
+            },
+            start,
+            stop,
+            dots: 0,
+        }
+    }
+
+    /// Names of the symbols the triggers wait for
+    pub(crate) fn symbols(&self) -> impl Iterator<Item = &str> {
+        [&self.start, &self.stop]
+            .into_iter()
+            .filter_map(|trigger| match trigger {
+                Some(Trigger::Symbol(name)) => Some(name.as_str()),
+                _ => None,
+            })
+    }
+
+    pub(crate) fn tick(&mut self, dots: u64) {
+        self.dots += dots;
+    }
+
+    /// Log the instruction about to run at pc, given the symbol there and the 4 bytes from pc
+    pub(crate) fn log(
+        &mut self,
+        reg_set: &RegisterSet,
+        symbol: Option<&str>,
+        pcmem: [u8; 4],
+    ) -> io::Result<()> {
+        let cycles = self.dots / 4;
+        let triggered = |trigger: &Option<Trigger>| match trigger {
+            Some(Trigger::Cycle(cycle)) => cycles >= *cycle,
+            Some(Trigger::Address(addr)) => reg_set.pc == *addr,
+            Some(Trigger::Symbol(name)) => symbol == Some(name.as_str()),
+            None => false,
+        };
+        if self.state == State::Waiting && triggered(&self.start) {
+            log::info!(cycles; "trace started");
+            self.state = State::Logging;
+        }
+        if self.state == State::Logging && triggered(&self.stop) {
+            log::info!(cycles; "trace stopped");
+            self.state = State::Done;
+            return self.out.flush();
+        }
+        if self.state != State::Logging {
+            return Ok(());
+        }
+        let RegisterSet {
+            a,
+            f,
+            b,
+            c,
+            d,
+            e,
+            h,
+            l,
+            sp,
+            pc,
+            ..
+        } = reg_set;
+        let [m0, m1, m2, m3] = pcmem;
+        writeln!(
+            self.out,
+            "A:{a:02X} F:{f:02X} B:{b:02X} C:{c:02X} D:{d:02X} E:{e:02X} H:{h:02X} L:{l:02X} SP:{sp:04X} PC:{pc:04X} PCMEM:{m0:02X},{m1:02X},{m2:02X},{m3:02X}"
+        )
+    }
+
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl Display for Trigger {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cycle(cycle) => write!(f, "cycle:{cycle}"),
+            Self::Address(addr) => write!(f, "pc:{addr:04X}"),
+            Self::Symbol(name) => write!(f, "symbol:{name}"),
+        }
+    }
+}
+
+/// Parses `cycle:<M-cycles>`, `pc:<hex address>` or `symbol:<name>`
+impl FromStr for Trigger {
+    type Err = String;
+
+    fn from_str(trigger: &str) -> Result<Self, Self::Err> {
+        let expected = || {
+            format!(
+                "unknown trigger '{trigger}', expected cycle:<M-cycles>, pc:<hex address> or symbol:<name>"
+            )
+        };
+        match trigger.split_once(':').ok_or_else(expected)? {
+            ("cycle", cycle) => cycle.parse().map(Self::Cycle).map_err(|_| expected()),
+            ("pc", addr) => u16::from_str_radix(addr.trim_start_matches('$'), 16)
+                .map(Self::Address)
+                .map_err(|_| expected()),
+            ("symbol", name) if !name.is_empty() => Ok(Self::Symbol(name.into())),
+            _ => Err(expected()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// A sink the test can read back
+    #[derive(Clone, Default)]
+    struct SharedLog(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedLog {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Log the instructions at `pcs`, each taking an M-cycle, with a symbol at $0200
+    fn trace(start: Option<Trigger>, stop: Option<Trigger>, pcs: &[u16]) -> Vec<String> {
+        let log = SharedLog::default();
+        let mut trace = Trace::new(log.clone(), start, stop);
+        for &pc in pcs {
+            let reg_set = RegisterSet {
+                a: 0x01,
+                sp: 0xFFFE,
+                pc,
+                ..Default::default()
+            };
+            let symbol = (pc == 0x0200).then_some("memcpy");
+            trace
+                .log(&reg_set, symbol, [0x00, 0xC3, 0x50, 0x01])
+                .unwrap();
+            trace.tick(4);
+        }
+        trace.flush().unwrap();
+        let out = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        out.lines().map(Into::into).collect()
+    }
+
+    #[test]
+    fn logs_doctor_format() {
+        assert_eq!(
+            trace(None, None, &[0x0100]),
+            ["A:01 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:FFFE PC:0100 PCMEM:00,C3,50,01"]
+        );
+    }
+
+    #[test]
+    fn stops_on_cycle() {
+        let lines = trace(
+            None,
+            Some(Trigger::Cycle(2)),
+            &[0x0100, 0x0101, 0x0150, 0x0151],
+        );
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].contains("PC:0101"));
+    }
+
+    #[test]
+    fn starts_on_address_and_stops_on_symbol() {
+        let start = Some(Trigger::Address(0x0150));
+        let stop = Some(Trigger::Symbol("memcpy".into()));
+        let lines = trace(start, stop, &[0x0100, 0x0150, 0x0151, 0x0200, 0x0150]);
+        let pcs: Vec<_> = lines
+            .iter()
+            .map(|line| line.split(' ').nth(9).unwrap())
+            .collect();
+        // once stopped, reaching the start address again doesn't restart it
+        assert_eq!(pcs, ["PC:0150", "PC:0151"]);
+    }
+
+    #[test]
+    fn parses_triggers() {
+        for (trigger, parsed) in [
+            ("cycle:3", Trigger::Cycle(3)),
+            ("pc:0208", Trigger::Address(0x0208)),
+            ("pc:$0208", Trigger::Address(0x0208)),
+            ("symbol:memcpy", Trigger::Symbol("memcpy".into())),
+        ] {
+            assert_eq!(trigger.parse(), Ok(parsed.clone()));
+            assert_eq!(parsed.to_string().parse(), Ok(parsed));
+        }
+        for trigger in ["symbol:", "cycle:-1", "pc:10000", "frame:1", "0208"] {
+            assert!(trigger.parse::<Trigger>().is_err());
+        }
+    }
+}
@@ -0,0 +1,437 @@
+use crate::{
+    frame::Frame,
+    system::{CLOCK_HZ, FRAME_DOTS},
+};
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Format {
+    Gif,
+    Apng,
+    Y4m,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Gif(gif::EncodingError),
+    Png(png::EncodingError),
+    UnknownFormat,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(_) => write!(f, "couldn't write video"),
+            Self::Gif(_) => write!(f, "couldn't encode GIF frame"),
+            Self::Png(_) => write!(f, "couldn't encode APNG frame"),
+            Self::UnknownFormat => write!(f, "unknown video format"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            Self::Gif(err) => Some(err),
+            Self::Png(err) => Some(err),
+            Self::UnknownFormat => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+/// Encodes every frame it's given, timed at the hardware frame rate.
+/// Runs of identical frames are merged into a single, longer frame for GIF and APNG.
+/// The writer must be seekable, since an APNG's frame count is written once it's finished
+pub struct VideoWriter<W: Write + Seek> {
+    encoder: Encoder<W>,
+    width: usize,
+    height: usize,
+    frames: u64,
+    pending: Option<(Vec<u8>, u64)>,
+}
+
+enum Encoder<W: Write + Seek> {
+    Gif(gif::Encoder<W>),
+    Apng(Apng<W>),
+    Y4m(W),
+}
+
+/// An APNG written a frame at a time, with the frame count patched into its acTL chunk at the end
+struct Apng<W: Write + Seek> {
+    writer: W,
+    /// Where the acTL chunk starts
+    actl: u64,
+    frames: u32,
+    /// Sequence number of the next fcTL or fdAT chunk
+    sequence: u32,
+    /// Each frame is compressed as a PNG of its own, whose IDAT chunks are copied over
+    scratch: Vec<u8>,
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "gif" => Some(Self::Gif),
+            "png" | "apng" => Some(Self::Apng),
+            "y4m" => Some(Self::Y4m),
+            _ => None,
+        }
+    }
+
+    /// Time units per second used for frame delays
+    fn timebase(self) -> u64 {
+        match self {
+            Self::Gif => 100,
+            Self::Apng => 1000,
+            Self::Y4m => CLOCK_HZ,
+        }
+    }
+}
+
+impl VideoWriter<BufWriter<File>> {
+    /// Create a video file, with the format picked from the file extension
+    pub fn create(path: impl AsRef<Path>, width: usize, height: usize) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let format = Format::from_path(path).ok_or(Error::UnknownFormat)?;
+        Self::new(BufWriter::new(File::create(path)?), format, width, height)
+    }
+}
+
+impl<W: Write + Seek> VideoWriter<W> {
+    /// Frames must be `width` by `height`
+    pub fn new(mut writer: W, format: Format, width: usize, height: usize) -> Result<Self, Error> {
+        let encoder = match format {
+            Format::Gif => {
+                let mut encoder =
+                    gif::Encoder::new(writer, width as _, height as _, &[]).map_err(Error::Gif)?;
+                encoder
+                    .set_repeat(gif::Repeat::Infinite)
+                    .map_err(Error::Gif)?;
+                Encoder::Gif(encoder)
+            }
+            Format::Apng => Encoder::Apng(Apng::new(writer, width, height)?),
+            Format::Y4m => {
+                writeln!(
+                    writer,
+                    "YUV4MPEG2 W{width} H{height} F{CLOCK_HZ}:{FRAME_DOTS} Ip A1:1 C444"
+                )?;
+                Encoder::Y4m(writer)
+            }
+        };
+        Ok(Self {
+            encoder,
+            width,
+            height,
+            frames: 0,
+            pending: None,
+        })
+    }
+
+    pub fn format(&self) -> Format {
+        match self.encoder {
+            Encoder::Gif(_) => Format::Gif,
+            Encoder::Apng(..) => Format::Apng,
+            Encoder::Y4m(_) => Format::Y4m,
+        }
+    }
+
+    pub fn push(&mut self, frame: &Frame) -> Result<(), Error> {
+        let rgb = frame.as_rgb8();
+        if let Encoder::Y4m(writer) = &mut self.encoder {
+            write_y4m_frame(writer, rgb)?;
+        } else if self
+            .pending
+            .as_ref()
+            .is_none_or(|(pending, _)| pending[..] != *rgb)
+        {
+            let pending = self.pending.replace((rgb.to_vec(), self.frames));
+            self.emit(pending)?;
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<W, Error> {
+        let pending = self.pending.take();
+        self.emit(pending)?;
+        match self.encoder {
+            Encoder::Gif(encoder) => encoder.into_inner().map_err(Error::Gif),
+            Encoder::Apng(apng) => apng.finish(),
+            Encoder::Y4m(mut writer) => {
+                writer.flush()?;
+                Ok(writer)
+            }
+        }
+    }
+
+    /// Encode a frame that started at `start`, lasting until the current frame
+    fn emit(&mut self, pending: Option<(Vec<u8>, u64)>) -> Result<(), Error> {
+        let Some((rgb, start)) = pending else {
+            return Ok(());
+        };
+        let timebase = self.format().timebase();
+        // rounding the timestamps instead of each delay keeps the video from drifting
+        let timestamp = |frame: u64| (frame * FRAME_DOTS * timebase + CLOCK_HZ / 2) / CLOCK_HZ;
+        let mut delay = timestamp(self.frames) - timestamp(start);
+        loop {
+            let chunk = delay.min(u16::MAX.into());
+            match &mut self.encoder {
+                Encoder::Gif(encoder) => {
+                    let (palette, indices) = indexed(&rgb);
+                    encoder
+                        .write_frame(&gif::Frame {
+                            delay: chunk as _,
+                            width: self.width as _,
+                            height: self.height as _,
+                            palette: Some(palette),
+                            buffer: indices.into(),
+                            ..Default::default()
+                        })
+                        .map_err(Error::Gif)?;
+                }
+                Encoder::Apng(apng) => apng.write_frame(&rgb, self.width, self.height, chunk)?,
+                Encoder::Y4m(_) => unreachable!("y4m frames are written immediately"),
+            }
+            delay -= chunk;
+            if delay == 0 {
+                break Ok(());
+            }
+        }
+    }
+}
+
+impl<W: Write + Seek> Apng<W> {
+    fn new(mut writer: W, width: usize, height: usize) -> Result<Self, Error> {
+        writer.write_all(b"\x89PNG\r\n\x1A\n")?;
+        // 8-bit RGB, not interlaced
+        let size = [(width as u32).to_be_bytes(), (height as u32).to_be_bytes()];
+        write_chunk(
+            &mut writer,
+            b"IHDR",
+            &[size.as_flattened(), &[8, 2, 0, 0, 0]],
+        )?;
+        let actl = writer.stream_position()?;
+        // no frames yet, looping forever
+        write_chunk(
+            &mut writer,
+            b"acTL",
+            &[&0u32.to_be_bytes(), &0u32.to_be_bytes()],
+        )?;
+        Ok(Self {
+            writer,
+            actl,
+            frames: 0,
+            sequence: 0,
+            scratch: vec![],
+        })
+    }
+
+    fn write_frame(
+        &mut self,
+        rgb: &[u8],
+        width: usize,
+        height: usize,
+        delay: u64,
+    ) -> Result<(), Error> {
+        self.scratch.clear();
+        let mut encoder = png::Encoder::new(&mut self.scratch, width as _, height as _);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut encoder = encoder.write_header().map_err(Error::Png)?;
+        encoder.write_image_data(rgb).map_err(Error::Png)?;
+        encoder.finish().map_err(Error::Png)?;
+
+        let control = [
+            self.sequence.to_be_bytes(),
+            (width as u32).to_be_bytes(),
+            (height as u32).to_be_bytes(),
+            // no offset
+            [0; 4],
+            [0; 4],
+        ];
+        let delay = [
+            (delay as u16).to_be_bytes(),
+            (Format::Apng.timebase() as u16).to_be_bytes(),
+        ];
+        // the previous frame is fully replaced, so it's neither disposed of nor blended
+        write_chunk(
+            &mut self.writer,
+            b"fcTL",
+            &[control.as_flattened(), delay.as_flattened(), &[0, 0]],
+        )?;
+        self.sequence += 1;
+        // the first frame is the default image, and the rest are fdAT chunks
+        let mut chunks = &self.scratch[8..];
+        while let Some((len, rest)) = chunks.split_first_chunk::<4>() {
+            let len = u32::from_be_bytes(*len) as usize;
+            let (kind, data) = rest.split_at(4);
+            if kind == b"IDAT" && self.frames == 0 {
+                write_chunk(&mut self.writer, b"IDAT", &[&data[..len]])?;
+            } else if kind == b"IDAT" {
+                write_chunk(
+                    &mut self.writer,
+                    b"fdAT",
+                    &[&self.sequence.to_be_bytes(), &data[..len]],
+                )?;
+                self.sequence += 1;
+            }
+            // skip the data and CRC
+            chunks = &data[len + 4..];
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<W, Error> {
+        write_chunk(&mut self.writer, b"IEND", &[])?;
+        let end = self.writer.stream_position()?;
+        self.writer.seek(SeekFrom::Start(self.actl))?;
+        write_chunk(
+            &mut self.writer,
+            b"acTL",
+            &[&self.frames.to_be_bytes(), &0u32.to_be_bytes()],
+        )?;
+        self.writer.seek(SeekFrom::Start(end))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Write a PNG chunk whose data is `parts` put together
+fn write_chunk(writer: &mut impl Write, kind: &[u8; 4], parts: &[&[u8]]) -> std::io::Result<()> {
+    let len: usize = parts.iter().map(|part| part.len()).sum();
+    writer.write_all(&(len as u32).to_be_bytes())?;
+    writer.write_all(kind)?;
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    for part in parts {
+        writer.write_all(part)?;
+        crc.update(part);
+    }
+    writer.write_all(&crc.finalize().to_be_bytes())
+}
+
+/// Convert RGB pixels into a palette and palette indices. DMG and SGB frames never exceed 256 colors
+fn indexed(rgb: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let mut colors: Vec<[u8; 3]> = vec![];
+    let indices = rgb
+        .as_chunks::<3>()
+        .0
+        .iter()
+        .map(|color| {
+            let index = colors.iter().position(|c| c == color).unwrap_or_else(|| {
+                colors.push(*color);
+                colors.len() - 1
+            });
+            index as u8
+        })
+        .collect();
+    (colors.into_flattened(), indices)
+}
+
+fn write_y4m_frame(writer: &mut impl Write, rgb: &[u8]) -> Result<(), Error> {
+    let pixels = rgb.as_chunks::<3>().0;
+    // BT.601, limited range
+    let plane = |coeffs: [i32; 3], offset: i32| -> Vec<u8> {
+        pixels
+            .iter()
+            .map(|&[r, g, b]| {
+                let [cr, cg, cb] = coeffs;
+                (((cr * r as i32 + cg * g as i32 + cb * b as i32 + 128) >> 8) + offset) as u8
+            })
+            .collect()
+    };
+    writer.write_all(b"FRAME\n")?;
+    writer.write_all(&plane([66, 129, 25], 16))?;
+    writer.write_all(&plane([-38, -74, 112], 128))?;
+    writer.write_all(&plane([112, -94, -18], 128))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{self, Pixel};
+    use std::io::Cursor;
+
+    const FRAMES: u64 = 600;
+    /// The hardware frame rate, independent of the constants the writer uses
+    const FRAME_RATE: f64 = 59.7275;
+
+    /// Frames which alternate between white and black every 7 frames, so runs get merged
+    fn record(format: Format) -> Vec<u8> {
+        let mut video =
+            VideoWriter::new(Cursor::new(vec![]), format, frame::WIDTH, frame::HEIGHT).unwrap();
+        let mut frame = Frame::default();
+        frame.set_palette([Pixel(255, 255, 255), Pixel(0, 0, 0)]);
+        for i in 0..FRAMES {
+            frame.set(0, 0, 0, (i / 7 % 2) as u8);
+            frame.finish();
+            video.push(&frame).unwrap();
+        }
+        video.finish().unwrap().into_inner()
+    }
+
+    fn expected_delay(timebase: f64) -> u64 {
+        (FRAMES as f64 / FRAME_RATE * timebase).round() as u64
+    }
+
+    #[test]
+    fn gif_delays_follow_frame_rate() {
+        let video = record(Format::Gif);
+        let mut decoder = gif::DecodeOptions::new().read_info(&video[..]).unwrap();
+        let (mut frames, mut delay) = (0, 0);
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            frames += 1;
+            delay += frame.delay as u64;
+        }
+        assert_eq!(frames, FRAMES.div_ceil(7));
+        assert_eq!(delay, expected_delay(100.0));
+    }
+
+    #[test]
+    fn apng_delays_follow_frame_rate() {
+        let video = record(Format::Apng);
+        let mut reader = png::Decoder::new(Cursor::new(video)).read_info().unwrap();
+        let frames = reader.info().animation_control().unwrap().num_frames;
+        assert_eq!(frames as u64, FRAMES.div_ceil(7));
+        let mut buf = vec![0; reader.output_buffer_size().unwrap()];
+        let mut delay = 0;
+        for _ in 0..frames {
+            reader.next_frame(&mut buf).unwrap();
+            let control = reader.info().frame_control().unwrap();
+            assert_eq!(control.delay_den, 1000);
+            delay += control.delay_num as u64;
+        }
+        assert_eq!(delay, expected_delay(1000.0));
+    }
+
+    #[test]
+    fn y4m_frame_rate() {
+        let video = record(Format::Y4m);
+        let header = video.split(|&b| b == b'\n').next().unwrap();
+        let header = std::str::from_utf8(header).unwrap();
+        let rate = header
+            .split(' ')
+            .find_map(|field| field.strip_prefix('F'))
+            .unwrap();
+        let (num, den) = rate.split_once(':').unwrap();
+        let rate = num.parse::<f64>().unwrap() / den.parse::<f64>().unwrap();
+        assert!((rate - FRAME_RATE).abs() < 0.001, "{rate}");
+        let frame_len = b"FRAME\n".len() + frame::WIDTH * frame::HEIGHT * 3;
+        assert_eq!(video.len(), header.len() + 1 + FRAMES as usize * frame_len);
+    }
+}
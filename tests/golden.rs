@@ -0,0 +1,533 @@
+use yokoi::{
+    Input, Model, Options, Renderer, Scheduling,
+    cart::Cart,
+    frame::{Frame, Palettes, Pixel, Theme},
+    golden::{self, Golden},
+    system::System,
+};
+
+const TILES_ADDR: u16 = 0x1000;
+const MAP_ADDR: u16 = 0x2000;
+const OAM_ADDR: u16 = 0x3000;
+const PACKET_ADDR: u16 = 0x3100;
+const SEND_PACKET_ADDR: u16 = 0x0220;
+
+const TILES: [[u8; 16]; 6] = [
+    [0x00; 16],
+    [
+        0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF,
+        0x00,
+    ],
+    [
+        0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFF, 0x00,
+        0xFF,
+    ],
+    [0xFF; 16],
+    // checkerboard
+    [
+        0xAA, 0x55, 0x55, 0xAA, 0xAA, 0x55, 0x55, 0xAA, 0xAA, 0x55, 0x55, 0xAA, 0xAA, 0x55, 0x55,
+        0xAA,
+    ],
+    // arrow pointing up-left, asymmetric so flips are visible
+    [
+        0xF8, 0xF8, 0xC0, 0xF0, 0xA0, 0xD8, 0x90, 0xCC, 0x08, 0x86, 0x04, 0x03, 0x02, 0x01, 0x00,
+        0x00,
+    ],
+];
+
+struct Scene {
+    bgp: u8,
+    obp0: u8,
+    scx: u8,
+    scy: u8,
+    lcdc: u8,
+    objects: &'static [[u8; 4]],
+    sgb_packet: Option<[u8; 16]>,
+    /// Run once the scene is set up
+    code: &'static [u8],
+}
+
+impl Default for Scene {
+    fn default() -> Self {
+        Self {
+            bgp: 0b11100100,
+            obp0: 0b11100100,
+            scx: 0,
+            scy: 0,
+            lcdc: 0b10010001,
+            objects: &[],
+            sgb_packet: None,
+            code: &[],
+        }
+    }
+}
+
+/// Assemble a ROM which turns the LCD off, copies tile, map and OAM data, sets up the LCD
+/// registers for the scene, sends an SGB packet if there is one, runs the scene's code, then
+/// loops forever
+fn assemble(scene: &Scene) -> Cart {
+    let [tiles_lo, tiles_hi] = TILES_ADDR.to_le_bytes();
+    let [map_lo, map_hi] = MAP_ADDR.to_le_bytes();
+    let [oam_lo, oam_hi] = OAM_ADDR.to_le_bytes();
+    let tiles_len = (TILES.len() * 16) as u8;
+    #[rustfmt::skip]
+    let mut main = vec![
+        0xF0, 0x44,             // ldh a, [LY]
+        0xFE, 0x90,             // cp 144
+        0x20, 0xFA,             // jr nz, -6
+        0xAF,                   // xor a
+        0xE0, 0x40,             // ldh [LCDC], a
+        0x21, 0x00, 0x80,       // ld hl, $8000
+        0x11, tiles_lo, tiles_hi, // ld de, TILES_ADDR
+        0x01, tiles_len, 0x00,  // ld bc, tiles_len
+        0xCD, 0x00, 0x02,       // call memcpy
+        0x21, 0x00, 0x98,       // ld hl, $9800
+        0x11, map_lo, map_hi,   // ld de, MAP_ADDR
+        0x01, 0x00, 0x04,       // ld bc, $0400
+        0xCD, 0x00, 0x02,       // call memcpy
+        0x21, 0x00, 0xFE,       // ld hl, $FE00
+        0x11, oam_lo, oam_hi,   // ld de, OAM_ADDR
+        0x01, 0xA0, 0x00,       // ld bc, $00A0
+        0xCD, 0x00, 0x02,       // call memcpy
+        0x3E, scene.bgp,        // ld a, BGP
+        0xE0, 0x47,             // ldh [BGP], a
+        0x3E, scene.obp0,       // ld a, OBP0
+        0xE0, 0x48,             // ldh [OBP0], a
+        0x3E, scene.scx,        // ld a, SCX
+        0xE0, 0x43,             // ldh [SCX], a
+        0x3E, scene.scy,        // ld a, SCY
+        0xE0, 0x42,             // ldh [SCY], a
+        0x3E, scene.lcdc,       // ld a, LCDC
+        0xE0, 0x40,             // ldh [LCDC], a
+    ];
+    if scene.sgb_packet.is_some() {
+        let [packet_lo, packet_hi] = PACKET_ADDR.to_le_bytes();
+        let [send_lo, send_hi] = SEND_PACKET_ADDR.to_le_bytes();
+        main.extend([0x21, packet_lo, packet_hi, 0xCD, send_lo, send_hi]);
+    }
+    main.extend(scene.code);
+    main.extend([0x18, 0xFE]); // jr -2
+
+    Cart::test(&main, |rom| {
+        rom[0x0134..0x0138].copy_from_slice(b"TEST");
+        if let Some(packet) = scene.sgb_packet {
+            // SGB support, which requires the new licensee code
+            rom[0x0146] = 0x03;
+            rom[0x014B] = 0x33;
+            let packet_addr = PACKET_ADDR as usize;
+            rom[packet_addr..packet_addr + packet.len()].copy_from_slice(&packet);
+        }
+
+        #[rustfmt::skip]
+        let memcpy = [
+            0x1A,                   // ld a, [de]
+            0x22,                   // ld [hl+], a
+            0x13,                   // inc de
+            0x0B,                   // dec bc
+            0x78,                   // ld a, b
+            0xB1,                   // or c
+            0x20, 0xF8,             // jr nz, -8
+            0xC9,                   // ret
+        ];
+        rom[0x0200..0x0200 + memcpy.len()].copy_from_slice(&memcpy);
+
+        #[rustfmt::skip]
+        let vblank_handler = [
+            0xAF,                   // xor a
+            0xD9,                   // reti
+        ];
+        rom[0x0040..0x0040 + vblank_handler.len()].copy_from_slice(&vblank_handler);
+
+        #[rustfmt::skip]
+        let stat_handler = [
+            0xE0, 0x43,             // ldh [SCX], a
+            0x3C,                   // inc a
+            0xD9,                   // reti
+        ];
+        rom[0x0048..0x0048 + stat_handler.len()].copy_from_slice(&stat_handler);
+
+        #[rustfmt::skip]
+        let timer_handler = [
+            0xE0, 0x43,             // ldh [SCX], a
+            0xD9,                   // reti
+        ];
+        rom[0x0050..0x0050 + timer_handler.len()].copy_from_slice(&timer_handler);
+
+        // send the 16 bytes at hl through the joypad register, lowest bit first
+        #[rustfmt::skip]
+        let send_packet = [
+            0xAF,                   // xor a
+            0xE0, 0x00,             // ldh [JOYP], a
+            0x3E, 0x30,             // ld a, $30
+            0xE0, 0x00,             // ldh [JOYP], a
+            0x06, 0x10,             // ld b, 16
+            0x5E,                   // ld e, [hl]
+            0x23,                   // inc hl
+            0x16, 0x08,             // ld d, 8
+            0xCB, 0x1B,             // rr e
+            0x3E, 0x10,             // ld a, $10
+            0x38, 0x02,             // jr c, +2
+            0x3E, 0x20,             // ld a, $20
+            0xE0, 0x00,             // ldh [JOYP], a
+            0x3E, 0x30,             // ld a, $30
+            0xE0, 0x00,             // ldh [JOYP], a
+            0x15,                   // dec d
+            0x20, 0xEF,             // jr nz, -17
+            0x05,                   // dec b
+            0x20, 0xE8,             // jr nz, -24
+            0x3E, 0x20,             // ld a, $20
+            0xE0, 0x00,             // ldh [JOYP], a
+            0x3E, 0x30,             // ld a, $30
+            0xE0, 0x00,             // ldh [JOYP], a
+            0xC9,                   // ret
+        ];
+        let send_packet_addr = SEND_PACKET_ADDR as usize;
+        rom[send_packet_addr..send_packet_addr + send_packet.len()].copy_from_slice(&send_packet);
+
+        let tiles = TILES_ADDR as usize;
+        rom[tiles..tiles + TILES.len() * 16].copy_from_slice(TILES.as_flattened());
+        // diagonal stripes of every tile except the arrow
+        for y in 0..32 {
+            for x in 0..32 {
+                rom[MAP_ADDR as usize + y * 32 + x] = ((x / 2 + y) % (TILES.len() - 1)) as u8;
+            }
+        }
+        let oam = OAM_ADDR as usize;
+        rom[oam..oam + scene.objects.len() * 4].copy_from_slice(scene.objects.as_flattened());
+    })
+}
+
+fn render(scene: &Scene) -> Frame {
+    render_with(scene, Model::Dmg, Default::default())
+}
+
+fn render_with(scene: &Scene, model: Model, options: Options) -> Frame {
+    let mut system = System::init_options(
+        vec![],
+        assemble(scene),
+        model,
+        Options {
+            skip_boot: true,
+            ..options
+        },
+    )
+    .expect("system initialized");
+    for _ in 0..4 {
+        system.next_frame(Input::default()).expect("frame emulated");
+    }
+    system
+        .next_frame(Input::default())
+        .expect("frame emulated")
+        .clone()
+}
+
+/// Set to overwrite the reference frames instead of checking against them
+const BLESS_VAR: &str = "YOKOI_BLESS";
+
+fn check(name: &str, scene: Scene) {
+    let golden = Golden::new(
+        concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden"),
+        env!("CARGO_TARGET_TMPDIR"),
+        std::env::var_os(BLESS_VAR).is_some(),
+    );
+    if let Err(err) = golden.check(name, &render(&scene)) {
+        panic!("{name} doesn't match its reference frame: {err}");
+    }
+}
+
+/// Run the scene ticking every dot and on events, checking their states after each frame
+fn assert_schedulings_agree(scene: &Scene) {
+    let [mut dots, mut events] = [Scheduling::Dots, Scheduling::Events].map(|scheduling| {
+        let options = Options {
+            skip_boot: true,
+            scheduling,
+            ..Default::default()
+        };
+        System::init_options(vec![], assemble(scene), Model::Dmg, options)
+            .expect("system initialized")
+    });
+    for frame in 1..=8 {
+        dots.next_frame(Input::default()).expect("frame emulated");
+        events.next_frame(Input::default()).expect("frame emulated");
+        assert_eq!(
+            events.state_hash().unwrap(),
+            dots.state_hash().unwrap(),
+            "states differ after frame {frame}"
+        );
+    }
+}
+
+#[test]
+fn background() {
+    check("background", Scene::default());
+}
+
+#[test]
+fn background_scrolled() {
+    check(
+        "background_scrolled",
+        Scene {
+            scx: 3,
+            scy: 5,
+            ..Default::default()
+        },
+    );
+}
+
+#[test]
+fn background_palette() {
+    check(
+        "background_palette",
+        Scene {
+            bgp: 0b00011011,
+            ..Default::default()
+        },
+    );
+}
+
+#[test]
+fn objects() {
+    check(
+        "objects",
+        Scene {
+            lcdc: 0b10010011,
+            objects: &[
+                [16, 8, 5, 0b00000000],
+                [40, 40, 5, 0b00100000],
+                [60, 80, 5, 0b01000000],
+                [80, 120, 5, 0b01100000],
+                [100, 163, 5, 0b00000000],
+            ],
+            ..Default::default()
+        },
+    );
+}
+
+#[test]
+fn window() {
+    #[rustfmt::skip]
+    let code = &[
+        0x3E, 0x28,             // ld a, 40
+        0xE0, 0x4A,             // ldh [WY], a
+        0x3E, 0x2F,             // ld a, 47
+        0xE0, 0x4B,             // ldh [WX], a
+    ];
+    check(
+        "window",
+        Scene {
+            scx: 3,
+            lcdc: 0b10110001,
+            code,
+            ..Default::default()
+        },
+    );
+}
+
+#[test]
+fn renderers_agree() {
+    #[rustfmt::skip]
+    let code = &[
+        0x3E, 0x50,             // ld a, 80
+        0xE0, 0x4A,             // ldh [WY], a
+        0x3E, 0x5F,             // ld a, 95
+        0xE0, 0x4B,             // ldh [WX], a
+    ];
+    let scene = Scene {
+        scx: 3,
+        scy: 5,
+        lcdc: 0b10110011,
+        objects: &[
+            [16, 8, 5, 0b00000000],
+            [20, 12, 5, 0b00010000],
+            [60, 80, 5, 0b01000000],
+            [100, 90, 5, 0b10100000],
+            [140, 163, 5, 0b00000000],
+        ],
+        code,
+        ..Default::default()
+    };
+    let render_by = |renderer| {
+        let options = Options {
+            renderer,
+            ..Default::default()
+        };
+        render_with(&scene, Model::Dmg, options).hash()
+    };
+    // differential rendering fails on the first pixel or mode 3 the renderers disagree on
+    assert_eq!(render_by(Renderer::Differential), render_by(Renderer::Fifo));
+    assert_eq!(render_by(Renderer::Scanline), render_by(Renderer::Fifo));
+}
+
+#[test]
+fn object_penalties_agree() {
+    // each row of tiles has a pair of objects further right than the last, the second starting
+    // on the same tile as the first or the next one
+    let objects = (0..18)
+        .flat_map(|row| {
+            [
+                [16 + 8 * row, 2 * row, 5, 0],
+                [16 + 8 * row, 2 * row + 4, 5, 0],
+            ]
+        })
+        .collect::<Vec<_>>()
+        .leak();
+    for scx in 0..8 {
+        let scene = Scene {
+            scx,
+            lcdc: 0b10010011,
+            objects,
+            ..Default::default()
+        };
+        let options = Options {
+            renderer: Renderer::Differential,
+            ..Default::default()
+        };
+        render_with(&scene, Model::Dmg, options);
+    }
+}
+
+#[test]
+fn hash_is_stable() {
+    let frame = render(&Scene::default());
+    assert_eq!(frame.hash(), render(&Scene::default()).hash());
+    assert_eq!(golden::to_image(&frame).into_raw(), frame.as_rgb8());
+}
+
+#[test]
+fn save_state_resumes() {
+    let scene = Scene {
+        scx: 3,
+        ..Default::default()
+    };
+    let options = Options {
+        skip_boot: true,
+        ..Default::default()
+    };
+    let mut system = System::init_options(vec![], assemble(&scene), Model::Dmg, options)
+        .expect("system initialized");
+    system.next_frame(Input::default()).expect("frame emulated");
+    let mut state = vec![];
+    system.save_state(&mut state).expect("state saved");
+    let mut loaded = System::load(&state[..], assemble(&scene)).expect("state loaded");
+    for _ in 0..4 {
+        let hash = system
+            .next_frame(Input::default())
+            .expect("frame emulated")
+            .hash();
+        let loaded_hash = loaded
+            .next_frame(Input::default())
+            .expect("frame emulated")
+            .hash();
+        assert_eq!(loaded_hash, hash);
+    }
+    assert_eq!(loaded.state_hash().unwrap(), system.state_hash().unwrap());
+}
+
+#[test]
+fn exports_agree() {
+    fn assert_send_sync<T: Send + Sync>(_: &T) {}
+    let frame = render(&Scene::default());
+    assert_send_sync(&frame);
+    let (rgb, rgba, rgb565) = (frame.as_rgb8(), frame.as_rgba8(), frame.as_rgb565());
+    let shades = frame.as_indices();
+    let pixels = rgb.as_chunks::<3>().0.iter().zip(rgba.as_chunks::<4>().0);
+    for (i, (&[r, g, b], &rgba)) in pixels.enumerate() {
+        assert_eq!(rgba, [r, g, b, 255]);
+        assert_eq!(frame.pixel(i % 160, i / 160), Pixel(r, g, b));
+        let expected = ((r as u16 >> 3) << 11) | ((g as u16 >> 2) << 5) | (b as u16 >> 3);
+        assert_eq!(rgb565[i], expected);
+        // the default grayscale theme maps shades 0-3 from white to black
+        assert_eq!(shades[i], 3 - r / 85);
+    }
+}
+
+#[test]
+fn layer_palettes() {
+    let palettes = Palettes::parse(
+        "bg #FF0000 #AA0000 #550000 #000000 ; reds\n\
+         obj0 #00FF00 #00AA00 #005500 #000000",
+    )
+    .expect("palettes parsed");
+    assert_eq!(palettes.obj1, palettes.bg);
+    let scene = Scene {
+        lcdc: 0b10010011,
+        objects: &[[16, 8, 5, 0b00000000]],
+        ..Default::default()
+    };
+    let gray = render(&scene);
+    let colored = render_with(
+        &scene,
+        Model::Dmg,
+        Options {
+            theme: Theme::Custom(palettes),
+            ..Default::default()
+        },
+    );
+    assert_eq!(gray.as_indices(), colored.as_indices());
+    let mut obj_pixels = 0;
+    for (i, &shade) in colored.as_indices().iter().enumerate() {
+        let pixel = colored.pixel(i % 160, i / 160);
+        if pixel != palettes.bg.color(shade) {
+            assert_eq!(pixel, palettes.obj0.color(shade));
+            obj_pixels += 1;
+        }
+    }
+    assert!(obj_pixels > 0);
+
+    for (palettes, line) in [
+        ("bg #FFFFFF #AAAAAA #555555", 1),
+        ("bg #FFFFFF #AAAAAA #555555 #000000\nobj2 #FFFFFF", 2),
+        ("obj0 #FFFFFF #AAAAAA #555555 #000000", 1),
+        ("bg #FFFFFF #AAAAAA #555555 #00000G", 1),
+    ] {
+        let err = Palettes::parse(palettes).expect_err("invalid palettes");
+        assert_eq!(err.line, line, "{palettes}");
+    }
+}
+
+#[test]
+fn sgb_palettes() {
+    // PAL01, with red shared as color 0, then green, blue and black for palette 0
+    let mut packet = [0; 16];
+    packet[..9].copy_from_slice(&[0x01, 0x1F, 0x00, 0xE0, 0x03, 0x00, 0x7C, 0x00, 0x00]);
+    let colors = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [0, 0, 0]];
+    let scene = Scene {
+        sgb_packet: Some(packet),
+        ..Default::default()
+    };
+    let dmg = render(&scene);
+    let sgb = render_with(&scene, Model::Sgb, Default::default());
+    assert_eq!((sgb.width(), sgb.height()), (256, 224));
+    // without a border, the backdrop is color 0
+    let sgb_rgb = sgb.as_rgb8();
+    assert_eq!(sgb_rgb[..3], colors[0]);
+    for (i, &shade) in dmg.as_indices().iter().enumerate() {
+        let (x, y) = (i % 160 + 48, i / 160 + 40);
+        let rgb = &sgb_rgb[(y * 256 + x) * 3..][..3];
+        assert_eq!(rgb, colors[shade as usize], "pixel {x}, {y}");
+    }
+}
+
+#[test]
+fn stat_blocking() {
+    // every H-blank scrolls the following lines one more pixel, except line 50's: LY=LYC keeps
+    // the STAT interrupt line high from the H-blank before it
+    #[rustfmt::skip]
+    let code = &[
+        0x3E, 0x32,             // ld a, 50
+        0xE0, 0x45,             // ldh [LYC], a
+        0x3E, 0x48,             // ld a, $48
+        0xE0, 0x41,             // ldh [STAT], a, for LY=LYC and H-blank
+        0x3E, 0x03,             // ld a, $03
+        0xE0, 0xFF,             // ldh [IE], a
+        0xAF,                   // xor a
+        0xE0, 0x0F,             // ldh [IF], a
+        0xFB,                   // ei
+    ];
+    let scene = Scene {
+        code,
+        ..Default::default()
+    };
+    assert_schedulings_agree(&scene);
+    check("stat_blocking", scene);
+}
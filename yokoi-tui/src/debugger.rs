@@ -3,7 +3,8 @@ use crossterm::event::{Event, KeyCode, KeyEvent, KeyEventKind};
 use image::{Rgb, RgbImage};
 use yokoi::{
     Input, ScreenPos, SymbolError,
-    frame::{Frame, Pixel},
+    frame::Frame,
+    golden,
     system::{Address, System},
 };
 
@@ -61,9 +62,10 @@ impl Debugger {
         loop {
             let input = Input::default();
             match self.system.next_frame(input) {
-                Ok(frame) => {
-                    self.latest_frame = Some(frame.clone());
-                }
+                Ok(frame) => match &mut self.latest_frame {
+                    Some(latest) => latest.clone_from(frame),
+                    None => self.latest_frame = Some(frame.clone()),
+                },
                 Err(yokoi::system::Error::Breakpoint(breakpoint)) => {
                     log::info!(breakpoint;"");
                     match self.handle_break()? {
@@ -81,16 +83,11 @@ impl Debugger {
     }
 
     fn display_frame(&self, guides: bool) -> Result<(), Error> {
-        let mut image_buf = RgbImage::from_fn(160, 144, |x, y| {
-            self.latest_frame
-                .as_ref()
-                .and_then(|frame| frame.0.get(y as usize))
-                .and_then(|row| row.get(x as usize))
-                .map(|cell| cell.get())
-                .map(|Pixel(r, g, b)| [r, g, b])
-                .unwrap_or([0, 0, 0])
-                .into()
-        });
+        let mut image_buf = self
+            .latest_frame
+            .as_ref()
+            .map(golden::to_image)
+            .unwrap_or_else(|| RgbImage::new(160, 144));
         if guides {
             for x in (0..image_buf.width()).step_by(8).skip(1) {
                 for y in (0..image_buf.height()).step_by(8).skip(1) {
@@ -0,0 +1,190 @@
+use crate::{Error, keys::Button};
+use std::{io::Write, path::PathBuf};
+use yokoi::{Input, Joypad, golden, movie::Player, system::System};
+
+pub enum Scripted {
+    None,
+    Movie(Player),
+    Script(Script),
+}
+
+/// Timed button presses, one `<frame>[-<last frame>] <button>[+<button>...]` entry per line
+pub struct Script(Vec<Press>);
+
+struct Press {
+    frames: std::ops::RangeInclusive<u64>,
+    joypad: Joypad,
+}
+
+pub struct Headless<W: Write> {
+    pub system: System,
+    pub input: Scripted,
+    pub frames: Option<u64>,
+    pub until_hash: Option<String>,
+    pub screenshots: Vec<u64>,
+    pub screenshot_dir: PathBuf,
+    pub out: W,
+}
+
+impl Script {
+    pub fn parse(script: &str) -> Result<Self, Error> {
+        let mut presses = vec![];
+        for (i, line) in script.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let err = |reason| Error::Script {
+                line: i + 1,
+                reason,
+            };
+            let (frames, buttons) = line
+                .split_once(char::is_whitespace)
+                .ok_or(err("expected a frame and buttons"))?;
+            let parse_frame = |frame: &str| frame.parse::<u64>().map_err(|_| err("invalid frame"));
+            let frames = if let Some((first, last)) = frames.split_once('-') {
+                parse_frame(first)?..=parse_frame(last)?
+            } else {
+                let frame = parse_frame(frames)?;
+                frame..=frame
+            };
+            let mut joypad = Joypad::default();
+            for button in buttons.trim().split('+') {
+                Button::parse(button.trim())
+                    .ok_or(err("unknown button"))?
+                    .press(&mut joypad);
+            }
+            presses.push(Press { frames, joypad });
+        }
+        Ok(Self(presses))
+    }
+
+    fn joypad(&self, frame: u64) -> Joypad {
+        self.0
+            .iter()
+            .filter(|press| press.frames.contains(&frame))
+            .fold(Joypad::default(), |acc, press| acc | press.joypad)
+    }
+}
+
+impl<W: Write> Headless<W> {
+    pub fn run(mut self) -> Result<(), Error> {
+        let mut locked_up = false;
+        for frame in 1.. {
+            if self.frames.is_some_and(|frames| frame > frames) {
+                break;
+            }
+            let input = match &self.input {
+                Scripted::None => Input::default(),
+                Scripted::Movie(player) => player.next_input().unwrap_or_default(),
+                Scripted::Script(script) => Input {
+                    joypad: script.joypad(frame),
+                    ..Default::default()
+                },
+            };
+            let rendered = match self.system.next_frame(input) {
+                Ok(rendered) => rendered,
+                Err(yokoi::system::Error::Breakpoint(breakpoint)) => {
+                    writeln!(self.out, "Reached breakpoint: {breakpoint}")?;
+                    break;
+                }
+                Err(err) => return Err(Error::System(err)),
+            };
+            let hash = rendered.hash();
+            writeln!(self.out, "{frame} {hash}")?;
+            if self.screenshots.contains(&frame) {
+                let path = self.screenshot_dir.join(format!("frame_{frame}.png"));
+                golden::to_image(rendered)
+                    .save(&path)
+                    .map_err(Error::Image)?;
+                log::info!(path:? = path; "saved screenshot");
+            }
+            if !locked_up && let Some(lock_up) = self.system.lock_up() {
+                writeln!(self.out, "{lock_up}")?;
+                locked_up = true;
+            }
+            if let Scripted::Movie(player) = &mut self.input
+                && !player.finished()
+            {
+                player.advance(&self.system).map_err(Error::Movie)?;
+            }
+            if self.until_hash.as_ref() == Some(&hash) {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yokoi::{Model, cart::Cart};
+
+    /// Steps BGP once per frame, so every frame differs from the last
+    fn system() -> System {
+        #[rustfmt::skip]
+        let code = [
+            0xF0, 0x44,         // ldh a, [LY]
+            0xFE, 0x90,         // cp 144
+            0x20, 0xFA,         // jr nz, -6
+            0xF0, 0x47,         // ldh a, [BGP]
+            0x3C,               // inc a
+            0xE0, 0x47,         // ldh [BGP], a
+            0xF0, 0x44,         // ldh a, [LY]
+            0xFE, 0x90,         // cp 144
+            0x28, 0xFA,         // jr z, -6
+            0x18, 0xED,         // jr -19
+        ];
+        let cart = Cart::test(&code, |_| {});
+        System::init(vec![], cart, Model::Dmg).expect("system initialized")
+    }
+
+    fn run(frames: Option<u64>, until_hash: Option<String>) -> Vec<(u64, String)> {
+        let mut out = vec![];
+        Headless {
+            system: system(),
+            input: Scripted::None,
+            frames,
+            until_hash,
+            screenshots: vec![],
+            screenshot_dir: PathBuf::new(),
+            out: &mut out,
+        }
+        .run()
+        .expect("no error");
+        String::from_utf8(out)
+            .expect("text output")
+            .lines()
+            .map(|line| {
+                let (frame, hash) = line.split_once(' ').expect("frame and hash");
+                (frame.parse().expect("frame number"), hash.into())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn prints_frame_hashes() {
+        let mut system = system();
+        let expected: Vec<_> = (1..=8)
+            .map(|frame| {
+                let hash = system
+                    .next_frame(Input::default())
+                    .expect("no error")
+                    .hash();
+                (frame, hash)
+            })
+            .collect();
+        assert_eq!(run(Some(8), None), expected);
+        assert!(expected.windows(2).all(|pair| pair[0].1 != pair[1].1));
+    }
+
+    #[test]
+    fn stops_at_hash() {
+        let hashes = run(Some(4), None);
+        let until = hashes[2].1.clone();
+        assert_eq!(run(Some(4), Some(until)), hashes[..3]);
+        // without a match, the frame limit still applies
+        assert_eq!(run(Some(4), Some("none".into())), hashes);
+    }
+}
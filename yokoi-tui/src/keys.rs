@@ -0,0 +1,302 @@
+use crate::Error;
+use crossterm::event::{KeyCode, KeyEvent, KeyEventKind};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use yokoi::Joypad;
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Button {
+    Start,
+    Select,
+    Up,
+    Down,
+    Left,
+    Right,
+    A,
+    B,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Action {
+    Button(Button),
+    /// Repeatedly press and release a button while held
+    Turbo(Button),
+    Quit,
+    Pause,
+    Advance,
+    FastForward,
+    SlowMotion,
+}
+
+const DEFAULT_BINDINGS: &str = "
+    up w up
+    down s down
+    left a left
+    right d right
+    start c enter
+    select v
+    a space z
+    b x
+    turbo-a k
+    turbo-b j
+    quit q
+    pause p
+    advance n
+    fast-forward tab
+    slow-motion m
+";
+
+/// Key bindings, one `<action> <key> [<key>...]` entry per line
+pub struct Bindings(HashMap<KeyCode, Action>);
+
+/// Keys currently held down. Without key release events, a key counts as held until `timeout`
+/// passes without it being pressed again, which should be longer than the key repeat delay. An
+/// action is held while any of its keys are
+pub struct Keyboard {
+    bindings: Bindings,
+    releases: bool,
+    timeout: Duration,
+    held: HashMap<KeyCode, Option<Instant>>,
+}
+
+impl Button {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "start" => Some(Self::Start),
+            "select" => Some(Self::Select),
+            "up" => Some(Self::Up),
+            "down" => Some(Self::Down),
+            "left" => Some(Self::Left),
+            "right" => Some(Self::Right),
+            "a" => Some(Self::A),
+            "b" => Some(Self::B),
+            _ => None,
+        }
+    }
+
+    pub fn press(self, joypad: &mut Joypad) {
+        let held = match self {
+            Self::Start => &mut joypad.start,
+            Self::Select => &mut joypad.select,
+            Self::Up => &mut joypad.up,
+            Self::Down => &mut joypad.down,
+            Self::Left => &mut joypad.left,
+            Self::Right => &mut joypad.right,
+            Self::A => &mut joypad.a,
+            Self::B => &mut joypad.b,
+        };
+        *held = true;
+    }
+}
+
+impl Action {
+    fn parse(name: &str) -> Option<Self> {
+        let name = name.to_ascii_lowercase();
+        match name.as_str() {
+            "quit" => Some(Self::Quit),
+            "pause" => Some(Self::Pause),
+            "advance" => Some(Self::Advance),
+            "fast-forward" => Some(Self::FastForward),
+            "slow-motion" => Some(Self::SlowMotion),
+            _ => match name.strip_prefix("turbo-") {
+                Some(button) => Button::parse(button).map(Self::Turbo),
+                None => Button::parse(&name).map(Self::Button),
+            },
+        }
+    }
+}
+
+impl Bindings {
+    pub fn parse(bindings: &str) -> Result<Self, Error> {
+        let mut keys = HashMap::new();
+        for (i, line) in bindings.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let err = |reason| Error::Bindings {
+                line: i + 1,
+                reason,
+            };
+            let mut words = line.split_whitespace();
+            let action = words
+                .next()
+                .and_then(Action::parse)
+                .ok_or(err("unknown action"))?;
+            let mut bound = false;
+            for key in words {
+                keys.insert(parse_key(key).ok_or(err("unknown key"))?, action);
+                bound = true;
+            }
+            if !bound {
+                return Err(err("expected at least one key"));
+            }
+        }
+        Ok(Self(keys))
+    }
+
+    /// Add bindings, replacing any for the same keys
+    pub fn extend(&mut self, other: Self) {
+        self.0.extend(other.0);
+    }
+
+    pub fn action(&self, code: KeyCode) -> Option<Action> {
+        self.0.get(&normalize(code)).copied()
+    }
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        Self::parse(DEFAULT_BINDINGS).expect("default bindings are valid")
+    }
+}
+
+impl Keyboard {
+    pub fn new(bindings: Bindings, releases: bool, timeout: Duration) -> Self {
+        Self {
+            bindings,
+            releases,
+            timeout,
+            held: HashMap::new(),
+        }
+    }
+
+    /// Whether key release events are reported
+    pub fn releases(&self) -> bool {
+        self.releases
+    }
+
+    /// Track a key event, returning the action of a key press when no other key held it already
+    pub fn handle(&mut self, KeyEvent { code, kind, .. }: KeyEvent) -> Option<Action> {
+        let action = self.bindings.action(code)?;
+        let code = normalize(code);
+        let was_held = self.held(action);
+        match kind {
+            KeyEventKind::Press if self.releases => {
+                self.held.insert(code, None);
+            }
+            KeyEventKind::Press => {
+                self.held.insert(code, Some(Instant::now() + self.timeout));
+            }
+            KeyEventKind::Repeat => {}
+            KeyEventKind::Release => {
+                self.held.remove(&code);
+            }
+        }
+        (kind == KeyEventKind::Press && !was_held).then_some(action)
+    }
+
+    /// Forget keys which timed out
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        self.held
+            .retain(|_, deadline| deadline.is_none_or(|deadline| deadline > now));
+    }
+
+    pub fn held(&self, action: Action) -> bool {
+        self.actions().any(|held| held == action)
+    }
+
+    /// Actions of the held keys, once for each key
+    fn actions(&self) -> impl Iterator<Item = Action> {
+        self.held
+            .keys()
+            .filter_map(|&code| self.bindings.action(code))
+    }
+
+    /// Held buttons, and held turbo buttons
+    pub fn joypad(&self) -> (Joypad, Joypad) {
+        let mut joypad = Joypad::default();
+        let mut turbo = Joypad::default();
+        for action in self.actions() {
+            match action {
+                Action::Button(button) => button.press(&mut joypad),
+                Action::Turbo(button) => button.press(&mut turbo),
+                _ => {}
+            }
+        }
+        (joypad, turbo)
+    }
+}
+
+/// Letters are bound regardless of shift and caps lock
+fn normalize(code: KeyCode) -> KeyCode {
+    match code {
+        KeyCode::Char(c) => KeyCode::Char(c.to_ascii_lowercase()),
+        code => code,
+    }
+}
+
+fn parse_key(key: &str) -> Option<KeyCode> {
+    let mut chars = key.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Some(KeyCode::Char(c.to_ascii_lowercase()));
+    }
+    let key = key.to_ascii_lowercase();
+    match key.as_str() {
+        "space" => Some(KeyCode::Char(' ')),
+        "up" => Some(KeyCode::Up),
+        "down" => Some(KeyCode::Down),
+        "left" => Some(KeyCode::Left),
+        "right" => Some(KeyCode::Right),
+        "enter" => Some(KeyCode::Enter),
+        "tab" => Some(KeyCode::Tab),
+        "backspace" => Some(KeyCode::Backspace),
+        "esc" => Some(KeyCode::Esc),
+        _ => key
+            .strip_prefix('f')
+            .and_then(|n| n.parse().ok())
+            .map(KeyCode::F),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossterm::event::KeyModifiers;
+
+    fn event(key: char, kind: KeyEventKind) -> KeyEvent {
+        KeyEvent::new_with_kind(KeyCode::Char(key), KeyModifiers::NONE, kind)
+    }
+
+    fn keyboard(releases: bool) -> Keyboard {
+        Keyboard::new(Bindings::default(), releases, Duration::from_secs(60))
+    }
+
+    #[test]
+    fn action_held_while_any_key_is() {
+        let mut keyboard = keyboard(true);
+        let a = Action::Button(Button::A);
+        assert_eq!(keyboard.handle(event(' ', KeyEventKind::Press)), Some(a));
+        assert_eq!(keyboard.handle(event('z', KeyEventKind::Press)), None);
+        keyboard.handle(event(' ', KeyEventKind::Release));
+        assert!(keyboard.held(a));
+        assert!(keyboard.joypad().0.a);
+        keyboard.handle(event('z', KeyEventKind::Release));
+        assert!(!keyboard.held(a));
+        assert!(!keyboard.joypad().0.a);
+    }
+
+    #[test]
+    fn shifted_keys_release_the_same_key() {
+        let mut keyboard = keyboard(true);
+        keyboard.handle(event('w', KeyEventKind::Press));
+        keyboard.handle(event('W', KeyEventKind::Release));
+        assert!(!keyboard.held(Action::Button(Button::Up)));
+    }
+
+    #[test]
+    fn presses_time_out_without_releases() {
+        let mut keyboard = keyboard(false);
+        keyboard.handle(event('x', KeyEventKind::Press));
+        keyboard.expire();
+        assert!(keyboard.joypad().0.b);
+
+        let mut keyboard = Keyboard::new(Bindings::default(), false, Duration::ZERO);
+        keyboard.handle(event('x', KeyEventKind::Press));
+        keyboard.expire();
+        assert!(!keyboard.joypad().0.b);
+    }
+}
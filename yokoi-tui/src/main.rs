@@ -1,25 +1,38 @@
 mod debugger;
+mod headless;
+mod keys;
 mod logger;
+mod screen;
 mod tui;
 
-use clap::{Parser, Subcommand};
+use clap::{ArgGroup, Parser, Subcommand};
 use log::LevelFilter;
 use logger::Logger;
 use std::{
     fmt::{Display, Formatter},
+    fs::File,
     io::{self, BufRead, BufReader, Write},
     net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream},
     path::PathBuf,
     process::{Command, Stdio},
+    time::{Duration, Instant},
 };
 use yokoi::{
-    Mode, Options,
+    Input, Model, Options, Renderer, Scheduling,
     cart::{Cart, ColorSupport, Feature},
-    frame::Theme,
-    system::System,
+    frame::{Palettes, Theme},
+    movie::Movie,
+    system::{self, System},
+    trace::{Trace, Trigger},
+    video::VideoWriter,
 };
 
-use crate::debugger::Debugger;
+use crate::{
+    debugger::Debugger,
+    headless::{Headless, Script, Scripted},
+    keys::Bindings,
+    screen::RenderMode,
+};
 
 /// Interface with the Yokoi emulator backend from the terminal.
 #[derive(Parser)]
@@ -40,10 +53,34 @@ enum Commands {
         #[arg(long)]
         classic_theme: bool,
 
+        /// Load colors from a palette file. Each line is `<layer> <color> <color> <color> <color>`,
+        /// lightest first, for the `bg`, `obj0` and `obj1` layers
+        #[arg(long, conflicts_with_all = ["classic_theme", "cgb_colors"])]
+        palette: Option<PathBuf>,
+
+        /// Colorize the game like a CGB does, picking palettes by its title
+        #[arg(long, conflicts_with = "classic_theme")]
+        cgb_colors: bool,
+
         /// Skip the boot-up sequence
         #[arg(long)]
         skip_boot: bool,
 
+        /// Hardware model to emulate: dmg0, dmg, mgb, sgb, sgb2, cgb or agb. Color models only run
+        /// carts without CGB support
+        #[arg(long, default_value_t = Model::Dmg)]
+        model: Model,
+
+        /// Pixel renderer: fifo, scanline (faster), or differential, which runs both and stops
+        /// at the first pixel they disagree on
+        #[arg(long, default_value_t = Renderer::Fifo)]
+        renderer: Renderer,
+
+        /// When the timer, serial, OAM DMA and PPU are ticked: events, only when they have work to
+        /// do, or dots, every dot
+        #[arg(long, default_value_t = Scheduling::Events)]
+        scheduling: Scheduling,
+
         /// Don't show terminal UI. For use within a debugger
         #[arg(long)]
         debug: bool,
@@ -52,6 +89,11 @@ enum Commands {
         #[arg(long)]
         strict_mem_access: bool,
 
+        /// Illegal opcodes freeze the CPU while the screen keeps running, like on hardware,
+        /// instead of stopping with an error
+        #[arg(long)]
+        lock_up: bool,
+
         /// Log level when debugging. Overriden by RUST_LOG
         #[arg(long, requires = "debug", default_value_t = LevelFilter::Info)]
         log_level: LevelFilter,
@@ -72,9 +114,193 @@ enum Commands {
         #[arg(short = 'B', long = "breakpoint", requires = "symbols")]
         breakpoints: Vec<String>,
 
-        /// Path to boot ROM file
+        /// Log each instruction's registers to this file, in the format Gameboy Doctor compares
+        #[arg(long)]
+        trace: Option<PathBuf>,
+
+        /// Start the trace at cycle:<M-cycles>, pc:<hex address> or symbol:<name>
+        #[arg(long, requires = "trace")]
+        trace_start: Option<Trigger>,
+
+        /// Stop the trace at cycle:<M-cycles>, pc:<hex address> or symbol:<name>
+        #[arg(long, requires = "trace")]
+        trace_stop: Option<Trigger>,
+
+        /// Record joypad input to a movie file
+        #[arg(long, conflicts_with = "play_movie")]
+        record_movie: Option<PathBuf>,
+
+        /// Play back joypad input from a movie file
+        #[arg(long)]
+        play_movie: Option<PathBuf>,
+
+        /// Record gameplay to a .gif, .png (APNG) or .y4m video file
+        #[arg(long)]
+        record_video: Option<PathBuf>,
+
+        /// Speed multiplier while fast-forward (Tab) is held
+        #[arg(long, default_value_t = 4.0, value_parser = parse_multiplier)]
+        fast_forward: f64,
+
+        /// Speed multiplier while slow-motion (m) is toggled on
+        #[arg(long, default_value_t = 0.25, value_parser = parse_multiplier)]
+        slow_motion: f64,
+
+        /// Key bindings file, applied over the defaults.
+        /// Each line is `<action> <key> [<key>...]`, e.g. `turbo-a k`
+        #[arg(long)]
+        keys: Option<PathBuf>,
+
+        /// Milliseconds a key stays held after being pressed, if the terminal doesn't report key
+        /// releases. Should be longer than the keyboard's repeat delay
+        #[arg(long, default_value_t = 500)]
+        hold_timeout: u64,
+
+        /// How the game screen is drawn
+        #[arg(long, value_enum, default_value_t)]
+        render: RenderMode,
+
+        /// Frames turbo buttons are pressed for, then released for
+        #[arg(long, default_value_t = 2, value_parser = clap::value_parser!(u64).range(1..))]
+        turbo_frames: u64,
+
+        /// Path to boot ROM file. Without one, the boot-up sequence is skipped
+        #[arg(short, long)]
+        boot: Option<PathBuf>,
+
+        /// Path to cartridge file
+        cart: PathBuf,
+    },
+
+    /// Run a cartridge without the terminal UI, printing a hash of each frame
+    #[command(group(
+        ArgGroup::new("stop")
+            .required(true)
+            .multiple(true)
+            .args(["frames", "until_hash", "breakpoints"])
+    ))]
+    Headless {
+        /// Stop after N frames
+        #[arg(short = 'n', long)]
+        frames: Option<u64>,
+
+        /// Stop once a frame with this hash is rendered, or after --frames if it never is
+        #[arg(long, requires = "frames")]
+        until_hash: Option<String>,
+
+        /// Use the classic green color scheme instead of grayscale
+        #[arg(long)]
+        classic_theme: bool,
+
+        /// Load colors from a palette file. Each line is `<layer> <color> <color> <color> <color>`,
+        /// lightest first, for the `bg`, `obj0` and `obj1` layers
+        #[arg(long, conflicts_with_all = ["classic_theme", "cgb_colors"])]
+        palette: Option<PathBuf>,
+
+        /// Colorize the game like a CGB does, picking palettes by its title
+        #[arg(long, conflicts_with = "classic_theme")]
+        cgb_colors: bool,
+
+        /// Skip the boot-up sequence
+        #[arg(long)]
+        skip_boot: bool,
+
+        /// Hardware model to emulate: dmg0, dmg, mgb, sgb, sgb2, cgb or agb. Color models only run
+        /// carts without CGB support
+        #[arg(long, default_value_t = Model::Dmg)]
+        model: Model,
+
+        /// Pixel renderer: fifo, scanline (faster), or differential, which runs both and stops
+        /// at the first pixel they disagree on
+        #[arg(long, default_value_t = Renderer::Fifo)]
+        renderer: Renderer,
+
+        /// When the timer, serial, OAM DMA and PPU are ticked: events, only when they have work to
+        /// do, or dots, every dot
+        #[arg(long, default_value_t = Scheduling::Events)]
+        scheduling: Scheduling,
+
+        /// Out-of-bounds accesses are not permitted
+        #[arg(long)]
+        strict_mem_access: bool,
+
+        /// Illegal opcodes freeze the CPU while the screen keeps running, like on hardware,
+        /// instead of stopping with an error
+        #[arg(long)]
+        lock_up: bool,
+
+        /// Path to debug symbols used for breakpoints
+        #[arg(long)]
+        symbols: Option<PathBuf>,
+
+        /// Stop at a breakpoint on a debug symbol. Can be provided multiple times
+        #[arg(short = 'B', long = "breakpoint", requires = "symbols")]
+        breakpoints: Vec<String>,
+
+        /// Log each instruction's registers to this file, in the format Gameboy Doctor compares
+        #[arg(long)]
+        trace: Option<PathBuf>,
+
+        /// Start the trace at cycle:<M-cycles>, pc:<hex address> or symbol:<name>
+        #[arg(long, requires = "trace")]
+        trace_start: Option<Trigger>,
+
+        /// Stop the trace at cycle:<M-cycles>, pc:<hex address> or symbol:<name>
+        #[arg(long, requires = "trace")]
+        trace_stop: Option<Trigger>,
+
+        /// Play back joypad input from a movie file
+        #[arg(long, conflicts_with = "script")]
+        movie: Option<PathBuf>,
+
+        /// Play back timed button presses from a script file.
+        /// Each line is `<frame>[-<last frame>] <button>[+<button>...]`
+        #[arg(long)]
+        script: Option<PathBuf>,
+
+        /// Save a PNG screenshot of frame N. Can be provided multiple times
+        #[arg(short = 's', long = "screenshot")]
+        screenshots: Vec<u64>,
+
+        /// Directory screenshots are saved to
+        #[arg(long, default_value = ".")]
+        screenshot_dir: PathBuf,
+
+        /// Path to boot ROM file. Without one, the boot-up sequence is skipped
+        #[arg(short, long)]
+        boot: Option<PathBuf>,
+
+        /// Path to cartridge file
+        cart: PathBuf,
+    },
+
+    /// Run a cartridge as fast as possible, reporting the emulated clock speed
+    Benchmark {
+        /// Frames to run
+        #[arg(short = 'n', long, default_value_t = 3600)]
+        frames: u64,
+
+        /// Skip the boot-up sequence
+        #[arg(long)]
+        skip_boot: bool,
+
+        /// Hardware model to emulate: dmg0, dmg, mgb, sgb, sgb2, cgb or agb. Color models only run
+        /// carts without CGB support
+        #[arg(long, default_value_t = Model::Dmg)]
+        model: Model,
+
+        /// Pixel renderer: fifo, scanline or differential
+        #[arg(long, default_value_t = Renderer::Fifo)]
+        renderer: Renderer,
+
+        /// When the timer, serial, OAM DMA and PPU are ticked: events, only when they have work to
+        /// do, or dots, every dot
+        #[arg(long, default_value_t = Scheduling::Events)]
+        scheduling: Scheduling,
+
+        /// Path to boot ROM file. Without one, the boot-up sequence is skipped
         #[arg(short, long)]
-        boot: PathBuf,
+        boot: Option<PathBuf>,
 
         /// Path to cartridge file
         cart: PathBuf,
@@ -104,6 +330,19 @@ pub enum Error {
     Cart(yokoi::cart::Error),
     Image(image::ImageError),
     Viuer(viuer::ViuError),
+    Movie(yokoi::movie::Error),
+    Palette(yokoi::frame::PaletteError),
+    Video(yokoi::video::Error),
+    Script {
+        line: usize,
+        reason: &'static str,
+    },
+    Bindings {
+        line: usize,
+        reason: &'static str,
+    },
+    /// The emulation thread panicked, with the panic's message
+    Panic(String),
 }
 
 impl Display for Error {
@@ -117,20 +356,107 @@ impl Display for Error {
             Self::System(yokoi::system::Error::Breakpoint(breakpoint)) => {
                 writeln!(f, "Reached breakpoint: {breakpoint}")
             }
+            Self::System(yokoi::system::Error::RendererMismatch { x, y }) => {
+                writeln!(f, "Renderers disagree at pixel ({x}, {y})")
+            }
+            Self::System(yokoi::system::Error::RendererTimingMismatch { ly, fifo, scanline }) => {
+                writeln!(
+                    f,
+                    "Renderers disagree on line {ly}: mode 3 took {fifo} dots, the scanline renderer expected {scanline}"
+                )
+            }
             Self::Image(err) => writeln!(f, "Error while rendering image: {err}"),
             Self::Viuer(err) => writeln!(f, "Error while rendering image: {err}"),
-            Self::System(err) => writeln!(f, "Internal system error: {err:?}"),
+            Self::System(err) => writeln!(f, "Internal system error: {}", Chain(err)),
             Self::Cart(yokoi::cart::Error(err)) => writeln!(f, "Error while parsing cart: {err}"),
+            Self::Movie(yokoi::movie::Error::WrongCart) => {
+                writeln!(f, "Movie was recorded with a different cart")
+            }
+            Self::Movie(yokoi::movie::Error::WrongSettings { recorded, .. }) => {
+                writeln!(f, "Movie was recorded with {recorded}")
+            }
+            Self::Movie(yokoi::movie::Error::Desync { frame, .. }) => {
+                writeln!(f, "Movie desynced at frame {frame}")
+            }
+            Self::Movie(yokoi::movie::Error::System(err)) => {
+                writeln!(
+                    f,
+                    "Internal system error while playing movie: {}",
+                    Chain(err)
+                )
+            }
+            Self::Movie(err) => writeln!(f, "Error while handling movie: {}", Chain(err)),
+            Self::Video(yokoi::video::Error::UnknownFormat) => {
+                writeln!(
+                    f,
+                    "Unknown video format, expected .gif, .png, .apng or .y4m"
+                )
+            }
+            Self::Video(err) => writeln!(f, "Error while recording video: {}", Chain(err)),
+            Self::Palette(yokoi::frame::PaletteError { line, reason }) => {
+                writeln!(f, "Error while parsing palette at line {line}: {reason}")
+            }
+            Self::Script { line, reason } => {
+                writeln!(f, "Error while parsing script at line {line}: {reason}")
+            }
+            Self::Bindings { line, reason } => {
+                writeln!(
+                    f,
+                    "Error while parsing key bindings at line {line}: {reason}"
+                )
+            }
+            Self::Panic(message) => writeln!(f, "Emulation thread panicked: {message}"),
         }
     }
 }
 
+/// An error's message, followed by those of its sources
+struct Chain<'a>(&'a dyn std::error::Error);
+
+impl Display for Chain<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)?;
+        let mut source = self.0.source();
+        while let Some(err) = source {
+            write!(f, ": {err}")?;
+            source = err.source();
+        }
+        Ok(())
+    }
+}
+
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Self {
         Self::Io(err)
     }
 }
 
+fn parse_multiplier(arg: &str) -> Result<f64, String> {
+    match arg.parse::<f64>() {
+        Ok(multiplier) if multiplier.is_finite() && multiplier > 0.0 => Ok(multiplier),
+        Ok(_) => Err("must be a positive number".into()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Color models colorize DMG carts unless another theme is picked
+fn theme(
+    classic: bool,
+    palette: Option<PathBuf>,
+    cgb_colors: bool,
+    model: Model,
+) -> Result<Theme, Error> {
+    Ok(if let Some(path) = palette {
+        Theme::Custom(Palettes::parse(&std::fs::read_to_string(path)?).map_err(Error::Palette)?)
+    } else if cgb_colors || (!classic && matches!(model, Model::Cgb | Model::Agb)) {
+        Theme::Cgb
+    } else if classic {
+        Theme::Classic
+    } else {
+        Theme::Grayscale
+    })
+}
+
 fn main() {
     if let Err(err) = run() {
         eprintln!("{err}");
@@ -178,14 +504,32 @@ fn run() -> Result<(), Error> {
 
         Commands::Run {
             classic_theme,
+            palette,
+            cgb_colors,
             skip_boot,
+            model,
+            renderer,
+            scheduling,
             debug,
             strict_mem_access,
+            lock_up,
             log_level,
             log_socket,
             short_circuit,
             symbols,
             breakpoints,
+            trace,
+            trace_start,
+            trace_stop,
+            record_movie,
+            play_movie,
+            record_video,
+            fast_forward,
+            slow_motion,
+            keys,
+            hold_timeout,
+            turbo_frames,
+            render,
             boot,
             cart,
             ..
@@ -206,44 +550,203 @@ fn run() -> Result<(), Error> {
                 );
             }
 
-            let boot_rom_data = std::fs::read(&boot)?;
+            let boot_rom_data = boot.map(std::fs::read).transpose()?.unwrap_or_default();
             let cart_data = std::fs::read(&cart)?;
             let cart = Cart::new(cart_data).map_err(Error::Cart)?;
-            let system = System::init_options(
-                boot_rom_data,
-                cart,
-                Mode::Dmg,
-                Options {
-                    theme: if classic_theme {
-                        Theme::Classic
-                    } else {
-                        Theme::Grayscale
-                    },
-                    short_circuit,
-                    debug,
-                    strict_mem_access,
-                    skip_boot,
-                    symbols: symbols
-                        .map(std::fs::read_to_string)
-                        .transpose()
-                        .map_err(Error::Io)?,
-                    breakpoints,
-                },
-            )
-            .map_err(Error::System)?;
+            let (width, height) = model.mode(&cart).screen_size();
+            let options = Options {
+                theme: theme(classic_theme, palette, cgb_colors, model)?,
+                renderer,
+                scheduling,
+                short_circuit,
+                debug,
+                strict_mem_access,
+                lock_up,
+                skip_boot,
+                symbols: symbols
+                    .map(std::fs::read_to_string)
+                    .transpose()
+                    .map_err(Error::Io)?,
+                breakpoints,
+                trace: trace
+                    .map(|path| {
+                        File::create(path).map(|file| Trace::new(file, trace_start, trace_stop))
+                    })
+                    .transpose()
+                    .map_err(Error::Io)?,
+            };
+            let (system, playback) = if let Some(path) = play_movie {
+                let movie = Movie::read(File::open(path)?).map_err(Error::Movie)?;
+                let system = movie
+                    .init_system(boot_rom_data, cart, model, options)
+                    .map_err(Error::Movie)?;
+                (system, Some(movie.play()))
+            } else {
+                let system = System::init_options(boot_rom_data, cart, model, options)
+                    .map_err(Error::System)?;
+                (system, None)
+            };
+            let mut bindings = Bindings::default();
+            if let Some(path) = keys {
+                bindings.extend(Bindings::parse(&std::fs::read_to_string(path)?)?);
+            }
+            let session = tui::Session {
+                recording: record_movie.map(|path| (Movie::power_on(&system), path)),
+                playback,
+                video: record_video
+                    .map(|path| VideoWriter::create(path, width, height))
+                    .transpose()
+                    .map_err(Error::Video)?,
+            };
 
             // if this a lone debugging session (not connected to a server), don't create a TUI
             if debug && log_socket.is_none() {
+                if session.recording.is_some()
+                    || session.playback.is_some()
+                    || session.video.is_some()
+                {
+                    log::warn!("recording isn't supported in the debugger");
+                }
                 Debugger::new(system).run()?;
             } else {
                 let term = ratatui::try_init()?;
-                if let Err(err) = tui::run(term, system) {
+                if let Err(err) = tui::run(
+                    term,
+                    system,
+                    session,
+                    tui::Controls {
+                        bindings,
+                        fast_forward,
+                        slow_motion,
+                        hold_timeout: Duration::from_millis(hold_timeout),
+                        turbo_frames,
+                    },
+                    render,
+                ) {
                     log::error!("{err}");
                 }
                 ratatui::restore();
             }
         }
 
+        Commands::Headless {
+            frames,
+            until_hash,
+            classic_theme,
+            palette,
+            cgb_colors,
+            skip_boot,
+            model,
+            renderer,
+            scheduling,
+            strict_mem_access,
+            lock_up,
+            symbols,
+            breakpoints,
+            trace,
+            trace_start,
+            trace_stop,
+            movie,
+            script,
+            screenshots,
+            screenshot_dir,
+            boot,
+            cart,
+        } => {
+            let boot_rom_data = boot.map(std::fs::read).transpose()?.unwrap_or_default();
+            let cart_data = std::fs::read(&cart)?;
+            let cart = Cart::new(cart_data).map_err(Error::Cart)?;
+            let options = Options {
+                theme: theme(classic_theme, palette, cgb_colors, model)?,
+                renderer,
+                scheduling,
+                strict_mem_access,
+                lock_up,
+                skip_boot,
+                symbols: symbols
+                    .map(std::fs::read_to_string)
+                    .transpose()
+                    .map_err(Error::Io)?,
+                breakpoints,
+                trace: trace
+                    .map(|path| {
+                        File::create(path).map(|file| Trace::new(file, trace_start, trace_stop))
+                    })
+                    .transpose()
+                    .map_err(Error::Io)?,
+                ..Default::default()
+            };
+            let (system, input) = if let Some(path) = movie {
+                let movie = Movie::read(File::open(path)?).map_err(Error::Movie)?;
+                let system = movie
+                    .init_system(boot_rom_data, cart, model, options)
+                    .map_err(Error::Movie)?;
+                (system, Scripted::Movie(movie.play()))
+            } else {
+                let system = System::init_options(boot_rom_data, cart, model, options)
+                    .map_err(Error::System)?;
+                let input = if let Some(path) = script {
+                    Scripted::Script(Script::parse(&std::fs::read_to_string(path)?)?)
+                } else {
+                    Scripted::None
+                };
+                (system, input)
+            };
+            Headless {
+                system,
+                input,
+                frames,
+                until_hash,
+                screenshots,
+                screenshot_dir,
+                out,
+            }
+            .run()?;
+        }
+
+        Commands::Benchmark {
+            frames,
+            skip_boot,
+            model,
+            renderer,
+            scheduling,
+            boot,
+            cart,
+        } => {
+            let boot_rom_data = boot.map(std::fs::read).transpose()?.unwrap_or_default();
+            let cart_data = std::fs::read(&cart)?;
+            let cart = Cart::new(cart_data).map_err(Error::Cart)?;
+            let options = Options {
+                theme: theme(false, None, false, model)?,
+                renderer,
+                scheduling,
+                skip_boot,
+                ..Default::default()
+            };
+            let mut system =
+                System::init_options(boot_rom_data, cart, model, options).map_err(Error::System)?;
+            let start = Instant::now();
+            for _ in 0..frames {
+                system.next_frame(Input::default()).map_err(Error::System)?;
+            }
+            let secs = start.elapsed().as_secs_f64();
+            let hz = system.elapsed_dots() as f64 / secs;
+            writeln!(
+                out,
+                "{frames} frames in {secs:.2}s: {:.2} MHz, {:.1}x real time",
+                hz / 1e6,
+                hz / system::CLOCK_HZ as f64
+            )?;
+            let stats = system.op_cache_stats();
+            let fetches = stats.hits + stats.misses + stats.uncached;
+            writeln!(
+                out,
+                "op cache: {:.1}% of {fetches} fetches hit, {} invalidated",
+                100.0 * stats.hits as f64 / fetches.max(1) as f64,
+                stats.invalidations
+            )?;
+        }
+
         Commands::CartInfo { cart } => {
             let data = std::fs::read(&cart)?;
             let cart = Cart::new(data).map_err(Error::Cart)?;
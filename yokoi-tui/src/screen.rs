@@ -0,0 +1,217 @@
+use crate::Error;
+use image::{DynamicImage, imageops::FilterType};
+use ratatui::{
+    prelude::*,
+    widgets::{Block, Widget},
+};
+use std::io::Write;
+use yokoi::{
+    frame::{Frame, Pixel},
+    golden,
+};
+
+const UPPER_HALF_BLOCK: &str = "▀";
+const BRAILLE_START: u32 = 0x2800;
+/// Graphics are pre-scaled with nearest neighbor, so the terminal's own scaling doesn't blur them
+const GRAPHICS_SCALE: u32 = 4;
+/// Braille dot bits, indexed by [y][x] within a cell
+const BRAILLE_DOTS: [[u8; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+
+#[derive(Copy, Clone, PartialEq, Default, Debug, clap::ValueEnum)]
+pub enum RenderMode {
+    /// Two cells per pixel, colored by their background
+    Blocks,
+    /// Two pixels per cell, one above the other
+    #[default]
+    HalfBlock,
+    /// 2x4 monochrome dots per cell
+    Braille,
+    /// Kitty, iTerm or sixel graphics, when the terminal supports them
+    Graphics,
+}
+
+#[derive(Default)]
+pub struct GameScreen {
+    pub frame: Frame,
+    pub block: Block<'static>,
+    pub mode: RenderMode,
+}
+
+/// Where the frame is drawn within an area, in subpixels (the smallest unit a mode can color)
+struct Layout {
+    // subpixels per cell, and terminal columns per cell
+    sub_width: u16,
+    sub_height: u16,
+    columns: u16,
+    // scaled frame size in subpixels
+    width: u32,
+    height: u32,
+    // offset of the frame from the area, in cells
+    x: u16,
+    y: u16,
+}
+
+impl RenderMode {
+    /// Query the terminal for graphics support before the UI starts reading terminal events
+    pub fn detect_graphics(self) {
+        if self == Self::Graphics {
+            let kitty = viuer::get_kitty_support() != viuer::KittySupport::None;
+            log::info!(kitty, sixel = viuer::is_sixel_supported(); "graphics support");
+        }
+    }
+
+    /// Fit a `frame_width` by `frame_height` frame within an area
+    fn layout(self, area: Rect, frame_width: u32, frame_height: u32) -> Layout {
+        let (sub_width, sub_height, columns) = match self {
+            Self::Blocks => (1, 1, 2),
+            Self::HalfBlock | Self::Graphics => (1, 2, 1),
+            Self::Braille => (2, 4, 1),
+        };
+        let cells_width = area.width / columns;
+        let grid_width = (cells_width * sub_width) as u32;
+        let grid_height = (area.height * sub_height) as u32;
+
+        // integer scaling when the frame fits, otherwise shrink to fit while keeping the aspect
+        let scale = (grid_width / frame_width).min(grid_height / frame_height);
+        let (width, height) = if scale > 0 {
+            (frame_width * scale, frame_height * scale)
+        } else if grid_width * frame_height <= grid_height * frame_width {
+            (grid_width, grid_width * frame_height / frame_width)
+        } else {
+            (grid_height * frame_width / frame_height, grid_height)
+        };
+        let cells_used = |size: u32, sub: u16| size.div_ceil(sub as u32) as u16;
+        Layout {
+            sub_width,
+            sub_height,
+            columns,
+            width,
+            height,
+            x: (cells_width - cells_used(width, sub_width)) / 2 * columns,
+            y: (area.height - cells_used(height, sub_height)) / 2,
+        }
+    }
+}
+
+impl Layout {
+    /// Size in terminal cells
+    fn cells(&self) -> (u16, u16) {
+        let width = self.width.div_ceil(self.sub_width as u32) as u16;
+        let height = self.height.div_ceil(self.sub_height as u32) as u16;
+        (width * self.columns, height)
+    }
+}
+
+impl GameScreen {
+    fn layout(&self, mode: RenderMode, area: Rect) -> Layout {
+        let (width, height) = (self.frame.width(), self.frame.height());
+        mode.layout(area, width as u32, height as u32)
+    }
+
+    /// The pixel shown at a subpixel, if it's within the frame
+    fn sample(&self, layout: &Layout, x: u32, y: u32) -> Option<(Pixel, u8)> {
+        if x >= layout.width || y >= layout.height {
+            return None;
+        }
+        let x = (x * self.frame.width() as u32 / layout.width) as usize;
+        let y = (y * self.frame.height() as u32 / layout.height) as usize;
+        let shade = self.frame.as_indices()[y * self.frame.width() + x];
+        Some((self.frame.pixel(x, y), shade))
+    }
+
+    /// Draw the frame with terminal graphics, outside of the ratatui buffer
+    pub fn print_graphics(&self, area: Rect) -> Result<(), Error> {
+        let area = self.block.inner(area);
+        let layout = self.layout(RenderMode::Graphics, area);
+        let (width, height) = layout.cells();
+        let image = DynamicImage::from(golden::to_image(&self.frame)).resize(
+            self.frame.width() as u32 * GRAPHICS_SCALE,
+            self.frame.height() as u32 * GRAPHICS_SCALE,
+            FilterType::Nearest,
+        );
+        if viuer::get_kitty_support() != viuer::KittySupport::None {
+            // delete the previous frame's image
+            write!(std::io::stdout(), "\x1b_Ga=d\x1b\\")?;
+        }
+        let config = viuer::Config {
+            x: area.x + layout.x,
+            y: (area.y + layout.y) as i16,
+            width: Some(width as u32),
+            height: Some(height as u32),
+            restore_cursor: true,
+            ..Default::default()
+        };
+        viuer::print(&image, &config).map_err(Error::Viuer)?;
+        Ok(())
+    }
+}
+
+impl Widget for &GameScreen {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        (&self.block).render(area, buf);
+        let area = self.block.inner(area);
+        if self.mode == RenderMode::Graphics {
+            return;
+        }
+        let layout = self.layout(self.mode, area);
+        let (width, height) = layout.cells();
+        for cy in 0..height {
+            for cx in 0..width / layout.columns {
+                let sub_x = (cx * layout.sub_width) as u32;
+                let sub_y = (cy * layout.sub_height) as u32;
+                let x = area.x + layout.x + cx * layout.columns;
+                let y = area.y + layout.y + cy;
+                match self.mode {
+                    RenderMode::Blocks => {
+                        let Some((Pixel(r, g, b), _)) = self.sample(&layout, sub_x, sub_y) else {
+                            continue;
+                        };
+                        for column in 0..layout.columns {
+                            buf[(x + column, y)].set_bg(Color::Rgb(r, g, b));
+                        }
+                    }
+                    RenderMode::HalfBlock => {
+                        buf[(x, y)]
+                            .set_symbol(UPPER_HALF_BLOCK)
+                            .set_fg(color(self.sample(&layout, sub_x, sub_y)))
+                            .set_bg(color(self.sample(&layout, sub_x, sub_y + 1)));
+                    }
+                    RenderMode::Braille => {
+                        let mut dots = 0;
+                        let mut darkest: Option<(Pixel, u8)> = None;
+                        let mut lightest: Option<(Pixel, u8)> = None;
+                        for (dy, row) in BRAILLE_DOTS.iter().enumerate() {
+                            for (dx, bit) in row.iter().enumerate() {
+                                let sample =
+                                    self.sample(&layout, sub_x + dx as u32, sub_y + dy as u32);
+                                let Some((pixel, shade)) = sample else {
+                                    continue;
+                                };
+                                if shade >= 2 {
+                                    dots |= bit;
+                                }
+                                if darkest.is_none_or(|(_, darkest)| shade > darkest) {
+                                    darkest = Some((pixel, shade));
+                                }
+                                if lightest.is_none_or(|(_, lightest)| shade < lightest) {
+                                    lightest = Some((pixel, shade));
+                                }
+                            }
+                        }
+                        let symbol = char::from_u32(BRAILLE_START + dots as u32)
+                            .expect("braille patterns are valid chars");
+                        buf[(x, y)]
+                            .set_char(symbol)
+                            .set_fg(color(darkest))
+                            .set_bg(color(lightest));
+                    }
+                    RenderMode::Graphics => unreachable!("graphics are printed separately"),
+                }
+            }
+        }
+    }
+}
+
+fn color(sample: Option<(Pixel, u8)>) -> Color {
+    sample.map_or(Color::Reset, |(Pixel(r, g, b), _)| Color::Rgb(r, g, b))
+}
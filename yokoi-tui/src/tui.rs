@@ -1,57 +1,175 @@
-use crate::Error;
-use crossterm::event::{KeyCode, KeyEvent, KeyEventKind};
-use ratatui::{
-    DefaultTerminal,
-    prelude::*,
-    widgets::{Block, Widget},
+use crate::{
+    Error,
+    keys::{Action, Bindings, Keyboard},
+    screen::{GameScreen, RenderMode},
+};
+use crossterm::event::{
+    Event, KeyboardEnhancementFlags, PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+};
+use ratatui::{DefaultTerminal, widgets::Block};
+use std::{
+    fs::File,
+    io::{self, BufWriter},
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender, SyncSender, TryRecvError, TrySendError},
+    },
+    thread,
+    time::{Duration, Instant},
 };
-use std::time::{Duration, Instant};
 use yokoi::{
-    Input,
-    frame::{Frame, Pixel},
-    system::System,
+    Input, Joypad,
+    frame::Frame,
+    movie::{Movie, Player},
+    system::{CLOCK_HZ, FRAME_DOTS, System},
+    video::VideoWriter,
 };
 
-pub fn run(mut term: DefaultTerminal, mut system: System) -> Result<(), Error> {
-    let mut screen = GameScreen::default();
-    let delta_time = Duration::from_secs(1) / 100;
-    'game_loop: loop {
-        let mut now = Instant::now();
-        let next_frame_at = now + delta_time;
-        let mut input = Input::default();
-        while now < next_frame_at {
-            if crossterm::event::poll(next_frame_at - now)? {
-                match crossterm::event::read()?.as_key_event() {
-                    Some(KeyEvent {
-                        code,
-                        kind: KeyEventKind::Press,
-                        ..
-                    }) => match code {
-                        KeyCode::Char('q') => break 'game_loop,
-                        KeyCode::Char('w') | KeyCode::Up => input.joypad.up = true,
-                        KeyCode::Char('s') | KeyCode::Down => input.joypad.down = true,
-                        KeyCode::Char('a') | KeyCode::Left => input.joypad.left = true,
-                        KeyCode::Char('d') | KeyCode::Right => input.joypad.right = true,
-                        KeyCode::Char('c') | KeyCode::Enter => input.joypad.start = true,
-                        KeyCode::Char('v') => input.joypad.select = true,
-                        KeyCode::Char(' ') | KeyCode::Char('z') => input.joypad.a = true,
-                        KeyCode::Char('x') => input.joypad.b = true,
-                        _ => {}
-                    },
-                    _ => {}
-                }
-                log::debug!(joypad:? = input.joypad;"");
-            }
-            now = Instant::now();
-        }
-        let input = Input {
-            joypad: input.joypad,
-            ..Default::default()
+/// How long the UI thread waits for terminal events before checking for new frames
+const POLL_TIME: Duration = Duration::from_millis(2);
+/// Frames the emulation thread can get ahead of the UI thread
+const FRAME_QUEUE: usize = 2;
+
+/// Optional recording and playback for a game session
+#[derive(Default)]
+pub struct Session {
+    pub recording: Option<(Movie, PathBuf)>,
+    pub playback: Option<Player>,
+    pub video: Option<VideoWriter<BufWriter<File>>>,
+}
+
+/// User configurable controls
+pub struct Controls {
+    pub bindings: Bindings,
+    /// Speed multiplier while fast-forwarding
+    pub fast_forward: f64,
+    /// Speed multiplier while in slow-motion
+    pub slow_motion: f64,
+    /// How long a key counts as held when key releases aren't reported
+    pub hold_timeout: Duration,
+    /// Turbo buttons are pressed for this many frames, then released for as many
+    pub turbo_frames: u64,
+}
+
+#[derive(PartialEq)]
+enum Control {
+    /// Held buttons, and held turbo buttons
+    Joypad(Joypad, Joypad),
+    Pause,
+    Resume(f64),
+    /// Emulate a single frame while paused
+    Advance,
+}
+
+/// Channels connecting the emulation thread to the UI thread. Drawn frames are sent back to be
+/// reused, so no frames are allocated after the first few. There's no audio channel: the APU
+/// doesn't produce samples yet, so audio output is left for when it does
+struct Emulation {
+    control: Receiver<Control>,
+    frames: SyncSender<Frame>,
+    free: Receiver<Frame>,
+    /// Frames that didn't fit in the queue because the UI thread fell behind
+    dropped: Arc<AtomicU64>,
+    turbo_frames: u64,
+}
+
+/// The UI thread's ends of the channels
+struct Ui {
+    control: Sender<Control>,
+    frames: Receiver<Frame>,
+    free: Sender<Frame>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// Frame deadlines are computed from the number of frames since `start` instead of being
+/// accumulated, so rounding errors don't add up
+struct Pacing {
+    start: Instant,
+    frames: u64,
+    speed: f64,
+}
+
+/// Speed state on the UI side, which decides what the emulation thread is told
+struct Speed {
+    fast_forward_speed: f64,
+    slow_motion_speed: f64,
+    paused: bool,
+    fast_forward: bool,
+    slow_motion: bool,
+    // frames received since `measured_at`, used for the measured speed
+    frames: u32,
+    measured_at: Instant,
+    measured: f64,
+}
+
+pub fn run(
+    mut term: DefaultTerminal,
+    system: System,
+    session: Session,
+    controls: Controls,
+    mode: RenderMode,
+) -> Result<(), Error> {
+    let (control_tx, control_rx) = mpsc::channel();
+    let (frame_tx, frame_rx) = mpsc::sync_channel(FRAME_QUEUE);
+    let (free_tx, free_rx) = mpsc::channel();
+    let dropped = Arc::new(AtomicU64::new(0));
+    let emulation = Emulation {
+        control: control_rx,
+        frames: frame_tx,
+        free: free_rx,
+        dropped: dropped.clone(),
+        turbo_frames: controls.turbo_frames,
+    };
+    let emulator = thread::Builder::new()
+        .name("emulation".into())
+        .spawn(move || emulation.run(system, session))?;
+
+    mode.detect_graphics();
+    // without key release events, held keys time out and fast-forward is toggled
+    let releases = crossterm::terminal::supports_keyboard_enhancement().unwrap_or(false);
+    if releases {
+        crossterm::execute!(
+            io::stdout(),
+            PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
+        )?;
+    }
+    let ui = Ui {
+        control: control_tx,
+        frames: frame_rx,
+        free: free_tx,
+        dropped,
+    };
+    let result = ui_loop(
+        &mut term,
+        ui,
+        Speed::new(&controls),
+        Keyboard::new(controls.bindings, releases, controls.hold_timeout),
+        mode,
+    );
+    if releases {
+        crossterm::execute!(io::stdout(), PopKeyboardEnhancementFlags)?;
+    }
+
+    // the terminal is restored by the caller, so a panic is returned like any other error
+    let (system, session, emulated) = emulator.join().map_err(|panic| {
+        let message = match panic.downcast::<String>() {
+            Ok(message) => *message,
+            Err(panic) => panic
+                .downcast_ref::<&str>()
+                .unwrap_or(&"unknown")
+                .to_string(),
         };
-        screen.frame = system.next_frame(input).map_err(Error::System)?;
-        term.draw(|f| {
-            f.render_widget(&screen, f.area());
-        })?;
+        Error::Panic(message)
+    })?;
+    if let Some((movie, path)) = session.recording {
+        movie.write(File::create(&path)?).map_err(Error::Movie)?;
+        log::info!(frames = movie.len(), path:? = path; "movie saved");
+    }
+    if let Some(video) = session.video {
+        video.finish().map_err(Error::Video)?;
+        log::info!("video saved");
     }
     for (i, frame) in system.stack_frames().iter().enumerate() {
         log::info!(
@@ -62,27 +180,284 @@ pub fn run(mut term: DefaultTerminal, mut system: System) -> Result<(), Error> {
             ;""
         );
     }
-    Ok(())
+    emulated.and(result)
 }
 
-#[derive(Default)]
-pub struct GameScreen {
-    pub frame: Frame,
-    pub block: Block<'static>,
+impl Emulation {
+    /// Emulate frames until the UI thread hangs up, returning the system and session for cleanup
+    fn run(self, mut system: System, mut session: Session) -> (System, Session, Result<(), Error>) {
+        let result = self.emulate(&mut system, &mut session);
+        (system, session, result)
+    }
+
+    fn emulate(&self, system: &mut System, session: &mut Session) -> Result<(), Error> {
+        let mut pacing = Pacing::new(1.0);
+        let mut paused = false;
+        let mut held = Joypad::default();
+        let mut turbo = Joypad::default();
+        // buttons tapped since the last frame are held for the next one
+        let mut tapped = Joypad::default();
+        let mut turbo_tapped = Joypad::default();
+        let mut frame = 0;
+        loop {
+            let mut advance = false;
+            loop {
+                let control = if paused && !advance {
+                    // nothing to emulate, so block until told otherwise
+                    self.control.recv().map_err(|_| TryRecvError::Disconnected)
+                } else {
+                    self.control.try_recv()
+                };
+                match control {
+                    Ok(Control::Joypad(joypad, turbo_joypad)) => {
+                        (held, turbo) = (joypad, turbo_joypad);
+                        tapped = tapped | held;
+                        turbo_tapped = turbo_tapped | turbo;
+                    }
+                    Ok(Control::Pause) => paused = true,
+                    Ok(Control::Resume(speed)) => {
+                        paused = false;
+                        pacing = Pacing::new(speed);
+                    }
+                    Ok(Control::Advance) => advance = paused,
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            }
+
+            let turbo_on = (frame / self.turbo_frames).is_multiple_of(2);
+            let joypad = if turbo_on {
+                tapped | turbo_tapped
+            } else {
+                tapped
+            };
+            self.emulate_frame(system, session, joypad)?;
+            (tapped, turbo_tapped) = (held, turbo);
+            frame += 1;
+            if !paused {
+                pacing.wait();
+            }
+        }
+    }
+
+    fn emulate_frame(
+        &self,
+        system: &mut System,
+        session: &mut Session,
+        joypad: Joypad,
+    ) -> Result<(), Error> {
+        let input = match session.playback.as_ref().and_then(Player::next_input) {
+            Some(input) => input,
+            None => Input {
+                joypad,
+                ..Default::default()
+            },
+        };
+        let joypad = input.joypad;
+        let frame = system.next_frame(input).map_err(Error::System)?;
+        if let Some(video) = &mut session.video {
+            video.push(frame).map_err(Error::Video)?;
+        }
+        let mut buffer = self.free.try_recv().unwrap_or_default();
+        buffer.clone_from(frame);
+        // the UI thread hanging up is noticed when reading controls
+        if let Err(TrySendError::Full(_)) = self.frames.try_send(buffer) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+
+        if let Some(player) = &mut session.playback {
+            if let Err(err) = player.advance(system) {
+                log::warn!("{}", Error::Movie(err));
+            }
+            if player.finished() {
+                log::info!(frames = player.frame(); "movie finished");
+                session.playback = None;
+            }
+        }
+        if let Some((movie, _)) = &mut session.recording {
+            movie.record(joypad, system).map_err(Error::Movie)?;
+        }
+        Ok(())
+    }
+}
+
+impl Pacing {
+    fn new(speed: f64) -> Self {
+        Self {
+            start: Instant::now(),
+            frames: 0,
+            speed,
+        }
+    }
+
+    /// Sleep until the next frame is due
+    fn wait(&mut self) {
+        self.frames += 1;
+        let dots = (self.frames * FRAME_DOTS) as f64 / self.speed;
+        let deadline = self.start + Duration::from_secs_f64(dots / CLOCK_HZ as f64);
+        let now = Instant::now();
+        if let Some(early) = deadline.checked_duration_since(now) {
+            thread::sleep(early);
+        } else if now - deadline > Duration::from_secs(1) {
+            // too far behind to catch up, e.g. after the process was suspended
+            *self = Self::new(self.speed);
+        }
+    }
+}
+
+impl Speed {
+    fn new(controls: &Controls) -> Self {
+        Self {
+            fast_forward_speed: controls.fast_forward,
+            slow_motion_speed: controls.slow_motion,
+            paused: false,
+            fast_forward: false,
+            slow_motion: false,
+            frames: 0,
+            measured_at: Instant::now(),
+            measured: 1.0,
+        }
+    }
+
+    fn control(&self) -> Control {
+        match self {
+            Self { paused: true, .. } => Control::Pause,
+            Self {
+                fast_forward: true, ..
+            } => Control::Resume(self.fast_forward_speed),
+            Self {
+                slow_motion: true, ..
+            } => Control::Resume(self.slow_motion_speed),
+            _ => Control::Resume(1.0),
+        }
+    }
+
+    /// Update the measured speed once a second, returning whether it was updated
+    fn measure(&mut self) -> bool {
+        let elapsed = self.measured_at.elapsed();
+        if elapsed < Duration::from_secs(1) {
+            return false;
+        }
+        let fps = self.frames as f64 / elapsed.as_secs_f64();
+        self.measured = fps * FRAME_DOTS as f64 / CLOCK_HZ as f64;
+        self.frames = 0;
+        self.measured_at = Instant::now();
+        true
+    }
+
+    fn status(&self, dropped: u64) -> String {
+        let mode = match self.control() {
+            Control::Pause => "paused".into(),
+            Control::Resume(speed) => format!("{speed}x"),
+            _ => unreachable!(),
+        };
+        format!(
+            "{mode} | speed {:.2}x | dropped frames: {dropped}",
+            self.measured
+        )
+    }
 }
 
-impl Widget for &GameScreen {
-    fn render(self, area: Rect, buf: &mut Buffer) {
-        (&self.block).render(area, buf);
-        let area = self.block.inner(area);
-        let rows = (0..).take_while(|&y| y < area.height).zip(&*self.frame.0);
-        for (y, row) in rows {
-            let pixels = (0..).take_while(|&x| x < area.width / 2).zip(row);
-            for (x, pixel) in pixels {
-                let Pixel(r, g, b) = pixel.get();
-                buf.cell_mut((area.x + x * 2, area.y + y)).unwrap().bg = Color::Rgb(r, g, b);
-                buf.cell_mut((area.x + x * 2 + 1, area.y + y)).unwrap().bg = Color::Rgb(r, g, b);
+fn ui_loop(
+    term: &mut DefaultTerminal,
+    ui: Ui,
+    mut speed: Speed,
+    mut keyboard: Keyboard,
+    mode: RenderMode,
+) -> Result<(), Error> {
+    let mut screen = GameScreen {
+        mode,
+        ..Default::default()
+    };
+    // frames superseded by a newer one before they could be drawn
+    let mut superseded = 0;
+    let mut joypad = keyboard.joypad();
+    'ui: loop {
+        let mut redraw = speed.measure();
+        // whether the game screen itself changed, and not only the status line
+        let mut screen_changed = false;
+        let previous = speed.control();
+        let mut advance = false;
+        let event = if crossterm::event::poll(POLL_TIME)? {
+            Some(crossterm::event::read()?)
+        } else {
+            None
+        };
+        if let Some(Event::Resize(..)) = event {
+            redraw = true;
+            screen_changed = true;
+        }
+        if let Some(event) = event.as_ref().and_then(Event::as_key_event) {
+            match keyboard.handle(event) {
+                Some(Action::Quit) => break 'ui,
+                Some(Action::Pause) => speed.paused = !speed.paused,
+                Some(Action::SlowMotion) => speed.slow_motion = !speed.slow_motion,
+                Some(Action::Advance) => advance = speed.paused,
+                Some(Action::FastForward) if !keyboard.releases() => {
+                    speed.fast_forward = !speed.fast_forward;
+                }
+                _ => {}
+            }
+        }
+        keyboard.expire();
+        if keyboard.releases() {
+            speed.fast_forward = keyboard.held(Action::FastForward);
+        }
+
+        let mut commands = vec![];
+        if keyboard.joypad() != joypad {
+            joypad = keyboard.joypad();
+            log::debug!(joypad:? = joypad.0, turbo:? = joypad.1; "");
+            commands.push(Control::Joypad(joypad.0, joypad.1));
+        }
+        if speed.control() != previous {
+            commands.push(speed.control());
+            redraw = true;
+        }
+        if advance {
+            commands.push(Control::Advance);
+        }
+        // a hang up means the emulation thread stopped, which is noticed below
+        for command in commands {
+            let _ = ui.control.send(command);
+        }
+
+        // only the newest frame is drawn, older ones are dropped
+        let mut latest = None;
+        loop {
+            match ui.frames.try_recv() {
+                Ok(frame) => {
+                    speed.frames += 1;
+                    if let Some(older) = latest.replace(frame) {
+                        superseded += 1;
+                        let _ = ui.free.send(older);
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                // the emulation thread stopped, its error is reported by `run`
+                Err(TryRecvError::Disconnected) => break 'ui,
+            }
+        }
+        // identical frames aren't redrawn, and ratatui only redraws cells which changed
+        if let Some(frame) = latest {
+            if frame != screen.frame {
+                redraw = true;
+                screen_changed = true;
+            }
+            let _ = ui.free.send(std::mem::replace(&mut screen.frame, frame));
+        }
+        if redraw {
+            let dropped = superseded + ui.dropped.load(Ordering::Relaxed);
+            screen.block = Block::new().title(speed.status(dropped));
+            let completed = term.draw(|f| {
+                f.render_widget(&screen, f.area());
+            })?;
+            if mode == RenderMode::Graphics && screen_changed {
+                screen.print_graphics(completed.area)?;
             }
         }
     }
+    let dropped = superseded + ui.dropped.load(Ordering::Relaxed);
+    log::info!(dropped; "frames dropped");
+    Ok(())
 }